@@ -1,9 +1,31 @@
-use crate::assembler::program_parsers::{program, Program};
+use crate::assembler::assembler_errors::{AssemblerError, SourceLocation};
+use crate::assembler::expression_parsers::Expression;
+use crate::assembler::include_resolver::resolve_includes;
+use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::listing::Listing;
+use crate::assembler::macro_expander::{file_lines, source_lines, MacroExpander, SourceLine};
+use crate::assembler::object_file::{
+    Export, Import, ModuleTable, Native, ObjectFile, ObjectSymbol, Relocation, Section,
+};
+use crate::assembler::operand_parsers::unescape_string;
+use crate::assembler::program_parsers::{located_program, Program};
+use crate::assembler::register_parsers::named_register;
 use crate::instruction::Opcode;
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
 
+pub mod assembler_errors;
 pub mod directive_parsers;
+pub mod expression_parsers;
+pub mod include_resolver;
 pub mod instruction_parsers;
 pub mod label_parsers;
+pub mod listing;
+pub mod macro_expander;
+pub mod object_file;
 pub mod opcode_parsers;
 pub mod operand_parsers;
 pub mod program_parsers;
@@ -32,16 +54,67 @@ pub const ELF_HEADER_PREFIX: [u8; 19] = [
     127, 69, 76, 70, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 12,
 ];
 pub const ELF_HEADER_LENGTH: usize = 64;
+/// Where the header stores the type of the file
+pub const ELF_TYPE_OFFSET: usize = 16;
+pub const ELF_TYPE_REL: u8 = 1;
+pub const ELF_TYPE_EXEC: u8 = 2;
+/// The header is padded up to and including byte ELF_HEADER_LENGTH, the data section follows it
+pub const PROGRAM_START: usize = ELF_HEADER_LENGTH + 1;
+/// Where the header stores the length of the data section, a little endian u32. The code
+/// section starts right after the data, at PROGRAM_START plus that length.
+pub const DATA_LENGTH_OFFSET: usize = 24;
+/// Where the header of an object file stores the length of the code section, a little endian
+/// u32. The symbol and relocation tables follow the code. Executables record it when a module
+/// table follows the code.
+pub const CODE_LENGTH_OFFSET: usize = 28;
+
+/// Reads the length of the data section from a program header
+pub fn data_length(program: &[u8]) -> Option<usize> {
+    program
+        .get(DATA_LENGTH_OFFSET..DATA_LENGTH_OFFSET + 4)
+        .map(|b| LittleEndian::read_u32(b) as usize)
+}
+
+/// Where the code section of a program ends. Programs without a module table don't record
+/// the length of their code, which then runs to the end.
+pub fn code_end(program: &[u8]) -> usize {
+    let code_length = program
+        .get(CODE_LENGTH_OFFSET..CODE_LENGTH_OFFSET + 4)
+        .map(|b| LittleEndian::read_u32(b) as usize)
+        .unwrap_or(0);
+    match data_length(program) {
+        Some(data_length) if code_length > 0 => PROGRAM_START + data_length + code_length,
+        _ => program.len(),
+    }
+}
+
+/// Builds the header of a file of the given type, padded up to PROGRAM_START
+pub fn elf_header(file_type: u8, data_length: usize) -> Vec<u8> {
+    let mut header = ELF_HEADER_PREFIX.to_vec();
+    header[ELF_TYPE_OFFSET] = file_type;
+    header.resize(PROGRAM_START, 0);
+    LittleEndian::write_u32(
+        &mut header[DATA_LENGTH_OFFSET..DATA_LENGTH_OFFSET + 4],
+        data_length as u32,
+    );
+    header
+}
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Op { code: Opcode },
     Register { reg_num: u8 },
+    RegisterName { name: String },
+    AliasDeclaration { name: String },
     IntegerOperand { value: i32 },
     LabelDeclaration { name: String },
     LabelUsage { name: String },
+    Expression { expr: Expression },
+    ConstantDeclaration { name: String },
     Directive { name: String },
     IrString { name: String },
+    ModuleName { name: String },
+    FunctionName { name: String },
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -54,6 +127,20 @@ pub enum AssemblerPhase {
 pub struct Assembler {
     pub phase: AssemblerPhase,
     pub symbols: SymbolTable,
+    pub macros: MacroExpander,
+    /// The register aliases declared with `.alias`, which like macros carry over to code
+    /// appended to the program
+    aliases: HashMap<String, u8>,
+    /// The last global label declared, which the local labels of appended code belong to
+    scope: Option<String>,
+    /// The listing of the last program assembled successfully
+    pub listing: Option<Listing>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Assembler {
@@ -61,71 +148,901 @@ impl Assembler {
         Assembler {
             phase: AssemblerPhase::First,
             symbols: SymbolTable::new(),
+            macros: MacroExpander::new(),
+            aliases: HashMap::new(),
+            scope: None,
+            listing: None,
+        }
+    }
+
+    /// Assembles source text, resolving `.include` paths relative to the working directory
+    pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let lines = resolve_includes(source_lines(raw), Path::new("."), &mut vec![])?;
+        self.assemble_lines(lines)
+    }
+
+    /// Reads and assembles a file, resolving `.include` paths relative to the file itself
+    pub fn assemble_file(&mut self, path: &Path) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let lines = Assembler::read_file(path)?;
+        self.assemble_lines(lines)
+    }
+
+    /// Assembles source text into an object file, which may refer to labels declared `.extern`
+    /// and defined by other object files
+    pub fn assemble_object(&mut self, raw: &str) -> Result<ObjectFile, Vec<AssemblerError>> {
+        let lines = resolve_includes(source_lines(raw), Path::new("."), &mut vec![])?;
+        let sections = self.assemble_sections(lines, true, None)?;
+        self.object_file(sections)
+    }
+
+    /// Reads a file and assembles it into an object file
+    pub fn assemble_object_file(&mut self, path: &Path) -> Result<ObjectFile, Vec<AssemblerError>> {
+        let lines = Assembler::read_file(path)?;
+        let sections = self.assemble_sections(lines, true, None)?;
+        self.object_file(sections)
+    }
+
+    /// Assembles code to be appended at `address` to the program this assembler assembled last,
+    /// as the REPL does with what is typed. The code can use the labels and constants of the
+    /// program, and what it defines is added to them, so that the next code appended can use
+    /// it too. Nothing is added if the code doesn't assemble.
+    pub fn assemble_appended(
+        &mut self,
+        raw: &str,
+        address: usize,
+    ) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let symbols = self.symbols.clone();
+        let lines = resolve_includes(source_lines(raw), Path::new("."), &mut vec![])?;
+        let assembled = self
+            .assemble_sections(lines, false, Some((symbols.clone(), address)))
+            .and_then(|sections| {
+                let errors: Vec<AssemblerError> = sections
+                    .program
+                    .instructions
+                    .iter()
+                    .zip(&sections.locations)
+                    .filter_map(|(i, location)| match i.get_directive_name().as_deref() {
+                        Some(
+                            directive @ ("asciiz" | "module" | "export" | "import" | "native"
+                            | "extern" | "global"),
+                        ) => Some(AssemblerError::NotAppendable {
+                            directive: directive.to_string(),
+                            location: location.clone(),
+                        }),
+                        _ => None,
+                    })
+                    .collect();
+                if errors.is_empty() {
+                    Ok(sections.code)
+                } else {
+                    Err(errors)
+                }
+            });
+        if assembled.is_err() {
+            self.symbols = symbols;
         }
+        assembled
     }
 
-    pub fn assemble(&mut self, raw: &str) -> Option<Vec<u8>> {
-        match program(raw) {
-            Ok((_, program)) => {
-                // First get the header so we can smush it into the bytecode letter
-                let mut assembled_program = self.write_elf_header();
-                self.process_first_phase(&program);
-                let mut body = self.process_second_phase(&program);
+    /// Reads the lines of a file, with its includes
+    fn read_file(path: &Path) -> Result<Vec<SourceLine>, Vec<AssemblerError>> {
+        let name = path.display().to_string();
+        let (canonical, raw) = fs::canonicalize(path)
+            .and_then(|c| fs::read_to_string(&c).map(|s| (c, s)))
+            .map_err(|e| {
+                vec![AssemblerError::FileNotReadable {
+                    path: name.clone(),
+                    error: e.to_string(),
+                }]
+            })?;
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        resolve_includes(file_lines(&raw, &name), base, &mut vec![canonical])
+    }
 
-                // Merge the header with the populated body vector
-                assembled_program.append(&mut body);
-                Some(assembled_program)
+    fn assemble_lines(&mut self, lines: Vec<SourceLine>) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let mut sections = self.assemble_sections(lines, false, None)?;
+        // Get the header so we can smush it into the bytecode letter, followed by the data
+        // and the populated body vector
+        let mut assembled_program = elf_header(ELF_TYPE_EXEC, sections.data.len());
+        if let Some(module) = &sections.module {
+            LittleEndian::write_u32(
+                &mut assembled_program[CODE_LENGTH_OFFSET..CODE_LENGTH_OFFSET + 4],
+                sections.code.len() as u32,
+            );
+            sections.code.append(&mut module.to_bytes());
+        }
+        assembled_program.append(&mut sections.data);
+        assembled_program.append(&mut sections.code);
+        Ok(assembled_program)
+    }
+
+    /// Runs every pass over the source and encodes the data and code sections. Labels get the
+    /// addresses they would have in an executable made of this source alone, unless the code is
+    /// appended to a program, given by its symbols and where the code goes. For an `object`
+    /// file, `.extern` labels are allowed and every label usage must be relocatable.
+    fn assemble_sections(
+        &mut self,
+        lines: Vec<SourceLine>,
+        object: bool,
+        appended_to: Option<(SymbolTable, usize)>,
+    ) -> Result<Sections, Vec<AssemblerError>> {
+        // Every call assembles a separate program, unless it is appended to one
+        let (symbols, code_start) = match appended_to {
+            Some((symbols, address)) => (symbols, Some(address)),
+            None => {
+                self.macros = MacroExpander::new();
+                self.aliases.clear();
+                self.scope = None;
+                (SymbolTable::new(), None)
             }
-            Err(e) => {
-                println!("There was an error assembling the code: {:?}", e);
-                None
+        };
+        self.symbols = symbols;
+        self.phase = AssemblerPhase::First;
+        self.listing = None;
+        // Macros are expanded on the raw lines, so the parser never sees them
+        let lines = self.macros.expand(lines)?;
+        let source = lines
+            .iter()
+            .map(|l| l.text.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n");
+        let (leftover, (mut program, positions)) = match located_program(&source) {
+            Ok(parsed) => parsed,
+            Err(_) => return Err(vec![Assembler::parse_error(&source, &source, &lines)]),
+        };
+        if !leftover.trim().is_empty() {
+            return Err(vec![Assembler::parse_error(&source, leftover, &lines)]);
+        }
+        let mut line_indices: Vec<usize> = positions
+            .iter()
+            .map(|p| Assembler::line_index(&source, *p))
+            .collect();
+        let mut locations: Vec<SourceLocation> = positions
+            .iter()
+            .map(|p| Assembler::location_at(&source, *p, &lines))
+            .collect();
+        self.resolve_registers(&mut program, &locations)?;
+        self.resolve_labels(&mut program, &locations)?;
+        Assembler::fuse_compare_and_branch(&mut program, &mut locations, &mut line_indices);
+        let data = self.process_first_phase(&program, &locations, object, code_start)?;
+        let module = self.module_table(&program, &locations)?;
+        let code_start = code_start.unwrap_or(PROGRAM_START + data.len());
+        let code = self.process_second_phase(&program, &locations, code_start)?;
+        self.listing = Some(Listing::new(
+            &program,
+            &line_indices,
+            &lines,
+            &self.symbols,
+            PROGRAM_START,
+            code_start,
+        ));
+        Ok(Sections {
+            data,
+            code,
+            program,
+            locations,
+            module,
+        })
+    }
+
+    /// Turns assembled sections into an object file, with relocations for every label usage
+    fn object_file(&self, sections: Sections) -> Result<ObjectFile, Vec<AssemblerError>> {
+        let code_start = (PROGRAM_START + sections.data.len()) as u32;
+        let mut object = ObjectFile {
+            data: sections.data,
+            code: sections.code,
+            module: sections.module,
+            ..ObjectFile::default()
+        };
+        for symbol in &self.symbols.symbols {
+            let (section, offset) = match symbol.symbol_type {
+                SymbolType::Constant | SymbolType::Import | SymbolType::Native => continue,
+                SymbolType::External => (Section::External, 0),
+                SymbolType::Data => (Section::Data, symbol.offset - PROGRAM_START as u32),
+                SymbolType::Label => (Section::Code, symbol.offset - code_start),
+            };
+            object.symbols.push(ObjectSymbol {
+                name: symbol.name.clone(),
+                section,
+                offset,
+                exported: false,
+            });
+        }
+
+        let mut errors = vec![];
+        let mut offset = 0;
+        for (i, location) in sections
+            .program
+            .instructions
+            .iter()
+            .zip(&sections.locations)
+        {
+            if i.get_directive_name().as_deref() == Some("global") {
+                let name = i.get_label_usage().unwrap_or_default();
+                match object.symbols.iter_mut().find(|s| s.name == name) {
+                    Some(symbol) if symbol.section != Section::External => symbol.exported = true,
+                    _ => errors.push(AssemblerError::UndefinedSymbol {
+                        name: name.to_string(),
+                        location: location.clone(),
+                    }),
+                }
             }
+            if !i.is_opcode() {
+                continue;
+            }
+            for (field, name) in i.label_usage_offsets() {
+                if let Some(symbol) = object.symbols.iter().position(|s| s.name == name) {
+                    object.relocations.push(Relocation {
+                        offset: offset + field as u32,
+                        symbol: symbol as u32,
+                    });
+                }
+            }
+            offset += 4;
+        }
+        if errors.is_empty() {
+            Ok(object)
+        } else {
+            Err(errors)
         }
     }
 
-    fn process_first_phase(&mut self, p: &Program) {
-        self.extract_labels(p);
+    /// Index of the line containing byte `position` of `source`
+    fn line_index(source: &str, position: usize) -> usize {
+        source[..position].matches('\n').count()
+    }
+
+    /// Finds the source location of the line containing byte `position` of `source`
+    fn location_at(source: &str, position: usize, lines: &[SourceLine]) -> SourceLocation {
+        let index = Assembler::line_index(source, position);
+        match lines.get(index) {
+            Some(line) => line.location.clone(),
+            None => SourceLocation::new(index + 1),
+        }
+    }
+
+    /// Builds an error pointing at the line where the parser stopped, `rest` being the input
+    /// it could not consume
+    fn parse_error(source: &str, rest: &str, lines: &[SourceLine]) -> AssemblerError {
+        let rest = rest.trim_start();
+        AssemblerError::ParseError {
+            location: Assembler::location_at(source, source.len() - rest.len(), lines),
+            error: rest.lines().next().unwrap_or_default().trim().to_string(),
+        }
+    }
+
+    /// Defines every label and constant, and returns the data section. Labels of the code
+    /// section start at `code_start` if given, right after the data otherwise.
+    fn process_first_phase(
+        &mut self,
+        p: &Program,
+        locations: &[SourceLocation],
+        object: bool,
+        code_start: Option<usize>,
+    ) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let (data, mut errors) = self.extract_data(p, locations);
+        let code_start = code_start.unwrap_or(PROGRAM_START + data.len());
+        errors.append(&mut self.extract_labels(p, locations, code_start));
+        errors.append(&mut self.extract_imports(p, locations));
+        errors.append(&mut self.extract_natives(p, locations));
+        if object {
+            errors.append(&mut self.extract_externals(p, locations));
+            errors.append(&mut Assembler::check_relocatable(p, locations));
+        }
+        errors.append(&mut self.process_directives(p, locations));
         self.phase = AssemblerPhase::Second;
+        if errors.is_empty() {
+            Ok(data)
+        } else {
+            Err(errors)
+        }
     }
 
-    fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
-        let mut program = vec![];
-        for i in &p.instructions {
-            let mut bytes = i.to_bytes(&self.symbols);
-            program.append(&mut bytes);
+    /// Encodes the code section, `code_start` being where it will be in the program
+    fn process_second_phase(
+        &mut self,
+        p: &Program,
+        locations: &[SourceLocation],
+        code_start: usize,
+    ) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let errors = self.check_operands(p, locations, code_start);
+        if errors.is_empty() {
+            // Every instruction was just checked to encode
+            Ok(p.to_bytes_from(&self.symbols, code_start as u32)
+                .unwrap_or_default())
+        } else {
+            Err(errors)
         }
-        program
     }
 
-    fn extract_labels(&mut self, p: &Program) {
-        let mut c = 0;
-        for i in &p.instructions {
-            if i.is_label() {
-                match i.get_label_name() {
-                    Some(name) => {
-                        let symbol = Symbol::new(name, SymbolType::Label, c);
-                        self.symbols.add_symbol(symbol);
+    /// Lays out the `.asciiz` strings of the data section and defines the labels naming them
+    fn extract_data(
+        &mut self,
+        p: &Program,
+        locations: &[SourceLocation],
+    ) -> (Vec<u8>, Vec<AssemblerError>) {
+        let mut data = vec![];
+        let mut errors = vec![];
+        for (i, location) in p.instructions.iter().zip(locations) {
+            if !i.is_data() {
+                continue;
+            }
+            let bytes = match &i.operand1 {
+                Some(Token::IrString { name }) => unescape_string(name),
+                _ => Err(".asciiz needs a string".to_string()),
+            };
+            match bytes {
+                Ok(mut bytes) => {
+                    if let Some(name) = i.get_label_name() {
+                        let offset = (PROGRAM_START + data.len()) as u32;
+                        let symbol = Symbol::new(name, SymbolType::Data, offset);
+                        if let Err(e) = self.symbols.add_symbol(symbol) {
+                            errors.push(e.at(location));
+                        }
+                    }
+                    data.append(&mut bytes);
+                    data.push(0);
+                }
+                Err(error) => errors.push(AssemblerError::InvalidString {
+                    error,
+                    location: location.clone(),
+                }),
+            }
+        }
+        (data, errors)
+    }
+
+    /// Handles directives in source order, so a `.equ` can use any label and the constants
+    /// defined above it
+    fn process_directives(
+        &mut self,
+        p: &Program,
+        locations: &[SourceLocation],
+    ) -> Vec<AssemblerError> {
+        let mut errors = vec![];
+        for (i, location) in p.instructions.iter().zip(locations) {
+            match i.get_directive_name().as_deref() {
+                Some("alias") | Some("asciiz") | Some("global") | Some("extern")
+                | Some("module") | Some("export") | Some("import") | Some("native") => {}
+                Some("equ") => {
+                    if let (
+                        Some(Token::ConstantDeclaration { name }),
+                        Some(Token::Expression { expr }),
+                    ) = (&i.operand1, &i.operand2)
+                    {
+                        let added = expr
+                            .evaluate(&self.symbols)
+                            .map_err(|e| AssemblerError::from_expression(e, location))
+                            .and_then(|value| {
+                                let symbol =
+                                    Symbol::new(name.clone(), SymbolType::Constant, value as u32);
+                                self.symbols.add_symbol(symbol).map_err(|e| e.at(location))
+                            });
+                        if let Err(e) = added {
+                            errors.push(e);
+                        }
                     }
-                    None => {}
+                }
+                Some(directive) => errors.push(AssemblerError::UnknownDirective {
+                    directive: directive.to_string(),
+                    location: location.clone(),
+                }),
+                None => {}
+            }
+        }
+        errors
+    }
+
+    /// Defines a `module.function` symbol for every `.import`, whose value is the index of the
+    /// import that `CALLEXT` takes
+    fn extract_imports(
+        &mut self,
+        p: &Program,
+        locations: &[SourceLocation],
+    ) -> Vec<AssemblerError> {
+        let mut errors = vec![];
+        let mut index = 0;
+        for (i, location) in p.instructions.iter().zip(locations) {
+            if let (Some(Token::ModuleName { name: module }), Some(Token::FunctionName { name })) =
+                (&i.operand1, &i.operand2)
+            {
+                let full_name = format!("{}.{}", module, name);
+                let symbol = Symbol::new(full_name, SymbolType::Import, index);
+                match self.symbols.add_symbol(symbol) {
+                    Ok(()) => index += 1,
+                    Err(e) => errors.push(e.at(location)),
+                }
+            }
+        }
+        errors
+    }
+
+    /// Defines a symbol for every `.native`, whose value is the index of the native that `CALLN`
+    /// takes
+    fn extract_natives(
+        &mut self,
+        p: &Program,
+        locations: &[SourceLocation],
+    ) -> Vec<AssemblerError> {
+        let mut errors = vec![];
+        let mut index = 0;
+        for (i, location) in p.instructions.iter().zip(locations) {
+            if i.get_directive_name().as_deref() != Some("native") {
+                continue;
+            }
+            if let Some(Token::FunctionName { name }) = &i.operand1 {
+                let symbol = Symbol::new(name.clone(), SymbolType::Native, index);
+                match self.symbols.add_symbol(symbol) {
+                    Ok(()) => index += 1,
+                    Err(e) => errors.push(e.at(location)),
+                }
+            }
+        }
+        errors
+    }
+
+    /// Collects the `.module`, `.export`, `.import` and `.native` directives, `None` meaning
+    /// the source doesn't use any
+    fn module_table(
+        &self,
+        p: &Program,
+        locations: &[SourceLocation],
+    ) -> Result<Option<ModuleTable>, Vec<AssemblerError>> {
+        let mut table = ModuleTable::default();
+        let mut is_module = false;
+        let mut named = false;
+        let mut first_export = None;
+        let mut errors = vec![];
+        for (i, location) in p.instructions.iter().zip(locations) {
+            match (i.get_directive_name().as_deref(), &i.operand1, &i.operand2) {
+                (Some("module"), Some(Token::ModuleName { name }), _) => {
+                    if named {
+                        errors.push(AssemblerError::ModuleAlreadyNamed {
+                            location: location.clone(),
+                        });
+                    }
+                    table.name = name.clone();
+                    named = true;
+                }
+                (
+                    Some("export"),
+                    Some(Token::LabelUsage { name }),
+                    Some(Token::IntegerOperand { value }),
+                ) => match self.symbols.label_value(name) {
+                    Some(address) => table.exports.push(Export {
+                        name: name.clone(),
+                        arity: *value as u8,
+                        address,
+                    }),
+                    None => errors.push(AssemblerError::UndefinedSymbol {
+                        name: name.clone(),
+                        location: location.clone(),
+                    }),
+                },
+                (
+                    Some("import"),
+                    Some(Token::ModuleName { name: module }),
+                    Some(Token::FunctionName { name }),
+                ) => table.imports.push(Import {
+                    module: module.clone(),
+                    function: name.clone(),
+                }),
+                (
+                    Some("native"),
+                    Some(Token::FunctionName { name }),
+                    Some(Token::IntegerOperand { value }),
+                ) => table.natives.push(Native {
+                    name: name.clone(),
+                    arity: *value as u8,
+                }),
+                _ => {
+                    let callee = match &i.operand1 {
+                        Some(Token::LabelUsage { name }) => Some(name.as_str()),
+                        _ => None,
+                    };
+                    let is_call = |code| i.opcode == Some(Token::Op { code });
+                    if is_call(Opcode::CALLEXT)
+                        && !callee.is_some_and(|n| self.symbols.is_import(n))
+                    {
+                        errors.push(AssemblerError::NotAnImport {
+                            location: location.clone(),
+                        });
+                    }
+                    if is_call(Opcode::CALLN) && !callee.is_some_and(|n| self.symbols.is_native(n))
+                    {
+                        errors.push(AssemblerError::NotANative {
+                            location: location.clone(),
+                        });
+                    }
+                    continue;
+                }
+            }
+            is_module = true;
+            if first_export.is_none() && i.get_directive_name().as_deref() == Some("export") {
+                first_export = Some(location);
+            }
+        }
+        if let (Some(location), false) = (first_export, named) {
+            errors.push(AssemblerError::MissingModuleName {
+                location: location.clone(),
+            });
+        }
+        if !errors.is_empty() {
+            Err(errors)
+        } else if is_module {
+            Ok(Some(table))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Defines the labels declared `.extern`, which other object files provide
+    fn extract_externals(
+        &mut self,
+        p: &Program,
+        locations: &[SourceLocation],
+    ) -> Vec<AssemblerError> {
+        let mut errors = vec![];
+        for (i, location) in p.instructions.iter().zip(locations) {
+            if i.get_directive_name().as_deref() != Some("extern") {
+                continue;
+            }
+            if let Some(name) = i.get_label_usage() {
+                let symbol = Symbol::new(name.to_string(), SymbolType::External, 0);
+                if let Err(e) = self.symbols.add_symbol(symbol) {
+                    errors.push(e.at(location));
+                }
+            }
+        }
+        errors
+    }
+
+    /// In an object file labels are only known once linked, so they can't take part in
+    /// expressions, and compare-and-branch instructions can only reach labels of the same file
+    fn check_relocatable(p: &Program, locations: &[SourceLocation]) -> Vec<AssemblerError> {
+        let mut errors = vec![];
+        for (i, location) in p.instructions.iter().zip(locations) {
+            for operand in [&i.operand1, &i.operand2, &i.operand3]
+                .iter()
+                .copied()
+                .flatten()
+            {
+                let label = match operand {
+                    Token::Expression { expr } => expr.labels().first().map(|l| l.to_string()),
+                    Token::LabelUsage { name } if i.is_branch() => {
+                        let local = p
+                            .instructions
+                            .iter()
+                            .any(|d| d.get_label_name().as_ref() == Some(name));
+                        if local {
+                            None
+                        } else {
+                            Some(name.clone())
+                        }
+                    }
+                    _ => None,
+                };
+                if let Some(name) = label {
+                    errors.push(AssemblerError::NotRelocatable {
+                        name,
+                        location: location.clone(),
+                    });
+                }
+            }
+        }
+        errors
+    }
+
+    /// Makes sure every label and expression operand can be resolved before encoding
+    fn check_operands(
+        &self,
+        p: &Program,
+        locations: &[SourceLocation],
+        code_start: usize,
+    ) -> Vec<AssemblerError> {
+        let mut errors = vec![];
+        let mut offset = code_start as u32;
+        for (i, location) in p.instructions.iter().zip(locations) {
+            if !i.is_opcode() {
+                continue;
+            }
+            match i.to_bytes(&self.symbols, offset) {
+                Ok(bytes) => offset += bytes.len() as u32,
+                Err(e) => {
+                    errors.push(AssemblerError::from_encoding(e, location));
+                    offset += 4;
+                }
+            }
+        }
+        errors
+    }
+
+    /// Replaces symbolic register names with register numbers, following `.alias` directives
+    /// in source order so an alias can be redefined further down
+    fn resolve_registers(
+        &mut self,
+        p: &mut Program,
+        locations: &[SourceLocation],
+    ) -> Result<(), Vec<AssemblerError>> {
+        let aliases = &mut self.aliases;
+        let mut errors = vec![];
+        for (i, location) in p.instructions.iter_mut().zip(locations) {
+            if let (Some(Token::AliasDeclaration { name }), Some(target)) =
+                (&i.operand1, &i.operand2)
+            {
+                if named_register(name).is_some() {
+                    errors.push(AssemblerError::AliasShadowsRegister {
+                        name: name.clone(),
+                        location: location.clone(),
+                    });
+                } else {
+                    match Assembler::resolve_register(target, aliases, location) {
+                        Ok(reg_num) => {
+                            aliases.insert(name.clone(), reg_num);
+                        }
+                        Err(e) => errors.push(e),
+                    }
+                }
+                continue;
+            }
+            for operand in i
+                .operand1
+                .iter_mut()
+                .chain(i.operand2.iter_mut())
+                .chain(i.operand3.iter_mut())
+            {
+                if let Token::RegisterName { .. } = operand {
+                    match Assembler::resolve_register(operand, aliases, location) {
+                        Ok(reg_num) => *operand = Token::Register { reg_num },
+                        Err(e) => errors.push(e),
+                    }
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn resolve_register(
+        token: &Token,
+        aliases: &HashMap<String, u8>,
+        location: &SourceLocation,
+    ) -> Result<u8, AssemblerError> {
+        match token {
+            Token::Register { reg_num } => Ok(*reg_num),
+            Token::RegisterName { name } => {
+                if let Some(reg_num) = aliases.get(name) {
+                    Ok(*reg_num)
+                } else if name.chars().all(|c| c.is_ascii_digit()) {
+                    Err(AssemblerError::RegisterOutOfRange {
+                        register: name.clone(),
+                        location: location.clone(),
+                    })
+                } else {
+                    Err(AssemblerError::UnknownRegister {
+                        name: name.clone(),
+                        location: location.clone(),
+                    })
+                }
+            }
+            _ => Err(AssemblerError::UnknownRegister {
+                name: format!("{:?}", token),
+                location: location.clone(),
+            }),
+        }
+    }
+
+    /// Gives local and numeric labels their full names. A `.name` label belongs to the last
+    /// global label above it and becomes `global.name`. The n-th definition of a numeric label
+    /// `1:` becomes `1.n`, which lets `@1b` and `@1f` point at the closest definition before and
+    /// after. Names starting with `..` are left alone and don't open a new scope.
+    fn resolve_labels(
+        &mut self,
+        p: &mut Program,
+        locations: &[SourceLocation],
+    ) -> Result<(), Vec<AssemblerError>> {
+        let mut numeric: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, i) in p.instructions.iter().enumerate() {
+            if let Some(name) = i.get_label_name() {
+                if name.chars().all(|c| c.is_ascii_digit()) {
+                    numeric.entry(name).or_default().push(index);
+                }
+            }
+        }
+
+        let mut errors = vec![];
+        let global = &mut self.scope;
+        for (index, (i, location)) in p.instructions.iter_mut().zip(locations).enumerate() {
+            if let Some(Token::LabelDeclaration { name }) = &mut i.label {
+                if name.chars().all(|c| c.is_ascii_digit()) {
+                    let n = numeric[name.as_str()].iter().position(|d| *d == index);
+                    *name = format!("{}.{}", name, n.unwrap_or_default());
+                } else if name.starts_with("..") {
+                } else if name.starts_with('.') {
+                    if let Some(global) = global {
+                        *name = format!("{}{}", global, name);
+                    }
+                } else {
+                    *global = Some(name.clone());
+                }
+            }
+            for operand in i
+                .operand1
+                .iter_mut()
+                .chain(i.operand2.iter_mut())
+                .chain(i.operand3.iter_mut())
+            {
+                let names = match operand {
+                    Token::LabelUsage { name } => vec![name],
+                    Token::Expression { expr } => expr.labels_mut(),
+                    _ => continue,
                 };
+                for name in names {
+                    match Assembler::full_label_name(name, index, global, &numeric) {
+                        Some(full) => *name = full,
+                        None => errors.push(AssemblerError::UndefinedSymbol {
+                            name: name.clone(),
+                            location: location.clone(),
+                        }),
+                    }
+                }
             }
-            c += 4;
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
         }
     }
 
-    fn write_elf_header(&self) -> Vec<u8> {
-        let mut header = vec![];
-        for byte in ELF_HEADER_PREFIX.iter() {
-            header.push(byte.clone());
+    /// Resolves a label reference made from instruction `index`, `None` meaning a numeric
+    /// reference with no matching definition in that direction
+    fn full_label_name(
+        name: &str,
+        index: usize,
+        global: &Option<String>,
+        numeric: &HashMap<String, Vec<usize>>,
+    ) -> Option<String> {
+        if let Some(number) = name.strip_suffix('b') {
+            if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) {
+                let n = numeric.get(number)?.iter().rposition(|d| *d <= index)?;
+                return Some(format!("{}.{}", number, n));
+            }
         }
-        while header.len() <= ELF_HEADER_LENGTH {
-            header.push(0 as u8);
+        if let Some(number) = name.strip_suffix('f') {
+            if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) {
+                let n = numeric.get(number)?.iter().position(|d| *d > index)?;
+                return Some(format!("{}.{}", number, n));
+            }
+        }
+        match global {
+            Some(global) if name.starts_with('.') && !name.starts_with("..") => {
+                Some(format!("{}{}", global, name))
+            }
+            _ => Some(name.to_string()),
         }
-        header
+    }
+
+    /// Peephole pass that turns a comparison followed by `JEQ @label` or `JNEQ @label` into a
+    /// single compare-and-branch instruction. Only labels declared in the code of the program
+    /// and close enough for the offset of a branch are fused, and never when the jump itself
+    /// carries a label, since something else may jump to it. The
+    /// fused instruction does not update the equal flag. `locations` and `line_indices` are kept
+    /// in step with the instructions.
+    fn fuse_compare_and_branch(
+        p: &mut Program,
+        locations: &mut Vec<SourceLocation>,
+        line_indices: &mut Vec<usize>,
+    ) {
+        // Where each code label is, counted in instructions
+        let mut declared: HashMap<String, usize> = HashMap::new();
+        for (index, i) in p.instructions.iter().filter(|i| i.is_opcode()).enumerate() {
+            if let Some(name) = i.get_label_name() {
+                declared.insert(name, index);
+            }
+        }
+        let mut fused: Vec<AssemblerInstruction> = Vec::with_capacity(p.instructions.len());
+        let mut fused_locations = Vec::with_capacity(locations.len());
+        let mut fused_lines = Vec::with_capacity(line_indices.len());
+        // Where the next instruction was among the instructions before fusing
+        let mut position = 0;
+        for ((i, location), line) in p
+            .instructions
+            .drain(..)
+            .zip(locations.drain(..))
+            .zip(line_indices.drain(..))
+        {
+            let jump_index = position;
+            if i.is_opcode() {
+                position += 1;
+            }
+            if let Some(previous) = fused.last_mut() {
+                if let Some(branch) = Assembler::fused_branch(previous, &i, &declared, jump_index) {
+                    previous.opcode = Some(Token::Op { code: branch });
+                    previous.operand3 = i.operand1;
+                    continue;
+                }
+            }
+            fused.push(i);
+            fused_locations.push(location);
+            fused_lines.push(line);
+        }
+        p.instructions = fused;
+        *locations = fused_locations;
+        *line_indices = fused_lines;
+    }
+
+    fn fused_branch(
+        compare: &AssemblerInstruction,
+        jump: &AssemblerInstruction,
+        declared: &HashMap<String, usize>,
+        jump_index: usize,
+    ) -> Option<Opcode> {
+        if jump.is_label() || jump.operand2.is_some() {
+            return None;
+        }
+        match &jump.operand1 {
+            // The branch replaces the comparison, right before the jump. Fusing only brings
+            // the target closer, so the distance before fusing is an upper bound.
+            Some(Token::LabelUsage { name }) => match declared.get(name) {
+                Some(target) if i8::try_from(*target as i64 + 1 - jump_index as i64).is_ok() => {}
+                _ => return None,
+            },
+            _ => return None,
+        }
+        let compare_code = match (&compare.opcode, &compare.operand1, &compare.operand2) {
+            (
+                Some(Token::Op { code }),
+                Some(Token::Register { .. }),
+                Some(Token::Register { .. }),
+            ) => *code,
+            _ => return None,
+        };
+        match jump.opcode {
+            Some(Token::Op { code: Opcode::JEQ }) => compare_code.branch_on_true(),
+            Some(Token::Op { code: Opcode::JNEQ }) => compare_code.branch_on_false(),
+            _ => None,
+        }
+    }
+
+    fn extract_labels(
+        &mut self,
+        p: &Program,
+        locations: &[SourceLocation],
+        code_start: usize,
+    ) -> Vec<AssemblerError> {
+        let mut errors = vec![];
+        let mut c = code_start as u32;
+        for (i, location) in p.instructions.iter().zip(locations) {
+            if i.is_label() && !i.is_data() {
+                if let Some(name) = i.get_label_name() {
+                    let symbol = Symbol::new(name, SymbolType::Label, c);
+                    if let Err(e) = self.symbols.add_symbol(symbol) {
+                        errors.push(e.at(location));
+                    }
+                }
+            }
+            if i.is_opcode() {
+                c += 4;
+            }
+        }
+        errors
     }
 }
 
-#[derive(Debug)]
+/// What the passes over a source produce
+struct Sections {
+    data: Vec<u8>,
+    code: Vec<u8>,
+    program: Program,
+    locations: Vec<SourceLocation>,
+    module: Option<ModuleTable>,
+}
+
+#[derive(Debug, Clone)]
 pub struct Symbol {
     name: String,
     offset: u32,
@@ -142,23 +1059,110 @@ impl Symbol {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum SymbolType {
+    /// A label of an instruction
     Label,
+    /// A label of a string in the data section
+    Data,
+    Constant,
+    /// A label declared `.extern`, defined by another object file
+    External,
+    /// A function of another module declared with `.import`, its value being the index of the
+    /// import
+    Import,
+    /// A function of the host declared with `.native`, its value being the index of the native
+    Native,
 }
 
-#[derive(Debug)]
+impl SymbolType {
+    /// Whether the symbol is a label, of an instruction or of data
+    pub fn is_label(&self) -> bool {
+        matches!(self, SymbolType::Label | SymbolType::Data)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SymbolAlreadyDefined {
+    pub name: String,
+}
+
+impl SymbolAlreadyDefined {
+    pub fn at(self, location: &SourceLocation) -> AssemblerError {
+        AssemblerError::SymbolAlreadyDefined {
+            name: self.name,
+            location: location.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct SymbolTable {
     symbols: Vec<Symbol>,
 }
 
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SymbolTable {
     pub fn new() -> SymbolTable {
         SymbolTable { symbols: vec![] }
     }
 
-    pub fn add_symbol(&mut self, s: Symbol) {
+    /// Adds a symbol, refusing names that are already defined
+    pub fn add_symbol(&mut self, s: Symbol) -> Result<(), SymbolAlreadyDefined> {
+        if self.symbol_value(&s.name).is_some() {
+            return Err(SymbolAlreadyDefined { name: s.name });
+        }
         self.symbols.push(s);
+        Ok(())
+    }
+
+    /// The value of a symbol if it is a label of this program
+    pub fn label_value(&self, s: &str) -> Option<u32> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == s && symbol.symbol_type.is_label())
+            .map(|symbol| symbol.offset)
+    }
+
+    /// The value of a symbol if it is the label of an instruction of this program
+    pub fn code_label_value(&self, s: &str) -> Option<u32> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == s && matches!(symbol.symbol_type, SymbolType::Label))
+            .map(|symbol| symbol.offset)
+    }
+
+    /// The labels and their values, in the order they were defined
+    pub fn labels(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.symbols
+            .iter()
+            .filter(|symbol| symbol.symbol_type.is_label())
+            .map(|symbol| (symbol.name.as_str(), symbol.offset))
+    }
+
+    /// The name of a label whose value is `offset`, the first one if there are several
+    pub fn label_at(&self, offset: u32) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.offset == offset && symbol.symbol_type.is_label())
+            .map(|symbol| symbol.name.as_str())
+    }
+
+    pub fn is_import(&self, s: &str) -> bool {
+        self.symbols
+            .iter()
+            .any(|symbol| symbol.name == s && matches!(symbol.symbol_type, SymbolType::Import))
+    }
+
+    pub fn is_native(&self, s: &str) -> bool {
+        self.symbols
+            .iter()
+            .any(|symbol| symbol.name == s && matches!(symbol.symbol_type, SymbolType::Native))
     }
 
     pub fn symbol_value(&self, s: &str) -> Option<u32> {
@@ -174,12 +1178,12 @@ impl SymbolTable {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::vm::VM;
+    use crate::vm::{ExitReason, VM};
     #[test]
     fn test_symbol_table() {
         let mut sym = SymbolTable::new();
         let new_symbol = Symbol::new("test".to_string(), SymbolType::Label, 12);
-        sym.add_symbol(new_symbol);
+        assert!(sym.add_symbol(new_symbol).is_ok());
         assert_eq!(sym.symbols.len(), 1);
         let v = sym.symbol_value("test");
         assert_eq!(true, v.is_some());
@@ -187,6 +1191,8 @@ mod tests {
         assert_eq!(v, 12);
         let v = sym.symbol_value("does_not_exist");
         assert_eq!(v.is_some(), false);
+        assert_eq!(sym.label_at(12), Some("test"));
+        assert_eq!(sym.label_at(13), None);
     }
 
     #[test]
@@ -196,8 +1202,540 @@ mod tests {
             "load $0 #100\nload $1 #1\nload $2 #0\ntest: inc $0\nneq $0 $2\njmpe @test\nhlt";
         let program = asm.assemble(test_string).unwrap();
         let mut vm = VM::new();
-        assert_eq!(program.len(), 89);
+        assert_eq!(program.len(), 93);
         vm.add_bytes(program);
-        assert_eq!(vm.program.len(), 89);
+        assert_eq!(vm.program.len(), 93);
+    }
+
+    #[test]
+    fn test_assemble_appended() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble("start: load $0 #1\nhlt").unwrap();
+        let address = program.len();
+        let code = asm
+            .assemble_appended(
+                "again: inc $0\nload $1 @start\nlt $0 $2\njeq @again\njeq @done\ndone: hlt",
+                address,
+            )
+            .unwrap();
+        assert_eq!(code.len(), 20);
+        assert_eq!(code[4..8], [1, 1, 0, PROGRAM_START as u8]);
+        assert_eq!(asm.symbols.label_value("again"), Some(address as u32));
+        assert_eq!(asm.symbols.label_value("done"), Some(address as u32 + 16));
+
+        let errors = asm
+            .assemble_appended("msg: .asciiz 'hi'\nhlt", address + 20)
+            .unwrap_err();
+        assert!(matches!(
+            &errors[..],
+            [AssemblerError::NotAppendable { directive, .. }] if directive == "asciiz"
+        ));
+        assert_eq!(asm.symbols.label_value("msg"), None);
+        assert!(asm.assemble_appended("again: hlt", address + 20).is_err());
+        assert_eq!(asm.symbols.label_value("again"), Some(address as u32));
+    }
+
+    #[test]
+    fn test_fuse_compare_and_branch() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("top: inc $0\nlt $0 $1\njeq @top\ngt $0 $1\njneq @top\nhlt")
+            .unwrap();
+        let body = &program[ELF_HEADER_LENGTH + 1..];
+        assert_eq!(
+            body,
+            [18, 0, 0, 0, 23, 0, 1, 0xFF, 25, 0, 1, 0xFE, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_no_fusion_for_labelled_jump() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble("eq $0 $1\nhere: jeq @here\nhlt").unwrap();
+        assert_eq!(program.len(), ELF_HEADER_LENGTH + 1 + 12);
+    }
+
+    #[test]
+    fn test_no_fusion_for_far_target() {
+        let mut asm = Assembler::new();
+        let source = format!(
+            "top: inc $0\n{}eq $0 $2\njeq @top\nhlt",
+            "inc $1\n".repeat(200)
+        );
+        let program = asm.assemble(&source).unwrap();
+        assert_eq!(program.len(), ELF_HEADER_LENGTH + 1 + 204 * 4);
+        let jump = ELF_HEADER_LENGTH + 1 + 202 * 4;
+        assert_eq!(Opcode::from(program[jump]), Opcode::JEQ);
+    }
+
+    #[test]
+    fn test_branch_too_far() {
+        let mut asm = Assembler::new();
+        let source = format!("top: inc $0\n{}beq $0 $1 @top\nhlt", "inc $1\n".repeat(200));
+        let errors = asm.assemble(&source).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::BranchTooFar {
+                name: "top".to_string(),
+                location: SourceLocation::new(202),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_branch_to_data_or_constant() {
+        let mut asm = Assembler::new();
+        let source = "msg: .asciiz 'hi'\n.equ TEN 10\nbeq $0 $1 @msg\nbne $0 $1 @TEN\nhlt";
+        let errors = asm.assemble(source).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::BadBranchTarget {
+                    name: "msg".to_string(),
+                    location: SourceLocation::new(3),
+                },
+                AssemblerError::BadBranchTarget {
+                    name: "TEN".to_string(),
+                    location: SourceLocation::new(4),
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_branch_without_label() {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble("beq $0 $1 #5\nhlt").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::InvalidOperands {
+                error: "BEQ expects two registers and a label".to_string(),
+                location: SourceLocation::new(1),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_undefined_label() {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble("eq $0 $1\njeq @nowhere\nhlt").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::UndefinedSymbol {
+                name: "nowhere".to_string(),
+                location: SourceLocation::new(2),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_assemble_with_macro() {
+        let mut asm = Assembler::new();
+        let source = ".macro countdown reg\nagain: dec \\reg\nbne \\reg $31 @again\n.endm\nload $0 #3\ncountdown $0\ncountdown $1\nhlt";
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(program.len(), ELF_HEADER_LENGTH + 1 + 24);
+        let start = PROGRAM_START as u32;
+        assert_eq!(asm.symbols.symbol_value("..again_1"), Some(start + 4));
+        assert_eq!(asm.symbols.symbol_value("..again_2"), Some(start + 12));
+    }
+
+    #[test]
+    fn test_assemble_macro_twice() {
+        let mut asm = Assembler::new();
+        let source = ".macro bump reg\ninc \\reg\n.endm\nbump $0\nhlt";
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(asm.assemble(source), Ok(program));
+    }
+
+    #[test]
+    fn test_label_on_invocation_of_labelled_body() {
+        let mut asm = Assembler::new();
+        let source = ".macro spin\nagain: dec $0\n.endm\nstart: spin\nhlt";
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(program.len(), PROGRAM_START + 8);
+        let start = PROGRAM_START as u32;
+        assert_eq!(asm.symbols.symbol_value("start"), Some(start));
+        assert_eq!(asm.symbols.symbol_value("..again_1"), Some(start));
+    }
+
+    #[test]
+    fn test_parse_error_in_macro_body() {
+        let mut asm = Assembler::new();
+        let source = "load $0 #1\n.macro broken\ninc $0\n%oops\n.endm\nbroken\nhlt";
+        let errors = asm.assemble(source).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::ParseError {
+                location: SourceLocation {
+                    file: None,
+                    line: 4,
+                    expanded_from: Some(("broken".to_string(), Box::new(SourceLocation::new(6)))),
+                },
+                error: "%oops".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_assemble_file_with_include() {
+        let dir = std::env::temp_dir().join("bumbam_assemble_file");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("lib.iasm"),
+            ".macro twice r\ninc \\r\ninc \\r\n.endm",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("main.iasm"),
+            ".include \"lib.iasm\"\ntwice $0\n!\nhlt",
+        )
+        .unwrap();
+        let mut asm = Assembler::new();
+        let errors = asm.assemble_file(&dir.join("main.iasm")).unwrap_err();
+        let location = match &errors[0] {
+            AssemblerError::ParseError { location, .. } => location,
+            e => panic!("unexpected error {:?}", e),
+        };
+        assert_eq!(location.line, 3);
+        assert!(location.file.as_ref().unwrap().ends_with("main.iasm"));
+    }
+
+    #[test]
+    fn test_assemble_constants() {
+        let mut asm = Assembler::new();
+        let source = ".equ BUF_SIZE 8\n.equ DOUBLE BUF_SIZE*2\nstart: load $0 #DOUBLE + 1\nload $1 @end - @start\nend: hlt\n.equ LEN @end - @start";
+        let program = asm.assemble(source).unwrap();
+        let body = &program[ELF_HEADER_LENGTH + 1..];
+        assert_eq!(body, [1, 0, 0, 17, 1, 1, 0, 8, 0, 0, 0, 0]);
+        assert_eq!(asm.symbols.symbol_value("LEN"), Some(8));
+    }
+
+    #[test]
+    fn test_constant_errors() {
+        let mut asm = Assembler::new();
+        let errors = asm
+            .assemble(".equ A LATER\n.equ LATER 1\n.bogus\nload $0 #1/0")
+            .unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(
+            matches!(&errors[0], AssemblerError::UndefinedSymbol { name, .. } if name == "LATER")
+        );
+        assert!(
+            matches!(&errors[1], AssemblerError::UnknownDirective { directive, .. } if directive == "bogus")
+        );
+    }
+
+    #[test]
+    fn test_operand_out_of_range() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble("load $0 #0xFFFF\nhlt").unwrap();
+        assert_eq!(program[ELF_HEADER_LENGTH + 1..][..4], [1, 0, 0xFF, 0xFF]);
+        let errors = asm
+            .assemble(".equ BIG 0x10000\nload $0 #BIG\nload $1 #-1\nload $2 #0x7FFFFFFF\nhlt")
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::OperandOutOfRange {
+                    value: 0x10000,
+                    location: SourceLocation::new(2),
+                },
+                AssemblerError::OperandOutOfRange {
+                    value: -1,
+                    location: SourceLocation::new(3),
+                },
+                AssemblerError::OperandOutOfRange {
+                    value: 0x7FFFFFFF,
+                    location: SourceLocation::new(4),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_register_aliases() {
+        let mut asm = Assembler::new();
+        let source = ".alias counter $3\n.alias limit $counter\ninc $limit\n.alias counter $sp\ndec $counter\nadd $a0 $a1 $v0\nhlt";
+        let program = asm.assemble(source).unwrap();
+        let body = &program[ELF_HEADER_LENGTH + 1..];
+        assert_eq!(body, [18, 3, 0, 0, 19, 31, 0, 0, 2, 2, 3, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_register_errors() {
+        let mut asm = Assembler::new();
+        let errors = asm
+            .assemble("inc $200\ninc $nope\n.alias sp $1\nhlt")
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::RegisterOutOfRange {
+                    register: "200".to_string(),
+                    location: SourceLocation::new(1),
+                },
+                AssemblerError::UnknownRegister {
+                    name: "nope".to_string(),
+                    location: SourceLocation::new(2),
+                },
+                AssemblerError::AliasShadowsRegister {
+                    name: "sp".to_string(),
+                    location: SourceLocation::new(3),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let mut asm = Assembler::new();
+        let source = "first: load $0 #1\n.loop: dec $0\nbne $0 $1 @.loop\nsecond: load $0 #2\n.loop: dec $0\nbne $0 $1 @.loop\nload $2 @first.loop\nhlt";
+        let program = asm.assemble(source).unwrap();
+        let start = PROGRAM_START as u32;
+        assert_eq!(asm.symbols.symbol_value("first.loop"), Some(start + 4));
+        assert_eq!(asm.symbols.symbol_value("second.loop"), Some(start + 16));
+        let body = &program[ELF_HEADER_LENGTH + 1..];
+        assert_eq!(&body[8..12], [21, 0, 1, 0xFF]);
+        assert_eq!(&body[20..28], [21, 0, 1, 0xFF, 1, 2, 0, 69]);
+    }
+
+    #[test]
+    fn test_numeric_labels() {
+        let mut asm = Assembler::new();
+        let source =
+            "1: dec $0\nbgt $0 $1 @1b\nbeq $0 $1 @1f\ninc $0\n1: dec $0\nbgt $0 $1 @1b\nhlt";
+        let program = asm.assemble(source).unwrap();
+        let body = &program[ELF_HEADER_LENGTH + 1..];
+        assert_eq!(&body[4..12], [22, 0, 1, 0xFF, 20, 0, 1, 2]);
+        assert_eq!(&body[20..24], [22, 0, 1, 0xFF]);
+        let errors = asm.assemble("bgt $0 $1 @1b\n1: hlt").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::UndefinedSymbol {
+                name: "1b".to_string(),
+                location: SourceLocation::new(1),
+            }]
+        );
+        // Without a number, `b` and `f` are ordinary labels
+        let program = asm
+            .assemble("b: inc $0\nf: dec $0\nbgt $0 $1 @b\nblt $0 $1 @f\nhlt")
+            .unwrap();
+        let body = &program[ELF_HEADER_LENGTH + 1..];
+        assert_eq!(&body[8..16], [22, 0, 1, 0xFE, 23, 0, 1, 0xFE]);
+    }
+
+    #[test]
+    fn test_duplicate_symbols() {
+        let mut asm = Assembler::new();
+        let errors = asm
+            .assemble("here: inc $0\nhere: dec $0\n.equ here 1\nhlt")
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::SymbolAlreadyDefined {
+                    name: "here".to_string(),
+                    location: SourceLocation::new(2),
+                },
+                AssemblerError::SymbolAlreadyDefined {
+                    name: "here".to_string(),
+                    location: SourceLocation::new(3),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_object_file() {
+        let mut asm = Assembler::new();
+        let source = ".global start\n.extern print\nmsg: .asciiz 'hi'\nstart: load $0 @msg\nload $1 @print\njmp $1";
+        let object = asm.assemble_object(source).unwrap();
+        assert_eq!(object.data, b"hi\0");
+        assert_eq!(object.code.len(), 12);
+        assert_eq!(
+            object.symbols,
+            vec![
+                ObjectSymbol {
+                    name: "msg".to_string(),
+                    section: Section::Data,
+                    offset: 0,
+                    exported: false
+                },
+                ObjectSymbol {
+                    name: "start".to_string(),
+                    section: Section::Code,
+                    offset: 0,
+                    exported: true
+                },
+                ObjectSymbol {
+                    name: "print".to_string(),
+                    section: Section::External,
+                    offset: 0,
+                    exported: false
+                },
+            ]
+        );
+        assert_eq!(
+            object.relocations,
+            vec![
+                Relocation {
+                    offset: 2,
+                    symbol: 0
+                },
+                Relocation {
+                    offset: 6,
+                    symbol: 2
+                }
+            ]
+        );
+        // Without linking, external labels are undefined
+        assert!(asm.assemble(source).is_err());
+    }
+
+    #[test]
+    fn test_object_file_errors() {
+        let mut asm = Assembler::new();
+        let errors = asm
+            .assemble_object(".global missing\n.extern f\na: load $0 @a + 4\nbeq $0 $1 @f")
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::NotRelocatable {
+                    name: "a".to_string(),
+                    location: SourceLocation::new(3),
+                },
+                AssemblerError::NotRelocatable {
+                    name: "f".to_string(),
+                    location: SourceLocation::new(4),
+                },
+            ]
+        );
+        let errors = asm.assemble_object(".global missing\nhlt").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::UndefinedSymbol {
+                name: "missing".to_string(),
+                location: SourceLocation::new(1),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_module_table() {
+        let mut asm = Assembler::new();
+        let source = ".import io print\n.module math\n.export double/1\ndouble: add $0 $0 $0\ncallext @io.print\nret";
+        let program = asm.assemble(source).unwrap();
+        let end = code_end(&program);
+        assert_eq!(end, PROGRAM_START + 12);
+        let table = ModuleTable::from_bytes(&program[end..]).unwrap();
+        assert_eq!(table.name, "math");
+        assert_eq!(
+            table.exports,
+            vec![Export {
+                name: "double".to_string(),
+                arity: 1,
+                address: PROGRAM_START as u32
+            }]
+        );
+        assert_eq!(
+            table.imports,
+            vec![Import {
+                module: "io".to_string(),
+                function: "print".to_string()
+            }]
+        );
+        assert_eq!(
+            &program[PROGRAM_START + 4..PROGRAM_START + 8],
+            [26, 0, 0, 0]
+        );
+        let object = asm.assemble_object(source).unwrap();
+        assert_eq!(object.module, Some(table));
+        let program = asm.assemble(".native now/0\ncalln @now\nhlt").unwrap();
+        let table = ModuleTable::from_bytes(&program[code_end(&program)..]).unwrap();
+        assert_eq!(
+            table.natives,
+            vec![Native {
+                name: "now".to_string(),
+                arity: 0
+            }]
+        );
+        assert_eq!(&program[PROGRAM_START..PROGRAM_START + 4], [28, 0, 0, 0]);
+        // Programs without module directives don't record where their code ends
+        let program = asm.assemble("hlt").unwrap();
+        assert_eq!(code_end(&program), program.len());
+    }
+
+    #[test]
+    fn test_module_table_errors() {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble("hlt\n.import a b\n.import a b").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::SymbolAlreadyDefined {
+                name: "a.b".to_string(),
+                location: SourceLocation::new(3),
+            }]
+        );
+        let errors = asm
+            .assemble(".export f/0\n.export g/0\nf: callext @f\n.module m\n.module n")
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::UndefinedSymbol {
+                    name: "g".to_string(),
+                    location: SourceLocation::new(2),
+                },
+                AssemblerError::NotAnImport {
+                    location: SourceLocation::new(3),
+                },
+                AssemblerError::ModuleAlreadyNamed {
+                    location: SourceLocation::new(5),
+                },
+            ]
+        );
+        let errors = asm
+            .assemble(".import a b\n.native now/0\ncalln @a.b\ncallext @now")
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                AssemblerError::NotANative {
+                    location: SourceLocation::new(3),
+                },
+                AssemblerError::NotAnImport {
+                    location: SourceLocation::new(4),
+                },
+            ]
+        );
+        let errors = asm.assemble(".export f/0\nf: hlt").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::MissingModuleName {
+                location: SourceLocation::new(1),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_data_section() {
+        let mut asm = Assembler::new();
+        let source = "hello: .asciiz 'Hi!'\nempty: .asciiz ''\nload $0 @hello\nload $1 @empty\nstart: jmp $1\nload $2 @start\nhlt";
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(data_length(&program), Some(5));
+        assert_eq!(&program[PROGRAM_START..PROGRAM_START + 5], b"Hi!\0\0");
+        let code = &program[PROGRAM_START + 5..];
+        assert_eq!(&code[..4], [1, 0, 0, 65]);
+        assert_eq!(&code[4..8], [1, 1, 0, 69]);
+        assert_eq!(&code[12..16], [1, 2, 0, 78]);
+
+        let mut vm = VM::new();
+        vm.add_bytes(
+            asm.assemble("load $0 @end\njmp $0\nload $1 #1\nend: hlt")
+                .unwrap(),
+        );
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(vm.registers[1], 0);
     }
 }
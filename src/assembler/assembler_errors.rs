@@ -0,0 +1,364 @@
+use super::expression_parsers::ExpressionError;
+use super::instruction_parsers::EncodingError;
+use crate::vm::REGISTER_COUNT;
+use std::fmt;
+
+/// Where a line handed to the parser originally came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    /// File the line was read from, if the source came from a file
+    pub file: Option<String>,
+    /// 1-based line number in the source
+    pub line: usize,
+    /// For lines produced by a macro, the name of the macro and the location of its invocation
+    pub expanded_from: Option<(String, Box<SourceLocation>)>,
+}
+
+impl SourceLocation {
+    pub fn new(line: usize) -> SourceLocation {
+        SourceLocation {
+            file: None,
+            line,
+            expanded_from: None,
+        }
+    }
+
+    pub fn in_file(file: &str, line: usize) -> SourceLocation {
+        SourceLocation {
+            file: Some(file.to_string()),
+            line,
+            expanded_from: None,
+        }
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file, self.line)?,
+            None => write!(f, "line {}", self.line)?,
+        }
+        if let Some((name, call_site)) = &self.expanded_from {
+            write!(f, " (in macro '{}', expanded at {})", name, call_site)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssemblerError {
+    FileNotReadable {
+        path: String,
+        error: String,
+    },
+    ParseError {
+        location: SourceLocation,
+        error: String,
+    },
+    MacroAlreadyDefined {
+        name: String,
+        location: SourceLocation,
+    },
+    UnterminatedMacro {
+        name: String,
+        location: SourceLocation,
+    },
+    UnmatchedEndMacro {
+        location: SourceLocation,
+    },
+    MissingMacroName {
+        location: SourceLocation,
+    },
+    MacroArgumentMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+        location: SourceLocation,
+    },
+    MacroExpansionTooDeep {
+        name: String,
+        location: SourceLocation,
+    },
+    UnknownDirective {
+        directive: String,
+        location: SourceLocation,
+    },
+    UndefinedSymbol {
+        name: String,
+        location: SourceLocation,
+    },
+    InvalidExpression {
+        error: String,
+        location: SourceLocation,
+    },
+    SymbolAlreadyDefined {
+        name: String,
+        location: SourceLocation,
+    },
+    ModuleAlreadyNamed {
+        location: SourceLocation,
+    },
+    MissingModuleName {
+        location: SourceLocation,
+    },
+    NotAnImport {
+        location: SourceLocation,
+    },
+    NotANative {
+        location: SourceLocation,
+    },
+    NotRelocatable {
+        name: String,
+        location: SourceLocation,
+    },
+    InvalidString {
+        error: String,
+        location: SourceLocation,
+    },
+    UnknownRegister {
+        name: String,
+        location: SourceLocation,
+    },
+    RegisterOutOfRange {
+        register: String,
+        location: SourceLocation,
+    },
+    AliasShadowsRegister {
+        name: String,
+        location: SourceLocation,
+    },
+    IncludeNotReadable {
+        path: String,
+        error: String,
+        location: SourceLocation,
+    },
+    IncludeCycle {
+        path: String,
+        location: SourceLocation,
+    },
+    IncludeTooDeep {
+        path: String,
+        location: SourceLocation,
+    },
+    InvalidOperands {
+        error: String,
+        location: SourceLocation,
+    },
+    BranchTooFar {
+        name: String,
+        location: SourceLocation,
+    },
+    /// A compare-and-branch to a data label, a constant or an address between instructions
+    BadBranchTarget {
+        name: String,
+        location: SourceLocation,
+    },
+    OperandOutOfRange {
+        value: i64,
+        location: SourceLocation,
+    },
+    /// Data and module directives, which can't go at the end of a program that is already laid
+    /// out
+    NotAppendable {
+        directive: String,
+        location: SourceLocation,
+    },
+}
+
+impl AssemblerError {
+    /// Attaches a location to a failed expression evaluation
+    pub fn from_expression(error: ExpressionError, location: &SourceLocation) -> AssemblerError {
+        let location = location.clone();
+        match error {
+            ExpressionError::UndefinedSymbol(name) => {
+                AssemblerError::UndefinedSymbol { name, location }
+            }
+            ExpressionError::DivisionByZero => AssemblerError::InvalidExpression {
+                error: "division by zero".to_string(),
+                location,
+            },
+            ExpressionError::Overflow => AssemblerError::InvalidExpression {
+                error: "value does not fit in 32 bits".to_string(),
+                location,
+            },
+        }
+    }
+
+    /// Attaches a location to an instruction that could not be encoded
+    pub fn from_encoding(error: EncodingError, location: &SourceLocation) -> AssemblerError {
+        let location = location.clone();
+        match error {
+            EncodingError::InvalidOperands(error) => {
+                AssemblerError::InvalidOperands { error, location }
+            }
+            EncodingError::UnknownRegister(name) => {
+                AssemblerError::UnknownRegister { name, location }
+            }
+            EncodingError::BranchTooFar(name) => AssemblerError::BranchTooFar { name, location },
+            EncodingError::BadBranchTarget(name) => {
+                AssemblerError::BadBranchTarget { name, location }
+            }
+            EncodingError::OutOfRange(value) => {
+                AssemblerError::OperandOutOfRange { value, location }
+            }
+            EncodingError::Expression(e) => AssemblerError::from_expression(e, &location),
+        }
+    }
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblerError::FileNotReadable { path, error } => {
+                write!(f, "unable to read '{}': {}", path, error)
+            }
+            AssemblerError::ParseError { location, error } => {
+                write!(f, "{}: unable to parse '{}'", location, error)
+            }
+            AssemblerError::MacroAlreadyDefined { name, location } => {
+                write!(f, "{}: macro '{}' is already defined", location, name)
+            }
+            AssemblerError::UnterminatedMacro { name, location } => {
+                write!(f, "{}: macro '{}' has no matching .endm", location, name)
+            }
+            AssemblerError::UnmatchedEndMacro { location } => {
+                write!(f, "{}: .endm without a matching .macro", location)
+            }
+            AssemblerError::MissingMacroName { location } => {
+                write!(f, "{}: .macro needs a name", location)
+            }
+            AssemblerError::MacroArgumentMismatch {
+                name,
+                expected,
+                found,
+                location,
+            } => write!(
+                f,
+                "{}: macro '{}' takes {} argument(s) but {} were given",
+                location, name, expected, found
+            ),
+            AssemblerError::MacroExpansionTooDeep { name, location } => write!(
+                f,
+                "{}: expansion of macro '{}' nests too deeply, is it recursive?",
+                location, name
+            ),
+            AssemblerError::UnknownDirective {
+                directive,
+                location,
+            } => write!(f, "{}: unknown directive '.{}'", location, directive),
+            AssemblerError::UndefinedSymbol { name, location } => {
+                write!(f, "{}: '{}' is not defined", location, name)
+            }
+            AssemblerError::InvalidExpression { error, location } => {
+                write!(f, "{}: invalid expression, {}", location, error)
+            }
+            AssemblerError::SymbolAlreadyDefined { name, location } => {
+                write!(f, "{}: '{}' is already defined", location, name)
+            }
+            AssemblerError::ModuleAlreadyNamed { location } => {
+                write!(f, "{}: the module already has a name", location)
+            }
+            AssemblerError::MissingModuleName { location } => write!(
+                f,
+                "{}: exporting functions needs a .module directive",
+                location
+            ),
+            AssemblerError::NotAnImport { location } => write!(
+                f,
+                "{}: CALLEXT expects a function declared with .import, as @module.function",
+                location
+            ),
+            AssemblerError::NotANative { location } => write!(
+                f,
+                "{}: CALLN expects a function declared with .native, as @name",
+                location
+            ),
+            AssemblerError::NotRelocatable { name, location } => write!(
+                f,
+                "{}: the address of '{}' is not known until linking and can't be used here",
+                location, name
+            ),
+            AssemblerError::InvalidString { error, location } => {
+                write!(f, "{}: invalid string, {}", location, error)
+            }
+            AssemblerError::UnknownRegister { name, location } => {
+                write!(f, "{}: unknown register '${}'", location, name)
+            }
+            AssemblerError::RegisterOutOfRange { register, location } => write!(
+                f,
+                "{}: register '${}' does not exist, there are only {}",
+                location, register, REGISTER_COUNT
+            ),
+            AssemblerError::AliasShadowsRegister { name, location } => write!(
+                f,
+                "{}: alias '{}' would hide the register '${}'",
+                location, name, name
+            ),
+            AssemblerError::IncludeNotReadable {
+                path,
+                error,
+                location,
+            } => write!(f, "{}: unable to include '{}': {}", location, path, error),
+            AssemblerError::IncludeCycle { path, location } => {
+                write!(f, "{}: '{}' is already being included", location, path)
+            }
+            AssemblerError::IncludeTooDeep { path, location } => {
+                write!(f, "{}: including '{}' nests too deeply", location, path)
+            }
+            AssemblerError::InvalidOperands { error, location } => {
+                write!(f, "{}: invalid operands, {}", location, error)
+            }
+            AssemblerError::BranchTooFar { name, location } => write!(
+                f,
+                "{}: '{}' is too far away to branch to, use a comparison and a jump",
+                location, name
+            ),
+            AssemblerError::BadBranchTarget { name, location } => {
+                write!(
+                    f,
+                    "{}: '{}' is not an instruction to branch to",
+                    location, name
+                )
+            }
+            AssemblerError::OperandOutOfRange { value, location } => write!(
+                f,
+                "{}: {} does not fit in an operand, which goes from 0 to {}",
+                location,
+                value,
+                u16::MAX
+            ),
+            AssemblerError::NotAppendable {
+                directive,
+                location,
+            } => write!(
+                f,
+                "{}: .{} can't be used in code appended to a program",
+                location, directive
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_macro_location() {
+        let location = SourceLocation {
+            file: None,
+            line: 2,
+            expanded_from: Some(("bump".to_string(), Box::new(SourceLocation::new(9)))),
+        };
+        assert_eq!(
+            location.to_string(),
+            "line 2 (in macro 'bump', expanded at line 9)"
+        );
+    }
+
+    #[test]
+    fn test_display_file_location() {
+        let location = SourceLocation::in_file("lib/math.iasm", 3);
+        assert_eq!(location.to_string(), "lib/math.iasm:3");
+    }
+}
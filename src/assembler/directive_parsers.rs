@@ -1,13 +1,16 @@
+use super::expression_parsers::{expression, identifier};
 use super::instruction_parsers::AssemblerInstruction;
-use super::label_parsers::label_declaration;
+use super::label_parsers::{label_declaration, label_name};
 use super::operand_parsers::operand;
+use super::register_parsers::register;
 use super::Token;
 use nom::{
+    branch::alt,
     bytes::complete::tag,
-    character::complete::{alphanumeric0, multispace0},
-    combinator::opt,
+    character::complete::{alphanumeric0, char, digit1, multispace0, multispace1, space1},
+    combinator::{map_res, opt},
     sequence::tuple,
-    sequence::{preceded, terminated},
+    sequence::{delimited, preceded, terminated},
     IResult,
 };
 
@@ -42,13 +45,185 @@ fn directive_combined(input: &str) -> IResult<&str, AssemblerInstruction> {
     ))
 }
 
+/// Parses `.equ NAME expr`, which defines a named constant
+fn equ_directive(input: &str) -> IResult<&str, AssemblerInstruction> {
+    let (leftover, (_, name, expr)) = tuple((
+        delimited(multispace0, tag(".equ"), multispace1),
+        terminated(identifier, space1),
+        terminated(expression, multispace0),
+    ))(input)?;
+    Ok((
+        leftover,
+        AssemblerInstruction {
+            label: None,
+            directive: Some(Token::Directive {
+                name: "equ".to_string(),
+            }),
+            opcode: None,
+            operand1: Some(Token::ConstantDeclaration {
+                name: name.to_string(),
+            }),
+            operand2: Some(Token::Expression { expr }),
+            operand3: None,
+        },
+    ))
+}
+
+/// Parses `.alias NAME $register`, which gives a register another name
+fn alias_directive(input: &str) -> IResult<&str, AssemblerInstruction> {
+    let (leftover, (_, name, reg)) = tuple((
+        delimited(multispace0, tag(".alias"), multispace1),
+        terminated(identifier, space1),
+        register,
+    ))(input)?;
+    Ok((
+        leftover,
+        AssemblerInstruction {
+            label: None,
+            directive: Some(Token::Directive {
+                name: "alias".to_string(),
+            }),
+            opcode: None,
+            operand1: Some(Token::AliasDeclaration {
+                name: name.to_string(),
+            }),
+            operand2: Some(reg),
+            operand3: None,
+        },
+    ))
+}
+
+/// Parses `.global name`, which lets other object files use a label, and `.extern name`, which
+/// declares a label defined by another object file
+fn linkage_directive(input: &str) -> IResult<&str, AssemblerInstruction> {
+    let (leftover, (_, dir, name)) = tuple((
+        multispace0,
+        terminated(
+            preceded(tag("."), alt((tag("global"), tag("extern")))),
+            space1,
+        ),
+        terminated(label_name, multispace0),
+    ))(input)?;
+    Ok((
+        leftover,
+        AssemblerInstruction {
+            label: None,
+            directive: Some(Token::Directive {
+                name: dir.to_string(),
+            }),
+            opcode: None,
+            operand1: Some(Token::LabelUsage {
+                name: name.to_string(),
+            }),
+            operand2: None,
+            operand3: None,
+        },
+    ))
+}
+
+/// Builds a directive with the given operands
+fn module_instruction(
+    name: &str,
+    operand1: Token,
+    operand2: Option<Token>,
+) -> AssemblerInstruction {
+    AssemblerInstruction {
+        label: None,
+        directive: Some(Token::Directive {
+            name: name.to_string(),
+        }),
+        opcode: None,
+        operand1: Some(operand1),
+        operand2,
+        operand3: None,
+    }
+}
+
+/// Parses `.module name`, which names the module the file defines
+fn module_directive(input: &str) -> IResult<&str, AssemblerInstruction> {
+    let (leftover, name) = delimited(
+        tuple((multispace0, tag(".module"), space1)),
+        identifier,
+        multispace0,
+    )(input)?;
+    let name = Token::ModuleName {
+        name: name.to_string(),
+    };
+    Ok((leftover, module_instruction("module", name, None)))
+}
+
+/// Parses `.export name/arity`, which lets other modules call the function at label `name`
+fn export_directive(input: &str) -> IResult<&str, AssemblerInstruction> {
+    let (leftover, (_, name, _, arity)) = tuple((
+        tuple((multispace0, tag(".export"), space1)),
+        label_name,
+        char('/'),
+        terminated(map_res(digit1, |d: &str| d.parse::<u8>()), multispace0),
+    ))(input)?;
+    let name = Token::LabelUsage {
+        name: name.to_string(),
+    };
+    let arity = Token::IntegerOperand {
+        value: i32::from(arity),
+    };
+    Ok((leftover, module_instruction("export", name, Some(arity))))
+}
+
+/// Parses `.import module function`, after which `CALLEXT @module.function` calls it
+fn import_directive(input: &str) -> IResult<&str, AssemblerInstruction> {
+    let (leftover, (_, module, _, function)) = tuple((
+        tuple((multispace0, tag(".import"), space1)),
+        identifier,
+        space1,
+        terminated(identifier, multispace0),
+    ))(input)?;
+    let module = Token::ModuleName {
+        name: module.to_string(),
+    };
+    let function = Token::FunctionName {
+        name: function.to_string(),
+    };
+    Ok((
+        leftover,
+        module_instruction("import", module, Some(function)),
+    ))
+}
+
+/// Parses `.native name/arity`, after which `CALLN @name` calls the function the host
+/// registered under that name
+fn native_directive(input: &str) -> IResult<&str, AssemblerInstruction> {
+    let (leftover, (_, name, _, arity)) = tuple((
+        tuple((multispace0, tag(".native"), space1)),
+        identifier,
+        char('/'),
+        terminated(map_res(digit1, |d: &str| d.parse::<u8>()), multispace0),
+    ))(input)?;
+    let name = Token::FunctionName {
+        name: name.to_string(),
+    };
+    let arity = Token::IntegerOperand {
+        value: i32::from(arity),
+    };
+    Ok((leftover, module_instruction("native", name, Some(arity))))
+}
+
 pub fn directive(input: &str) -> IResult<&str, AssemblerInstruction> {
-    directive_combined(input)
+    alt((
+        equ_directive,
+        alias_directive,
+        linkage_directive,
+        module_directive,
+        export_directive,
+        import_directive,
+        native_directive,
+        directive_combined,
+    ))(input)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::expression_parsers::{BinaryOp, Expression};
     use crate::assembler::Token;
 
     #[test]
@@ -97,4 +272,83 @@ mod tests {
         assert_eq!(None, p.operand2);
         assert_eq!(None, p.operand3);
     }
+    #[test]
+    fn test_parse_linkage_directives() {
+        let (leftover, p) = directive(".global main\nhlt").unwrap();
+        assert_eq!(leftover, "hlt");
+        assert_eq!(p.get_directive_name(), Some("global".to_string()));
+        assert_eq!(p.get_label_usage(), Some("main"));
+        let (_, p) = directive(".extern print").unwrap();
+        assert_eq!(p.get_directive_name(), Some("extern".to_string()));
+        assert_eq!(p.get_label_usage(), Some("print"));
+    }
+    #[test]
+    fn test_parse_module_directives() {
+        let (_, p) = directive(".module math\n").unwrap();
+        assert_eq!(
+            p.operand1,
+            Some(Token::ModuleName {
+                name: "math".to_string()
+            })
+        );
+        let (_, p) = directive(".export double/1").unwrap();
+        assert_eq!(p.get_label_usage(), Some("double"));
+        assert_eq!(p.operand2, Some(Token::IntegerOperand { value: 1 }));
+        let (leftover, p) = directive(".import io print\nhlt").unwrap();
+        assert_eq!(leftover, "hlt");
+        assert_eq!(p.get_directive_name(), Some("import".to_string()));
+        assert_eq!(
+            p.operand2,
+            Some(Token::FunctionName {
+                name: "print".to_string()
+            })
+        );
+        assert!(directive(".export double").unwrap().1.operand1.is_none());
+        let (_, p) = directive(".native now/0").unwrap();
+        assert_eq!(p.get_directive_name(), Some("native".to_string()));
+        assert_eq!(
+            p.operand1,
+            Some(Token::FunctionName {
+                name: "now".to_string()
+            })
+        );
+        assert_eq!(p.operand2, Some(Token::IntegerOperand { value: 0 }));
+    }
+    #[test]
+    fn test_parse_equ_directive() {
+        let result = directive(".equ BUF_SIZE 4 * 2\nhlt");
+        assert!(result.is_ok());
+        let (leftover, p) = result.unwrap();
+        assert_eq!(leftover, "hlt");
+        assert_eq!(
+            Some(Token::ConstantDeclaration {
+                name: "BUF_SIZE".to_string()
+            }),
+            p.operand1
+        );
+        assert_eq!(
+            Some(Token::Expression {
+                expr: Expression::Binary(
+                    BinaryOp::Mul,
+                    Box::new(Expression::Number(4)),
+                    Box::new(Expression::Number(2))
+                )
+            }),
+            p.operand2
+        );
+    }
+    #[test]
+    fn test_parse_alias_directive() {
+        let result = directive(".alias counter $3\n");
+        assert!(result.is_ok());
+        let (leftover, p) = result.unwrap();
+        assert_eq!(leftover, "");
+        assert_eq!(
+            Some(Token::AliasDeclaration {
+                name: "counter".to_string()
+            }),
+            p.operand1
+        );
+        assert_eq!(Some(Token::Register { reg_num: 3 }), p.operand2);
+    }
 }
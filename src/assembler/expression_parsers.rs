@@ -0,0 +1,290 @@
+use crate::assembler::label_parsers::label_reference;
+use crate::assembler::SymbolTable;
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{alpha1, alphanumeric1, char, digit1, hex_digit1, space0},
+    combinator::{map, map_res, recognize},
+    multi::{fold_many0, many0},
+    sequence::{delimited, pair, preceded},
+    IResult,
+};
+use std::convert::TryFrom;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+/// An integer expression over literals, named constants and label addresses
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expression {
+    Number(i32),
+    Constant(String),
+    Label(String),
+    Unary(UnaryOp, Box<Expression>),
+    Binary(BinaryOp, Box<Expression>, Box<Expression>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExpressionError {
+    UndefinedSymbol(String),
+    DivisionByZero,
+    Overflow,
+}
+
+impl Expression {
+    /// Computes the value of the expression, looking names up in `symbols`
+    pub fn evaluate(&self, symbols: &SymbolTable) -> Result<i32, ExpressionError> {
+        match self {
+            Expression::Number(n) => Ok(*n),
+            Expression::Constant(name) | Expression::Label(name) => symbols
+                .symbol_value(name)
+                .map(|v| v as i32)
+                .ok_or_else(|| ExpressionError::UndefinedSymbol(name.clone())),
+            Expression::Unary(op, e) => {
+                let v = e.evaluate(symbols)?;
+                match op {
+                    UnaryOp::Neg => v.checked_neg().ok_or(ExpressionError::Overflow),
+                    UnaryOp::Not => Ok(!v),
+                }
+            }
+            Expression::Binary(op, l, r) => {
+                let l = l.evaluate(symbols)?;
+                let r = r.evaluate(symbols)?;
+                let result = match op {
+                    BinaryOp::Add => l.checked_add(r),
+                    BinaryOp::Sub => l.checked_sub(r),
+                    BinaryOp::Mul => l.checked_mul(r),
+                    BinaryOp::Div | BinaryOp::Rem if r == 0 => {
+                        return Err(ExpressionError::DivisionByZero)
+                    }
+                    BinaryOp::Div => l.checked_div(r),
+                    BinaryOp::Rem => l.checked_rem(r),
+                    BinaryOp::And => Some(l & r),
+                    BinaryOp::Or => Some(l | r),
+                    BinaryOp::Xor => Some(l ^ r),
+                    BinaryOp::Shl => u32::try_from(r).ok().and_then(|r| l.checked_shl(r)),
+                    BinaryOp::Shr => u32::try_from(r).ok().and_then(|r| l.checked_shr(r)),
+                };
+                result.ok_or(ExpressionError::Overflow)
+            }
+        }
+    }
+
+    /// Mutable access to the names of the labels the expression refers to
+    pub fn labels_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Expression::Label(name) => vec![name],
+            Expression::Number(_) | Expression::Constant(_) => vec![],
+            Expression::Unary(_, e) => e.labels_mut(),
+            Expression::Binary(_, l, r) => {
+                let mut names = l.labels_mut();
+                names.append(&mut r.labels_mut());
+                names
+            }
+        }
+    }
+
+    /// Names of the labels the expression refers to
+    pub fn labels(&self) -> Vec<&str> {
+        match self {
+            Expression::Label(name) => vec![name.as_str()],
+            Expression::Number(_) | Expression::Constant(_) => vec![],
+            Expression::Unary(_, e) => e.labels(),
+            Expression::Binary(_, l, r) => {
+                let mut names = l.labels();
+                names.append(&mut r.labels());
+                names
+            }
+        }
+    }
+
+    /// Names of every constant and label the expression refers to
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expression::Number(_) => vec![],
+            Expression::Constant(name) | Expression::Label(name) => vec![name.as_str()],
+            Expression::Unary(_, e) => e.symbols(),
+            Expression::Binary(_, l, r) => {
+                let mut names = l.symbols();
+                names.append(&mut r.symbols());
+                names
+            }
+        }
+    }
+}
+
+/// Names of constants and labels: a letter or `_` followed by letters, digits and `_`
+pub fn identifier(input: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        alt((alpha1, tag("_"))),
+        many0(alt((alphanumeric1, tag("_")))),
+    ))(input)
+}
+
+fn number(input: &str) -> IResult<&str, Expression> {
+    alt((
+        map_res(preceded(tag("0x"), hex_digit1), |h| {
+            i32::from_str_radix(h, 16).map(Expression::Number)
+        }),
+        map_res(digit1, |d: &str| d.parse::<i32>().map(Expression::Number)),
+    ))(input)
+}
+
+fn atom(input: &str) -> IResult<&str, Expression> {
+    delimited(
+        space0,
+        alt((
+            number,
+            map(preceded(char('@'), label_reference), |n| {
+                Expression::Label(n.to_string())
+            }),
+            map(identifier, |n| Expression::Constant(n.to_string())),
+            delimited(char('('), expression, preceded(space0, char(')'))),
+            map(preceded(char('-'), atom), |e| {
+                Expression::Unary(UnaryOp::Neg, Box::new(e))
+            }),
+            map(preceded(char('~'), atom), |e| {
+                Expression::Unary(UnaryOp::Not, Box::new(e))
+            }),
+        )),
+        space0,
+    )(input)
+}
+
+/// Parses one level of left-associative binary operators on top of `operand`
+fn binary_level<'a>(
+    input: &'a str,
+    operand: fn(&'a str) -> IResult<&'a str, Expression>,
+    operator: fn(&'a str) -> IResult<&'a str, BinaryOp>,
+) -> IResult<&'a str, Expression> {
+    let (input, first) = operand(input)?;
+    fold_many0(
+        pair(operator, operand),
+        move || first.clone(),
+        |l, (op, r)| Expression::Binary(op, Box::new(l), Box::new(r)),
+    )(input)
+}
+
+fn product(input: &str) -> IResult<&str, Expression> {
+    binary_level(input, atom, |i| {
+        alt((
+            map(char('*'), |_| BinaryOp::Mul),
+            map(char('/'), |_| BinaryOp::Div),
+            map(char('%'), |_| BinaryOp::Rem),
+        ))(i)
+    })
+}
+
+fn sum(input: &str) -> IResult<&str, Expression> {
+    binary_level(input, product, |i| {
+        alt((
+            map(char('+'), |_| BinaryOp::Add),
+            map(char('-'), |_| BinaryOp::Sub),
+        ))(i)
+    })
+}
+
+fn shift(input: &str) -> IResult<&str, Expression> {
+    binary_level(input, sum, |i| {
+        alt((
+            map(tag("<<"), |_| BinaryOp::Shl),
+            map(tag(">>"), |_| BinaryOp::Shr),
+        ))(i)
+    })
+}
+
+fn bit_and(input: &str) -> IResult<&str, Expression> {
+    binary_level(input, shift, |i| map(char('&'), |_| BinaryOp::And)(i))
+}
+
+fn bit_xor(input: &str) -> IResult<&str, Expression> {
+    binary_level(input, bit_and, |i| map(char('^'), |_| BinaryOp::Xor)(i))
+}
+
+/// Parses an expression. Operators bind like in C, and whitespace around them may not include
+/// line breaks, so an expression never runs into the next line.
+pub fn expression(input: &str) -> IResult<&str, Expression> {
+    binary_level(input, bit_xor, |i| map(char('|'), |_| BinaryOp::Or)(i))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{Symbol, SymbolType};
+
+    #[test]
+    fn test_parse_precedence() {
+        let (rest, e) = expression("1 + 2 * 3").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(e.evaluate(&SymbolTable::new()), Ok(7));
+        let (_, e) = expression("(1 + 2) * 3").unwrap();
+        assert_eq!(e.evaluate(&SymbolTable::new()), Ok(9));
+        let (_, e) = expression("1 << 4 | 0x3 & ~1").unwrap();
+        assert_eq!(e.evaluate(&SymbolTable::new()), Ok(18));
+        let (_, e) = expression("-4 - -4").unwrap();
+        assert_eq!(e.evaluate(&SymbolTable::new()), Ok(0));
+    }
+
+    #[test]
+    fn test_parse_number_out_of_range() {
+        let (rest, e) = expression("0x7FFFFFFF").unwrap();
+        assert_eq!((rest, e), ("", Expression::Number(i32::MAX)));
+        let (rest, _) = expression("0x100000000").unwrap();
+        assert_eq!(rest, "x100000000");
+        assert!(expression("2147483648").is_err());
+    }
+
+    #[test]
+    fn test_parse_stops_at_line_end() {
+        let (rest, e) = expression("@loop\nhlt").unwrap();
+        assert_eq!(rest, "\nhlt");
+        assert_eq!(e, Expression::Label("loop".to_string()));
+    }
+
+    #[test]
+    fn test_evaluate_symbols() {
+        let mut symbols = SymbolTable::new();
+        symbols
+            .add_symbol(Symbol::new(
+                "BUF_SIZE".to_string(),
+                SymbolType::Constant,
+                16,
+            ))
+            .unwrap();
+        symbols
+            .add_symbol(Symbol::new("start".to_string(), SymbolType::Label, 4))
+            .unwrap();
+        symbols
+            .add_symbol(Symbol::new("end".to_string(), SymbolType::Label, 20))
+            .unwrap();
+        let (_, e) = expression("BUF_SIZE*2").unwrap();
+        assert_eq!(e.evaluate(&symbols), Ok(32));
+        let (_, e) = expression("@end - @start").unwrap();
+        assert_eq!(e.evaluate(&symbols), Ok(16));
+        assert_eq!(e.symbols(), vec!["end", "start"]);
+        let (_, e) = expression("MISSING + 1").unwrap();
+        assert_eq!(
+            e.evaluate(&symbols),
+            Err(ExpressionError::UndefinedSymbol("MISSING".to_string()))
+        );
+        let (_, e) = expression("1 / (BUF_SIZE - 16)").unwrap();
+        assert_eq!(e.evaluate(&symbols), Err(ExpressionError::DivisionByZero));
+    }
+}
@@ -0,0 +1,146 @@
+use super::assembler_errors::AssemblerError;
+use super::macro_expander::{file_lines, SourceLine};
+use nom::{
+    bytes::complete::{is_not, tag},
+    character::complete::{multispace0, multispace1},
+    sequence::{delimited, preceded, tuple},
+    IResult,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How many files deep `.include` may nest
+pub const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Parses `.include "path"` and returns the path
+fn include_directive(input: &str) -> IResult<&str, &str> {
+    preceded(
+        tuple((multispace0, tag(".include"), multispace1)),
+        delimited(tag("\""), is_not("\""), tag("\"")),
+    )(input)
+}
+
+/// Replaces every `.include "path"` line with the lines of the named file, recursively. Paths
+/// are resolved relative to the directory of the including file, `base` for the top level.
+/// `stack` holds the files currently being included, outermost first.
+pub fn resolve_includes(
+    lines: Vec<SourceLine>,
+    base: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Vec<SourceLine>, Vec<AssemblerError>> {
+    let mut resolved = vec![];
+    let mut errors = vec![];
+    for line in lines {
+        let path = match include_directive(&line.text) {
+            Ok((rest, path)) if rest.trim().is_empty() => base.join(path),
+            _ => {
+                resolved.push(line);
+                continue;
+            }
+        };
+        let name = path.display().to_string();
+        let location = line.location;
+        if stack.len() >= MAX_INCLUDE_DEPTH {
+            errors.push(AssemblerError::IncludeTooDeep {
+                path: name,
+                location,
+            });
+            continue;
+        }
+        let (canonical, contents) =
+            match fs::canonicalize(&path).and_then(|c| fs::read_to_string(&c).map(|s| (c, s))) {
+                Ok(read) => read,
+                Err(e) => {
+                    errors.push(AssemblerError::IncludeNotReadable {
+                        path: name,
+                        error: e.to_string(),
+                        location,
+                    });
+                    continue;
+                }
+            };
+        if stack.contains(&canonical) {
+            errors.push(AssemblerError::IncludeCycle {
+                path: name,
+                location,
+            });
+            continue;
+        }
+        let dir = path.parent().unwrap_or(base).to_path_buf();
+        stack.push(canonical);
+        match resolve_includes(file_lines(&contents, &name), &dir, stack) {
+            Ok(mut included) => resolved.append(&mut included),
+            Err(mut e) => errors.append(&mut e),
+        }
+        stack.pop();
+    }
+    if errors.is_empty() {
+        Ok(resolved)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assembler_errors::SourceLocation;
+    use crate::assembler::macro_expander::source_lines;
+    use std::env;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("bumbam_include_{}", name));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_include_directive() {
+        let result = include_directive(".include \"lib/math.iasm\"");
+        assert_eq!(result, Ok(("", "lib/math.iasm")));
+        assert!(include_directive(".include lib/math.iasm").is_err());
+    }
+
+    #[test]
+    fn test_resolve_nested_includes() {
+        let dir = scratch_dir("nested");
+        fs::write(dir.join("lib/a.iasm"), "inc $0\n.include \"b.iasm\"").unwrap();
+        fs::write(dir.join("lib/b.iasm"), "dec $0").unwrap();
+        let lines = source_lines(".include \"lib/a.iasm\"\nhlt");
+        let resolved = resolve_includes(lines, &dir, &mut vec![]).unwrap();
+        let texts: Vec<&str> = resolved.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, vec!["inc $0", "dec $0", "hlt"]);
+        assert_eq!(
+            resolved[1].location,
+            SourceLocation::in_file(&dir.join("lib/b.iasm").display().to_string(), 1)
+        );
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = scratch_dir("cycle");
+        fs::write(dir.join("lib/a.iasm"), ".include \"b.iasm\"").unwrap();
+        fs::write(dir.join("lib/b.iasm"), "inc $0\n.include \"a.iasm\"").unwrap();
+        let lines = source_lines(".include \"lib/a.iasm\"");
+        let errors = resolve_includes(lines, &dir, &mut vec![]).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        match &errors[0] {
+            AssemblerError::IncludeCycle { location, .. } => {
+                assert_eq!(location.line, 2);
+                assert!(location.file.as_ref().unwrap().ends_with("b.iasm"));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_include_missing_file() {
+        let dir = scratch_dir("missing");
+        let lines = source_lines("hlt\n.include \"nope.iasm\"");
+        let errors = resolve_includes(lines, &dir, &mut vec![]).unwrap_err();
+        assert!(matches!(
+            &errors[0],
+            AssemblerError::IncludeNotReadable { location, .. } if location.line == 2
+        ));
+    }
+}
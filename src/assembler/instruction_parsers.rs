@@ -1,9 +1,12 @@
+use super::expression_parsers::ExpressionError;
 use super::label_parsers::label_declaration;
 use super::opcode_parsers::*;
 use super::operand_parsers::operand;
 use super::{SymbolTable, Token};
+use crate::instruction::Opcode;
 use byteorder::{ByteOrder, LittleEndian};
 use nom::{combinator::opt, sequence::tuple, IResult};
+use std::convert::TryFrom;
 
 #[derive(Debug, PartialEq)]
 pub struct AssemblerInstruction {
@@ -15,77 +18,227 @@ pub struct AssemblerInstruction {
     pub operand3: Option<Token>,
 }
 
+/// Why an instruction can't be encoded
+#[derive(Debug, PartialEq, Clone)]
+pub enum EncodingError {
+    /// The tokens don't fit the instruction, with a description of what was expected
+    InvalidOperands(String),
+    UnknownRegister(String),
+    /// The label a compare-and-branch goes to is more than 127 instructions away
+    BranchTooFar(String),
+    /// What a compare-and-branch goes to isn't the label of an instruction
+    BadBranchTarget(String),
+    /// An integer operand or a label address that doesn't fit in the 16 bits of an operand
+    OutOfRange(i64),
+    Expression(ExpressionError),
+}
+
 impl AssemblerInstruction {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    /// Encodes the instruction, `offset` being its position in the code section
+    pub fn to_bytes(&self, symbols: &SymbolTable, offset: u32) -> Result<Vec<u8>, EncodingError> {
         let mut results = vec![];
+        if !self.is_opcode() {
+            // Directives don't take any space in the code section
+            return Ok(results);
+        }
+        if let Some(code) = self.branch_opcode() {
+            return self.extract_branch(code, symbols, offset);
+        }
         if let Some(ref token) = self.opcode {
             match token {
-                Token::Op { code } => match code {
-                    _ => {
-                        let b: u8 = (*code).into();
-                        results.push(b);
-                    }
-                },
+                Token::Op { code } => {
+                    let b: u8 = (*code).into();
+                    results.push(b);
+                }
                 _ => {
-                    println!("Non-opcode found in opcode field");
-                    std::process::exit(1);
+                    return Err(EncodingError::InvalidOperands(
+                        "expected an opcode".to_string(),
+                    ))
                 }
             }
         }
 
-        for operand in &[&self.operand1, &self.operand2, &self.operand3] {
-            if let Some(token) = operand {
-                AssemblerInstruction::extract_operand(token, &mut results, symbols)
-            }
+        for token in [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            AssemblerInstruction::extract_operand(token, &mut results, symbols)?;
         }
 
         while results.len() < 4 {
             results.push(0);
         }
 
-        return results;
+        Ok(results)
+    }
+
+    /// Compare-and-branch instructions take two registers and a label, encoded as a signed
+    /// offset counted in instructions from the branch itself
+    fn extract_branch(
+        &self,
+        code: Opcode,
+        symbols: &SymbolTable,
+        offset: u32,
+    ) -> Result<Vec<u8>, EncodingError> {
+        let expected = || {
+            EncodingError::InvalidOperands(format!("{:?} expects two registers and a label", code))
+        };
+        let mut results = vec![code.into()];
+        for operand in &[&self.operand1, &self.operand2] {
+            match operand {
+                Some(Token::Register { reg_num }) => results.push(*reg_num),
+                Some(Token::RegisterName { name }) => {
+                    return Err(EncodingError::UnknownRegister(name.clone()))
+                }
+                _ => return Err(expected()),
+            }
+        }
+        let name = match &self.operand3 {
+            Some(Token::LabelUsage { name }) => name,
+            _ => return Err(expected()),
+        };
+        if symbols.symbol_value(name).is_none() {
+            return Err(EncodingError::Expression(ExpressionError::UndefinedSymbol(
+                name.clone(),
+            )));
+        }
+        let distance = match symbols.code_label_value(name) {
+            Some(target) if (i64::from(target) - i64::from(offset)) % 4 == 0 => {
+                (i64::from(target) - i64::from(offset)) / 4
+            }
+            _ => return Err(EncodingError::BadBranchTarget(name.clone())),
+        };
+        let distance =
+            i8::try_from(distance).map_err(|_| EncodingError::BranchTooFar(name.clone()))?;
+        results.push(distance as u8);
+        Ok(results)
+    }
+
+    fn branch_opcode(&self) -> Option<Opcode> {
+        match self.opcode {
+            Some(Token::Op { code }) if code.is_branch() => Some(code),
+            _ => None,
+        }
     }
 
-    fn extract_operand(t: &Token, results: &mut Vec<u8>, symbols: &SymbolTable) {
+    fn extract_operand(
+        t: &Token,
+        results: &mut Vec<u8>,
+        symbols: &SymbolTable,
+    ) -> Result<(), EncodingError> {
         match t {
             Token::Register { reg_num } => {
                 results.push(*reg_num);
             }
+            Token::RegisterName { name } => {
+                return Err(EncodingError::UnknownRegister(name.clone()));
+            }
             Token::IntegerOperand { value } => {
-                let converted = *value as u16;
+                let converted = AssemblerInstruction::operand_value(i64::from(*value))?;
                 let byte1 = converted;
                 let byte2 = converted >> 8;
                 results.push(byte2 as u8);
                 results.push(byte1 as u8);
             }
+            Token::Expression { expr } => {
+                let value = expr.evaluate(symbols).map_err(EncodingError::Expression)?;
+                let converted = AssemblerInstruction::operand_value(i64::from(value))?;
+                results.push((converted >> 8) as u8);
+                results.push(converted as u8);
+            }
             Token::LabelUsage { name } => {
-                if let Some(value) = symbols.symbol_value(name) {
-                    let mut wtr = vec![];
-                    LittleEndian::write_u32(&mut wtr, value);
-                    results.push(wtr[1]);
-                    results.push(wtr[0]);
-                } else {
-                    println!("No value found for {:?}", name);
-                }
+                let value = symbols.symbol_value(name).ok_or_else(|| {
+                    EncodingError::Expression(ExpressionError::UndefinedSymbol(name.clone()))
+                })?;
+                let mut wtr = vec![0; 2];
+                LittleEndian::write_u16(
+                    &mut wtr,
+                    AssemblerInstruction::operand_value(i64::from(value))?,
+                );
+                results.push(wtr[1]);
+                results.push(wtr[0]);
             }
 
             _ => {
-                println!("Opcode found in operand field");
-                std::process::exit(1);
+                return Err(EncodingError::InvalidOperands(
+                    "an opcode can't be an operand".to_string(),
+                ))
             }
         };
+        Ok(())
+    }
+    /// Integer operands are read back by the VM as unsigned 16 bit numbers
+    fn operand_value(value: i64) -> Result<u16, EncodingError> {
+        u16::try_from(value).map_err(|_| EncodingError::OutOfRange(value))
+    }
+
+    /// Where the 16 bit addresses of the labels used as operands are, counted in bytes from
+    /// the start of the instruction. Compare-and-branch targets are relative and not included.
+    pub fn label_usage_offsets(&self) -> Vec<(usize, &str)> {
+        let mut offsets = vec![];
+        if self.is_branch() {
+            return offsets;
+        }
+        let mut offset = 1;
+        for token in [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            match token {
+                Token::Register { .. } | Token::RegisterName { .. } => offset += 1,
+                Token::LabelUsage { name } => {
+                    offsets.push((offset, name.as_str()));
+                    offset += 2;
+                }
+                _ => offset += 2,
+            }
+        }
+        offsets
+    }
+
+    pub fn is_branch(&self) -> bool {
+        self.branch_opcode().is_some()
     }
+
+    pub fn is_opcode(&self) -> bool {
+        self.opcode.is_some()
+    }
+
+    pub fn is_directive(&self) -> bool {
+        self.directive.is_some()
+    }
+
+    /// True for directives that put bytes in the data section
+    pub fn is_data(&self) -> bool {
+        self.get_directive_name().as_deref() == Some("asciiz")
+    }
+
+    pub fn get_directive_name(&self) -> Option<String> {
+        match &self.directive {
+            Some(Token::Directive { name }) => Some(name.clone()),
+            _ => None,
+        }
+    }
+
     pub fn is_label(&self) -> bool {
         self.label.is_some()
     }
 
+    pub fn get_label_usage(&self) -> Option<&str> {
+        [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .find_map(|o| match o {
+                Some(Token::LabelUsage { name }) => Some(name.as_str()),
+                _ => None,
+            })
+    }
+
     pub fn get_label_name(&self) -> Option<String> {
         match &self.label {
-            Some(l) => match l {
-                Token::LabelDeclaration { name } => Some(name.clone()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::LabelDeclaration { name }) => Some(name.clone()),
+            _ => None,
         }
     }
 }
@@ -118,8 +271,7 @@ pub fn instruction(input: &str) -> IResult<&str, AssemblerInstruction> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::assembler::Token;
-    use crate::instruction::Opcode;
+    use crate::assembler::{Symbol, SymbolType, Token};
 
     #[test]
     fn parse_label_instruction_zero() {
@@ -245,4 +397,65 @@ mod tests {
         assert_eq!(Some(Token::Register { reg_num: 1 }), p.operand2);
         assert_eq!(Some(Token::Register { reg_num: 2 }), p.operand3);
     }
+    #[test]
+    fn test_branch_to_bytes() {
+        let mut symbols = SymbolTable::new();
+        symbols
+            .add_symbol(Symbol::new("top".to_string(), SymbolType::Label, 4))
+            .unwrap();
+        let (_, p) = instruction_combined("blt $1 $2 @top\n").unwrap();
+        assert_eq!(p.to_bytes(&symbols, 12), Ok(vec![23, 1, 2, 0xFE]));
+        assert_eq!(p.to_bytes(&symbols, 0), Ok(vec![23, 1, 2, 1]));
+    }
+
+    #[test]
+    fn test_branch_out_of_range() {
+        let mut symbols = SymbolTable::new();
+        symbols
+            .add_symbol(Symbol::new("top".to_string(), SymbolType::Label, 0))
+            .unwrap();
+        let (_, p) = instruction_combined("beq $1 $2 @top\n").unwrap();
+        assert_eq!(p.to_bytes(&symbols, 128 * 4), Ok(vec![20, 1, 2, 0x80]));
+        assert_eq!(
+            p.to_bytes(&symbols, 129 * 4),
+            Err(EncodingError::BranchTooFar("top".to_string()))
+        );
+        let (_, p) = instruction_combined("beq $1 $2 @nowhere\n").unwrap();
+        assert_eq!(
+            p.to_bytes(&symbols, 0),
+            Err(EncodingError::Expression(ExpressionError::UndefinedSymbol(
+                "nowhere".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn test_branch_to_bad_target() {
+        let mut symbols = SymbolTable::new();
+        for (name, symbol_type, offset) in [
+            ("msg", SymbolType::Data, 4),
+            ("TEN", SymbolType::Constant, 4),
+            ("odd", SymbolType::Label, 6),
+        ] {
+            symbols
+                .add_symbol(Symbol::new(name.to_string(), symbol_type, offset))
+                .unwrap();
+        }
+        for name in &["msg", "TEN", "odd"] {
+            let (_, p) = instruction_combined(&format!("bne $1 $2 @{}\n", name)).unwrap();
+            assert_eq!(
+                p.to_bytes(&symbols, 0),
+                Err(EncodingError::BadBranchTarget(name.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_branch_without_label() {
+        let (_, p) = instruction_combined("beq $0 $1 #5\n").unwrap();
+        assert!(matches!(
+            p.to_bytes(&SymbolTable::new(), 0),
+            Err(EncodingError::InvalidOperands(_))
+        ));
+    }
 }
@@ -1,13 +1,36 @@
 use crate::assembler::Token;
 use nom::{
+    branch::alt,
     bytes::complete::tag,
-    character::complete::{alphanumeric0, multispace0},
-    sequence::{preceded, terminated},
+    character::complete::{alpha1, alphanumeric1, digit1, multispace0, one_of},
+    combinator::recognize,
+    multi::many0,
+    sequence::{pair, preceded, terminated},
     IResult,
 };
 
+/// Label names are made of letters, digits, `_` and `.`, and don't start with a digit. A name
+/// starting with `.` is local to the last global label declared before it.
+pub fn label_name(input: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        alt((alpha1, tag("_"), tag("."))),
+        many0(alt((alphanumeric1, tag("_"), tag(".")))),
+    ))(input)
+}
+
+/// A reference to a numeric label: `1b` is the closest `1:` before, `1f` the closest after
+pub fn numeric_label_reference(input: &str) -> IResult<&str, &str> {
+    recognize(pair(digit1, one_of("bf")))(input)
+}
+
+/// What may follow `@` in a label usage
+pub fn label_reference(input: &str) -> IResult<&str, &str> {
+    alt((numeric_label_reference, label_name))(input)
+}
+
 pub fn label_declaration(input: &str) -> IResult<&str, Token> {
-    let (leftover, name) = terminated(terminated(alphanumeric0, tag(":")), multispace0)(input)?;
+    let (leftover, name) =
+        terminated(terminated(alt((label_name, digit1)), tag(":")), multispace0)(input)?;
     Ok((
         leftover,
         Token::LabelDeclaration {
@@ -17,7 +40,7 @@ pub fn label_declaration(input: &str) -> IResult<&str, Token> {
 }
 
 pub fn label_usage(input: &str) -> IResult<&str, Token> {
-    let (leftover, name) = terminated(preceded(tag("@"), alphanumeric0), multispace0)(input)?;
+    let (leftover, name) = terminated(preceded(tag("@"), label_reference), multispace0)(input)?;
     Ok((
         leftover,
         Token::LabelUsage {
@@ -55,3 +78,38 @@ fn test_parse_label_usage() {
     let result = label_usage("test");
     assert_eq!(result.is_ok(), false);
 }
+
+#[test]
+fn test_parse_label_names() {
+    for name in &["under_score", ".local", "main.loop", "_start", "42"] {
+        let input = format!("{}: inc $0", name);
+        let result = label_declaration(&input);
+        assert_eq!(
+            result,
+            Ok((
+                "inc $0",
+                Token::LabelDeclaration {
+                    name: name.to_string()
+                }
+            ))
+        );
+    }
+    assert!(label_declaration(":").is_err());
+    assert!(label_declaration("4ever:").is_err());
+}
+
+#[test]
+fn test_parse_numeric_label_usage() {
+    let result = label_usage("@1b");
+    assert_eq!(
+        result,
+        Ok((
+            "",
+            Token::LabelUsage {
+                name: "1b".to_string()
+            }
+        ))
+    );
+    assert!(label_usage("@1").is_err());
+    assert!(label_usage("@").is_err());
+}
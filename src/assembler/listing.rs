@@ -0,0 +1,200 @@
+use super::instruction_parsers::AssemblerInstruction;
+use super::macro_expander::SourceLine;
+use super::operand_parsers::unescape_string;
+use super::program_parsers::Program;
+use super::{SymbolTable, SymbolType, Token};
+use std::fmt;
+
+/// How many bytes are shown on each row of the listing
+const BYTES_PER_ROW: usize = 4;
+
+/// One source line of a listing, with what the assembler turned it into
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListingLine {
+    pub line: usize,
+    pub text: String,
+    /// Where the bytes of the line start, `None` for lines that don't emit anything
+    pub address: Option<usize>,
+    pub bytes: Vec<u8>,
+    /// The labels and constants declared or used by the line, with their values
+    pub symbols: Vec<(String, u32)>,
+}
+
+/// A human readable account of how a program was laid out: every source line with its address
+/// and bytes, followed by the symbol table
+#[derive(Debug, PartialEq, Clone)]
+pub struct Listing {
+    pub lines: Vec<ListingLine>,
+    pub symbols: Vec<(String, String, u32)>,
+}
+
+impl Listing {
+    /// Builds the listing of an assembled program. `line_indices` tells for every instruction
+    /// which of `lines` it came from.
+    pub fn new(
+        p: &Program,
+        line_indices: &[usize],
+        lines: &[SourceLine],
+        symbols: &SymbolTable,
+        data_start: usize,
+        code_start: usize,
+    ) -> Listing {
+        let mut listing: Vec<ListingLine> = lines
+            .iter()
+            .map(|l| ListingLine {
+                line: l.location.line,
+                text: l.text.clone(),
+                address: None,
+                bytes: vec![],
+                symbols: vec![],
+            })
+            .collect();
+        let mut data_address = data_start;
+        let mut code_address = code_start;
+        for (i, index) in p.instructions.iter().zip(line_indices) {
+            let entry = &mut listing[*index];
+            let bytes = if i.is_data() {
+                let mut bytes = match &i.operand1 {
+                    Some(Token::IrString { name }) => unescape_string(name).unwrap_or_default(),
+                    _ => vec![],
+                };
+                bytes.push(0);
+                entry.address.get_or_insert(data_address);
+                data_address += bytes.len();
+                bytes
+            } else if i.is_opcode() {
+                // The program assembled, so every instruction encodes
+                let bytes = i.to_bytes(symbols, code_address as u32).unwrap_or_default();
+                entry.address.get_or_insert(code_address);
+                code_address += bytes.len();
+                bytes
+            } else {
+                vec![]
+            };
+            entry.bytes.extend(bytes);
+            for name in Listing::referenced_symbols(i) {
+                if let Some(value) = symbols.symbol_value(&name) {
+                    if !entry.symbols.iter().any(|(n, _)| *n == name) {
+                        entry.symbols.push((name, value));
+                    }
+                }
+            }
+        }
+        let symbols = symbols
+            .symbols
+            .iter()
+            .map(|s| {
+                let kind = match s.symbol_type {
+                    SymbolType::Label | SymbolType::Data => "label",
+                    SymbolType::Constant => "constant",
+                    SymbolType::External => "external",
+                    SymbolType::Import => "import",
+                    SymbolType::Native => "native",
+                };
+                (s.name.clone(), kind.to_string(), s.offset)
+            })
+            .collect();
+        Listing {
+            lines: listing,
+            symbols,
+        }
+    }
+
+    fn referenced_symbols(i: &AssemblerInstruction) -> Vec<String> {
+        let mut names: Vec<String> = i.get_label_name().into_iter().collect();
+        for operand in [&i.operand1, &i.operand2, &i.operand3]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            match operand {
+                Token::LabelUsage { name } => names.push(name.clone()),
+                Token::Expression { expr } => {
+                    names.extend(expr.symbols().into_iter().map(String::from))
+                }
+                _ => {}
+            }
+        }
+        names
+    }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let empty_bytes = " ".repeat(BYTES_PER_ROW * 3 - 1);
+        for line in &self.lines {
+            let mut rows = line.bytes.chunks(BYTES_PER_ROW);
+            let first = rows.next().map(hex_bytes).unwrap_or_default();
+            let address = match line.address {
+                Some(address) => format!("{:04x}", address),
+                None => "    ".to_string(),
+            };
+            write!(
+                f,
+                "{}  {:<width$}  {:>5}  {}",
+                address,
+                first,
+                line.line,
+                line.text.trim_end(),
+                width = empty_bytes.len()
+            )?;
+            if !line.symbols.is_empty() {
+                let symbols: Vec<String> = line
+                    .symbols
+                    .iter()
+                    .map(|(name, value)| format!("{} = {:#06x}", name, value))
+                    .collect();
+                write!(f, "    ; {}", symbols.join(", "))?;
+            }
+            writeln!(f)?;
+            for (n, row) in rows.enumerate() {
+                let address = line.address.unwrap_or_default() + (n + 1) * BYTES_PER_ROW;
+                writeln!(f, "{:04x}  {}", address, hex_bytes(row))?;
+            }
+        }
+        writeln!(f)?;
+        writeln!(f, "Symbols:")?;
+        for (name, kind, value) in &self.symbols {
+            writeln!(f, "{:<24} {:<8} {:#06x}", name, kind, value)?;
+        }
+        Ok(())
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_listing() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".equ COUNT 3\nmsg: .asciiz 'hello'\nload $0 #COUNT\ntop: dec $0\nload $1 @top\nhlt")
+            .unwrap();
+        let listing = asm.listing.as_ref().unwrap();
+        assert_eq!(listing.lines.len(), 6);
+        assert_eq!(listing.lines[0].address, None);
+        assert_eq!(listing.lines[1].address, Some(65));
+        assert_eq!(listing.lines[1].bytes, b"hello\0".to_vec());
+        assert_eq!(listing.lines[3].address, Some(75));
+        assert_eq!(listing.lines[3].symbols, vec![("top".to_string(), 75)]);
+        let bytes: Vec<u8> = listing.lines.iter().flat_map(|l| l.bytes.clone()).collect();
+        assert_eq!(bytes, program[65..].to_vec());
+
+        let text = listing.to_string();
+        assert!(text.contains(
+            "0041  68 65 6c 6c      2  msg: .asciiz 'hello'    ; msg = 0x0041\n0045  6f 00\n"
+        ));
+        assert!(text.contains("004b  13 00 00 00      4  top: dec $0    ; top = 0x004b\n"));
+        assert!(text.contains("\nSymbols:\n"));
+        assert!(text.contains("COUNT                    constant 0x0003\n"));
+    }
+}
@@ -0,0 +1,367 @@
+use super::assembler_errors::{AssemblerError, SourceLocation};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+/// How deep macro invocations inside macro bodies may nest before we give up
+pub const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// A line of source handed to the parser, along with where it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub text: String,
+    pub location: SourceLocation,
+}
+
+/// Splits raw source into numbered lines
+pub fn source_lines(raw: &str) -> Vec<SourceLine> {
+    raw.lines()
+        .enumerate()
+        .map(|(i, text)| SourceLine {
+            text: text.to_string(),
+            location: SourceLocation::new(i + 1),
+        })
+        .collect()
+}
+
+/// Splits the contents of `file` into numbered lines that remember the file they came from
+pub fn file_lines(raw: &str, file: &str) -> Vec<SourceLine> {
+    raw.lines()
+        .enumerate()
+        .map(|(i, text)| SourceLine {
+            text: text.to_string(),
+            location: SourceLocation::in_file(file, i + 1),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<SourceLine>,
+    /// Labels declared in the body, renamed on every expansion so they stay unique
+    locals: Vec<String>,
+}
+
+/// Collects `.macro name arg1 arg2 ... .endm` definitions and expands their invocations.
+/// Inside a body, `\arg` is replaced by the matching argument of the invocation.
+#[derive(Debug, Default)]
+pub struct MacroExpander {
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+}
+
+impl MacroExpander {
+    pub fn new() -> MacroExpander {
+        MacroExpander {
+            macros: HashMap::new(),
+            expansions: 0,
+        }
+    }
+
+    /// Removes macro definitions from `lines` and replaces every invocation with its body
+    pub fn expand(
+        &mut self,
+        lines: Vec<SourceLine>,
+    ) -> Result<Vec<SourceLine>, Vec<AssemblerError>> {
+        let mut errors = vec![];
+        let lines = self.extract_definitions(lines, &mut errors);
+        let mut expanded = vec![];
+        for line in &lines {
+            self.expand_line(line, 0, &mut expanded, &mut errors);
+        }
+        if errors.is_empty() {
+            Ok(expanded)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn extract_definitions(
+        &mut self,
+        lines: Vec<SourceLine>,
+        errors: &mut Vec<AssemblerError>,
+    ) -> Vec<SourceLine> {
+        let mut remaining = vec![];
+        let mut current: Option<(String, SourceLocation, Macro)> = None;
+        for line in lines {
+            let mut words = line.text.split_whitespace();
+            match words.next() {
+                Some(".macro") if current.is_none() => {
+                    // A nameless macro is still read up to its .endm, but not defined
+                    let name = words.next().unwrap_or_default().to_string();
+                    if name.is_empty() {
+                        errors.push(AssemblerError::MissingMacroName {
+                            location: line.location.clone(),
+                        });
+                    }
+                    let params = words.map(|w| w.to_string()).collect();
+                    let definition = Macro {
+                        params,
+                        body: vec![],
+                        locals: vec![],
+                    };
+                    current = Some((name, line.location, definition));
+                }
+                Some(".endm") => match current.take() {
+                    Some((name, _, _)) if name.is_empty() => {}
+                    Some((name, location, mut definition)) => {
+                        definition.locals = definition
+                            .body
+                            .iter()
+                            .filter_map(|l| declared_label(&l.text))
+                            .filter(|l| !l.chars().all(|c| c.is_ascii_digit()))
+                            .map(|l| l.to_string())
+                            .collect();
+                        match self.macros.entry(name) {
+                            Entry::Occupied(entry) => {
+                                errors.push(AssemblerError::MacroAlreadyDefined {
+                                    name: entry.key().clone(),
+                                    location,
+                                });
+                            }
+                            Entry::Vacant(entry) => {
+                                entry.insert(definition);
+                            }
+                        }
+                    }
+                    None => errors.push(AssemblerError::UnmatchedEndMacro {
+                        location: line.location,
+                    }),
+                },
+                _ => match current {
+                    Some((_, _, ref mut definition)) => definition.body.push(line),
+                    None => remaining.push(line),
+                },
+            }
+        }
+        if let Some((name, location, _)) = current {
+            errors.push(AssemblerError::UnterminatedMacro { name, location });
+        }
+        remaining
+    }
+
+    fn expand_line(
+        &mut self,
+        line: &SourceLine,
+        depth: usize,
+        out: &mut Vec<SourceLine>,
+        errors: &mut Vec<AssemblerError>,
+    ) {
+        let label = declared_label(&line.text);
+        let mut words = line.text.split_whitespace();
+        if label.is_some() {
+            words.next();
+        }
+        let name = words.next().unwrap_or_default().to_string();
+        let definition = match self.macros.get(&name) {
+            Some(definition) => definition.clone(),
+            None => {
+                out.push(line.clone());
+                return;
+            }
+        };
+        let args: Vec<&str> = words.collect();
+        if args.len() != definition.params.len() {
+            errors.push(AssemblerError::MacroArgumentMismatch {
+                name,
+                expected: definition.params.len(),
+                found: args.len(),
+                location: line.location.clone(),
+            });
+            return;
+        }
+        if depth >= MAX_EXPANSION_DEPTH {
+            errors.push(AssemblerError::MacroExpansionTooDeep {
+                name,
+                location: line.location.clone(),
+            });
+            return;
+        }
+
+        self.expansions += 1;
+        let expansion = self.expansions;
+        if let Some(label) = label {
+            out.push(SourceLine {
+                text: format!("{}:", label),
+                location: line.location.clone(),
+            });
+        }
+        for body_line in &definition.body {
+            let text = replace_identifiers(&body_line.text, '\\', is_identifier_char, |param| {
+                definition
+                    .params
+                    .iter()
+                    .position(|p| p == param)
+                    .map(|i| args[i].to_string())
+            });
+            let text = rename_locals(&text, &definition.locals, expansion);
+            let expanded = SourceLine {
+                text,
+                location: SourceLocation {
+                    expanded_from: Some((name.clone(), Box::new(line.location.clone()))),
+                    ..body_line.location.clone()
+                },
+            };
+            self.expand_line(&expanded, depth + 1, out, errors);
+        }
+    }
+}
+
+/// Returns the label declared at the start of a line, if any
+fn declared_label(text: &str) -> Option<&str> {
+    let first = text.split_whitespace().next()?;
+    let name = first.strip_suffix(':')?;
+    if !name.is_empty() && name.chars().all(is_label_char) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+/// Gives the labels declared in a macro body a name unique to this expansion. Generated names
+/// start with `..`, which the assembler treats as neither global nor local, so expanding a macro
+/// does not change which global label later local labels belong to. Numeric labels are left
+/// alone since they may be redefined anyway.
+fn rename_locals(text: &str, locals: &[String], expansion: usize) -> String {
+    if locals.is_empty() {
+        return text.to_string();
+    }
+    let unique = |name: &str| format!("..{}_{}", name.trim_start_matches('.'), expansion);
+    let text = replace_identifiers(text, '@', is_label_char, |name| {
+        if locals.iter().any(|l| l == name) {
+            Some(format!("@{}", unique(name)))
+        } else {
+            None
+        }
+    });
+    match declared_label(&text) {
+        Some(label) if locals.iter().any(|l| l == label) => {
+            let start = text.find(label).unwrap_or(0);
+            format!(
+                "{}{}{}",
+                &text[..start],
+                unique(label),
+                &text[start + label.len()..]
+            )
+        }
+        _ => text,
+    }
+}
+
+/// Replaces every identifier that follows `prefix` with what `f` returns for it. The prefix is
+/// consumed; identifiers for which `f` returns `None` are left untouched.
+fn replace_identifiers<F>(text: &str, prefix: char, is_char: fn(char) -> bool, f: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(prefix) {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + prefix.len_utf8()..];
+        let end = after.find(|c: char| !is_char(c)).unwrap_or(after.len());
+        match f(&after[..end]) {
+            Some(replacement) => result.push_str(&replacement),
+            None => {
+                result.push(prefix);
+                result.push_str(&after[..end]);
+            }
+        }
+        rest = &after[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn is_label_char(c: char) -> bool {
+    is_identifier_char(c) || c == '.'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(source: &str) -> Result<Vec<String>, Vec<AssemblerError>> {
+        let mut expander = MacroExpander::new();
+        expander
+            .expand(source_lines(source))
+            .map(|lines| lines.into_iter().map(|l| l.text).collect())
+    }
+
+    #[test]
+    fn test_expand_with_arguments() {
+        let lines = expand(".macro add3 a b\nadd \\a \\b \\a\ninc \\a\n.endm\nadd3 $1 $2\nhlt");
+        assert_eq!(lines.unwrap(), vec!["add $1 $2 $1", "inc $1", "hlt"]);
+    }
+
+    #[test]
+    fn test_local_labels_are_unique() {
+        let lines =
+            expand(".macro spin\nagain: dec $0\nbne $0 $1 @again\n.endm\nspin\nspin").unwrap();
+        assert_eq!(lines[0], "..again_1: dec $0");
+        assert_eq!(lines[1], "bne $0 $1 @..again_1");
+        assert_eq!(lines[2], "..again_2: dec $0");
+        assert_eq!(lines[3], "bne $0 $1 @..again_2");
+    }
+
+    #[test]
+    fn test_label_on_invocation() {
+        let lines = expand(".macro one\ninc $0\n.endm\nstart: one").unwrap();
+        assert_eq!(lines, vec!["start:", "inc $0"]);
+    }
+
+    #[test]
+    fn test_nested_expansion_location() {
+        let mut expander = MacroExpander::new();
+        let lines = expander
+            .expand(source_lines(
+                ".macro inner\ninc $0\n.endm\n.macro outer\ninner\n.endm\nouter",
+            ))
+            .unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(
+            lines[0].location.to_string(),
+            "line 2 (in macro 'inner', expanded at line 5 (in macro 'outer', expanded at line 7))"
+        );
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let errors = expand(".macro two a b\n.endm\ntwo $1").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::MacroArgumentMismatch {
+                name: "two".to_string(),
+                expected: 2,
+                found: 1,
+                location: SourceLocation::new(3),
+            }]
+        );
+        let errors = expand(".macro forever\nforever\n.endm\nforever").unwrap_err();
+        assert!(matches!(
+            errors[0],
+            AssemblerError::MacroExpansionTooDeep { .. }
+        ));
+        let errors = expand(".macro open\ninc $0").unwrap_err();
+        assert!(matches!(
+            errors[0],
+            AssemblerError::UnterminatedMacro { .. }
+        ));
+        let errors = expand(".endm").unwrap_err();
+        assert!(matches!(
+            errors[0],
+            AssemblerError::UnmatchedEndMacro { .. }
+        ));
+        let errors = expand(".macro\ninc $0\n.endm\nhlt").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::MissingMacroName {
+                location: SourceLocation::new(1),
+            }]
+        );
+    }
+}
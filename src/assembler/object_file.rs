@@ -0,0 +1,328 @@
+use super::{
+    data_length, elf_header, CODE_LENGTH_OFFSET, ELF_HEADER_PREFIX, ELF_TYPE_OFFSET, ELF_TYPE_REL,
+    PROGRAM_START,
+};
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The section a symbol of an object file lives in
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Section {
+    Data,
+    Code,
+    /// Defined by another object file
+    External,
+}
+
+impl Section {
+    fn to_byte(self) -> u8 {
+        match self {
+            Section::External => 0,
+            Section::Data => 1,
+            Section::Code => 2,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Section> {
+        match b {
+            0 => Some(Section::External),
+            1 => Some(Section::Data),
+            2 => Some(Section::Code),
+            _ => None,
+        }
+    }
+}
+
+/// A label of an object file, its offset being relative to the start of its section
+#[derive(Debug, PartialEq, Clone)]
+pub struct ObjectSymbol {
+    pub name: String,
+    pub section: Section,
+    pub offset: u32,
+    /// Whether other object files may refer to it, as declared with `.global`
+    pub exported: bool,
+}
+
+/// A 16 bit address in the code section that must be set to the final address of a symbol
+#[derive(Debug, PartialEq, Clone)]
+pub struct Relocation {
+    /// Where the address is, relative to the start of the code section
+    pub offset: u32,
+    /// Index of the symbol in the object's symbol list
+    pub symbol: u32,
+}
+
+/// A function a module makes available to others
+#[derive(Debug, PartialEq, Clone)]
+pub struct Export {
+    pub name: String,
+    pub arity: u8,
+    /// The address of the function, as assembled
+    pub address: u32,
+}
+
+/// A function of another module, called with `CALLEXT` and the index of the import
+#[derive(Debug, PartialEq, Clone)]
+pub struct Import {
+    pub module: String,
+    pub function: String,
+}
+
+/// A function of the host application, called with `CALLN` and the index of the native
+#[derive(Debug, PartialEq, Clone)]
+pub struct Native {
+    pub name: String,
+    pub arity: u8,
+}
+
+/// What a module declares with `.module`, `.export`, `.import` and `.native`
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ModuleTable {
+    pub name: String,
+    pub exports: Vec<Export>,
+    pub imports: Vec<Import>,
+    pub natives: Vec<Native>,
+}
+
+impl ModuleTable {
+    /// Serializes the table, with the same encoding as the symbol table of object files
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        push_string(&mut bytes, &self.name);
+        push_u32(&mut bytes, self.exports.len() as u32);
+        for export in &self.exports {
+            push_string(&mut bytes, &export.name);
+            bytes.push(export.arity);
+            push_u32(&mut bytes, export.address);
+        }
+        push_u32(&mut bytes, self.imports.len() as u32);
+        for import in &self.imports {
+            push_string(&mut bytes, &import.module);
+            push_string(&mut bytes, &import.function);
+        }
+        push_u32(&mut bytes, self.natives.len() as u32);
+        for native in &self.natives {
+            push_string(&mut bytes, &native.name);
+            bytes.push(native.arity);
+        }
+        bytes
+    }
+
+    /// Reads back a table written by `to_bytes`, `None` meaning it is malformed
+    pub fn from_bytes(mut input: &[u8]) -> Option<ModuleTable> {
+        let input = &mut input;
+        let mut table = ModuleTable {
+            name: read_string(input)?,
+            ..ModuleTable::default()
+        };
+        for _ in 0..read_u32(input)? {
+            table.exports.push(Export {
+                name: read_string(input)?,
+                arity: take(input, 1)?[0],
+                address: read_u32(input)?,
+            });
+        }
+        for _ in 0..read_u32(input)? {
+            table.imports.push(Import {
+                module: read_string(input)?,
+                function: read_string(input)?,
+            });
+        }
+        for _ in 0..read_u32(input)? {
+            table.natives.push(Native {
+                name: read_string(input)?,
+                arity: take(input, 1)?[0],
+            });
+        }
+        if input.is_empty() {
+            Some(table)
+        } else {
+            None
+        }
+    }
+}
+
+/// A separately assembled piece of a program, to be combined with others by the linker, or
+/// loaded into a running VM if it is a module
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ObjectFile {
+    pub data: Vec<u8>,
+    pub code: Vec<u8>,
+    pub symbols: Vec<ObjectSymbol>,
+    pub relocations: Vec<Relocation>,
+    pub module: Option<ModuleTable>,
+}
+
+impl ObjectFile {
+    /// Serializes the object file: a REL header giving the length of the data and code sections,
+    /// both sections, then the symbol and relocation tables and the module table if there is one.
+    /// Integers are little endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = elf_header(ELF_TYPE_REL, self.data.len());
+        LittleEndian::write_u32(
+            &mut bytes[CODE_LENGTH_OFFSET..CODE_LENGTH_OFFSET + 4],
+            self.code.len() as u32,
+        );
+        bytes.extend_from_slice(&self.data);
+        bytes.extend_from_slice(&self.code);
+        push_u32(&mut bytes, self.symbols.len() as u32);
+        for symbol in &self.symbols {
+            push_string(&mut bytes, &symbol.name);
+            bytes.push(symbol.section.to_byte());
+            bytes.push(symbol.exported as u8);
+            push_u32(&mut bytes, symbol.offset);
+        }
+        push_u32(&mut bytes, self.relocations.len() as u32);
+        for relocation in &self.relocations {
+            push_u32(&mut bytes, relocation.offset);
+            push_u32(&mut bytes, relocation.symbol);
+        }
+        if let Some(module) = &self.module {
+            bytes.append(&mut module.to_bytes());
+        }
+        bytes
+    }
+
+    /// Reads back an object file written by `to_bytes`, `None` meaning it is malformed
+    pub fn from_bytes(bytes: &[u8]) -> Option<ObjectFile> {
+        let mut prefix = ELF_HEADER_PREFIX;
+        prefix[ELF_TYPE_OFFSET] = ELF_TYPE_REL;
+        if bytes.get(..prefix.len())? != prefix {
+            return None;
+        }
+        let data_end = PROGRAM_START.checked_add(data_length(bytes)?)?;
+        let code_length =
+            LittleEndian::read_u32(bytes.get(CODE_LENGTH_OFFSET..CODE_LENGTH_OFFSET + 4)?) as usize;
+        let code_end = data_end.checked_add(code_length)?;
+        let mut object = ObjectFile {
+            data: bytes.get(PROGRAM_START..data_end)?.to_vec(),
+            code: bytes.get(data_end..code_end)?.to_vec(),
+            ..ObjectFile::default()
+        };
+        let mut rest = &bytes[code_end..];
+        for _ in 0..read_u32(&mut rest)? {
+            let name = read_string(&mut rest)?;
+            let flags = take(&mut rest, 2)?;
+            object.symbols.push(ObjectSymbol {
+                name,
+                section: Section::from_byte(flags[0])?,
+                exported: flags[1] != 0,
+                offset: read_u32(&mut rest)?,
+            });
+        }
+        for _ in 0..read_u32(&mut rest)? {
+            object.relocations.push(Relocation {
+                offset: read_u32(&mut rest)?,
+                symbol: read_u32(&mut rest)?,
+            });
+        }
+        if !rest.is_empty() {
+            object.module = Some(ModuleTable::from_bytes(rest)?);
+        }
+        Some(object)
+    }
+}
+
+pub(crate) fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    let mut buf = [0; 4];
+    LittleEndian::write_u32(&mut buf, value);
+    bytes.extend_from_slice(&buf);
+}
+
+/// Strings are written as a little endian u16 length followed by UTF-8 bytes
+pub(crate) fn push_string(bytes: &mut Vec<u8>, s: &str) {
+    let mut length = [0; 2];
+    LittleEndian::write_u16(&mut length, s.len() as u16);
+    bytes.extend_from_slice(&length);
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+pub(crate) fn read_string(input: &mut &[u8]) -> Option<String> {
+    let length = LittleEndian::read_u16(take(input, 2)?) as usize;
+    String::from_utf8(take(input, length)?.to_vec()).ok()
+}
+
+/// Splits `n` bytes off the front of `input`
+pub(crate) fn take<'a>(input: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if input.len() < n {
+        return None;
+    }
+    let (taken, rest) = input.split_at(n);
+    *input = rest;
+    Some(taken)
+}
+
+pub(crate) fn read_u32(input: &mut &[u8]) -> Option<u32> {
+    take(input, 4).map(LittleEndian::read_u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_file_round_trip() {
+        let object = ObjectFile {
+            data: b"hi\0".to_vec(),
+            code: vec![1, 0, 0, 0, 0, 0, 0, 0],
+            symbols: vec![
+                ObjectSymbol {
+                    name: "msg".to_string(),
+                    section: Section::Data,
+                    offset: 0,
+                    exported: true,
+                },
+                ObjectSymbol {
+                    name: "print".to_string(),
+                    section: Section::External,
+                    offset: 0,
+                    exported: false,
+                },
+            ],
+            relocations: vec![Relocation {
+                offset: 2,
+                symbol: 1,
+            }],
+            module: None,
+        };
+        let bytes = object.to_bytes();
+        assert_eq!(bytes[ELF_TYPE_OFFSET], ELF_TYPE_REL);
+        assert_eq!(ObjectFile::from_bytes(&bytes), Some(object));
+        assert_eq!(ObjectFile::from_bytes(&bytes[..bytes.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_truncated_object_file() {
+        let bytes = ObjectFile::default().to_bytes();
+        for length in 0..bytes.len() {
+            assert_eq!(ObjectFile::from_bytes(&bytes[..length]), None);
+        }
+    }
+
+    #[test]
+    fn test_module_table_round_trip() {
+        let table = ModuleTable {
+            name: "math".to_string(),
+            exports: vec![Export {
+                name: "double".to_string(),
+                arity: 1,
+                address: 69,
+            }],
+            imports: vec![Import {
+                module: "io".to_string(),
+                function: "print".to_string(),
+            }],
+            natives: vec![Native {
+                name: "now".to_string(),
+                arity: 0,
+            }],
+        };
+        let bytes = table.to_bytes();
+        assert_eq!(ModuleTable::from_bytes(&bytes), Some(table.clone()));
+        let object = ObjectFile {
+            code: vec![0; 4],
+            module: Some(table),
+            ..ObjectFile::default()
+        };
+        assert_eq!(ObjectFile::from_bytes(&object.to_bytes()), Some(object));
+    }
+}
@@ -1,27 +1,53 @@
+use crate::assembler::expression_parsers::{expression, Expression};
 use crate::assembler::register_parsers::register;
 use crate::assembler::Token;
 
 use nom::{
     branch::alt,
-    bytes::complete::tag,
-    character::complete::{alphanumeric1, digit1, multispace0},
-    sequence::{delimited, preceded},
+    bytes::complete::{is_not, tag},
+    character::complete::{anychar, char, multispace0},
+    combinator::{peek, recognize},
+    multi::many0,
+    sequence::{delimited, pair, preceded, terminated},
     IResult,
 };
 
+/// Parses `#` followed by an expression. Plain literals become integer operands, anything
+/// else is left for the assembler to evaluate once the symbols are known
 pub fn integer_operand(input: &str) -> IResult<&str, Token> {
-    let (leftover, operand) =
-        delimited(multispace0, preceded(tag("#"), digit1), multispace0)(input)?;
-    Ok((
-        leftover,
-        Token::IntegerOperand {
-            value: operand.parse::<i32>().unwrap(),
-        },
-    ))
+    let (leftover, expr) =
+        delimited(multispace0, preceded(tag("#"), expression), multispace0)(input)?;
+    let token = match expr {
+        Expression::Number(value) => Token::IntegerOperand { value },
+        expr => Token::Expression { expr },
+    };
+    Ok((leftover, token))
+}
+
+/// Parses a label usage, or an expression starting with one such as `@end - @start`
+pub fn label_operand(input: &str) -> IResult<&str, Token> {
+    let (leftover, expr) = terminated(preceded(peek(tag("@")), expression), multispace0)(input)?;
+    let token = match expr {
+        Expression::Label(name) => Token::LabelUsage { name },
+        expr => Token::Expression { expr },
+    };
+    Ok((leftover, token))
 }
 
+/// Parses a single quoted string. The text is kept as written, escapes included, and turned
+/// into bytes by `unescape_string` when assembling.
 pub fn irstring(input: &str) -> IResult<&str, Token> {
-    let (leftover, content) = delimited(tag("'"), alphanumeric1, tag("'"))(input)?;
+    let (leftover, content) = terminated(
+        delimited(
+            tag("'"),
+            recognize(many0(alt((
+                is_not("'\\"),
+                recognize(pair(char('\\'), anychar)),
+            )))),
+            tag("'"),
+        ),
+        multispace0,
+    )(input)?;
     Ok((
         leftover,
         Token::IrString {
@@ -30,8 +56,55 @@ pub fn irstring(input: &str) -> IResult<&str, Token> {
     ))
 }
 
+/// Turns the text of a string operand into bytes. Besides UTF-8 text it understands `\n`,
+/// `\t`, `\0`, `\\`, `\'` and `\xHH` for arbitrary bytes.
+pub fn unescape_string(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('\'') => bytes.push(b'\''),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(b) if hex.len() == 2 => bytes.push(b),
+                    _ => return Err(format!("invalid escape '\\x{}'", hex)),
+                }
+            }
+            Some(other) => return Err(format!("invalid escape '\\{}'", other)),
+            None => return Err("string ends with '\\'".to_string()),
+        }
+    }
+    Ok(bytes)
+}
+
+/// The inverse of `unescape_string`: renders bytes as the text of a string operand
+pub fn escape_bytes(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len());
+    for b in bytes {
+        match b {
+            b'\\' => s.push_str("\\\\"),
+            b'\'' => s.push_str("\\'"),
+            b'\n' => s.push_str("\\n"),
+            b'\t' => s.push_str("\\t"),
+            0x20..=0x7e => s.push(*b as char),
+            _ => s.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    s
+}
+
 pub fn operand(input: &str) -> IResult<&str, Token> {
-    alt((integer_operand, register, irstring))(input)
+    alt((integer_operand, register, label_operand, irstring))(input)
 }
 
 mod tests {
@@ -51,12 +124,70 @@ mod tests {
         assert_eq!(result.is_ok(), false);
     }
     #[test]
+    fn test_parse_expression_operand() {
+        let (rest, value) = integer_operand("#BUF_SIZE*2\n").unwrap();
+        assert_eq!(rest, "");
+        assert!(matches!(value, Token::Expression { .. }));
+        let (rest, value) = operand("@end - @start").unwrap();
+        assert_eq!(rest, "");
+        assert!(matches!(value, Token::Expression { .. }));
+    }
+    #[test]
     fn test_parse_irstring() {
         // Test a valid integer operand
         let result = irstring("'Ciaone'");
         assert_eq!(result.is_ok(), true);
         let (rest, value) = result.unwrap();
         assert_eq!(rest, "");
-        assert_eq!(value, Token::IrString { name: "Ciaone".to_string() });
+        assert_eq!(
+            value,
+            Token::IrString {
+                name: "Ciaone".to_string()
+            }
+        );
+    }
+    #[test]
+    fn test_parse_irstring_escapes() {
+        let result = irstring("'Hello, world!\\n\\'quoted\\''\n");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Token::IrString {
+                    name: "Hello, world!\\n\\'quoted\\'".to_string()
+                }
+            ))
+        );
+        assert_eq!(
+            irstring("''"),
+            Ok((
+                "",
+                Token::IrString {
+                    name: "".to_string()
+                }
+            ))
+        );
+    }
+    #[test]
+    fn test_escape_round_trip() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let escaped = escape_bytes(&bytes);
+        assert_eq!(unescape_string(&escaped), Ok(bytes));
+        assert_eq!(unescape_string("é\\x41"), Ok(vec![0xC3, 0xA9, 0x41]));
+        assert!(unescape_string("\\q").is_err());
+        assert!(unescape_string("\\x4").is_err());
+    }
+    #[test]
+    fn test_parse_label_operand() {
+        let result = operand("@loop");
+        assert!(result.is_ok());
+        let (rest, value) = result.unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(
+            value,
+            Token::LabelUsage {
+                name: "loop".to_string()
+            }
+        );
     }
 }
@@ -1,6 +1,14 @@
-use super::instruction_parsers::{instruction, AssemblerInstruction};
+use super::directive_parsers::directive;
+use super::instruction_parsers::{instruction, AssemblerInstruction, EncodingError};
+use super::label_parsers::label_declaration;
 use crate::assembler::SymbolTable;
-use nom::{multi::many1, IResult};
+use nom::{
+    branch::alt,
+    combinator::{eof, peek, recognize},
+    multi::{many0, many1},
+    sequence::terminated,
+    IResult,
+};
 
 #[derive(Debug, PartialEq)]
 pub struct Program {
@@ -8,20 +16,63 @@ pub struct Program {
 }
 
 impl Program {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, EncodingError> {
+        self.to_bytes_from(symbols, 0)
+    }
+
+    /// Encodes the instructions as if the first one were at address `start`
+    pub fn to_bytes_from(
+        &self,
+        symbols: &SymbolTable,
+        start: u32,
+    ) -> Result<Vec<u8>, EncodingError> {
         let mut program = vec![];
         for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes(symbols));
+            let mut bytes = instruction.to_bytes(symbols, start + program.len() as u32)?;
+            program.append(&mut bytes);
         }
-        program
+        Ok(program)
     }
 }
 
+/// Parses a label declared on its own right before another one, or at the end of the source.
+/// Any other label goes with the instruction or directive after it.
+fn label_line(input: &str) -> IResult<&str, AssemblerInstruction> {
+    let (leftover, label) = terminated(
+        label_declaration,
+        alt((recognize(peek(label_declaration)), eof)),
+    )(input)?;
+    Ok((
+        leftover,
+        AssemblerInstruction {
+            label: Some(label),
+            directive: None,
+            opcode: None,
+            operand1: None,
+            operand2: None,
+            operand3: None,
+        },
+    ))
+}
+
 pub fn program(input: &str) -> IResult<&str, Program> {
-    let (leftover, i) = many1(instruction)(input)?;
+    let (leftover, i) = many1(alt((label_line, instruction, directive)))(input)?;
     Ok((leftover, Program { instructions: i }))
 }
 
+/// Like `program`, but also returns for every instruction the byte position in `input` where
+/// its text starts, so callers can map instructions back to source lines. There may be no
+/// instructions, when the source only defines macros.
+pub fn located_program<'a>(input: &'a str) -> IResult<&'a str, (Program, Vec<usize>)> {
+    let (leftover, located) = many0(|i: &'a str| {
+        let position = input.len() - i.trim_start().len();
+        alt((label_line, instruction, directive))(i)
+            .map(|(rest, parsed)| (rest, (position, parsed)))
+    })(input)?;
+    let (positions, instructions) = located.into_iter().unzip();
+    Ok((leftover, (Program { instructions }, positions)))
+}
+
 mod tests {
     #![allow(unused_imports)]
     use super::*;
@@ -62,11 +113,31 @@ mod tests {
         assert_eq!(result.is_ok(), true);
         let (_, program) = result.unwrap();
         let symbols = SymbolTable::new();
-        let bytecode = program.to_bytes(&symbols);
+        let bytecode = program.to_bytes(&symbols).unwrap();
         assert_eq!(bytecode.len(), 16);
         assert_eq!(
             bytecode,
             [1, 0, 0, 100, 1, 1, 0, 200, 2, 0, 1, 2, 0, 0, 0, 0]
         );
     }
+    #[test]
+    fn test_located_program() {
+        let result = located_program("load $0 #100\n\n  .equ TEN 10\nhlt");
+        assert!(result.is_ok());
+        let (leftover, (p, positions)) = result.unwrap();
+        assert_eq!(leftover, "");
+        assert_eq!(p.instructions.len(), 3);
+        assert_eq!(positions, vec![0, 16, 28]);
+    }
+    #[test]
+    fn test_parse_label_lines() {
+        let (leftover, p) = program("start:\nagain: dec $0\nend:").unwrap();
+        assert_eq!(leftover, "");
+        assert_eq!(p.instructions.len(), 3);
+        assert_eq!(p.instructions[0].get_label_name().as_deref(), Some("start"));
+        assert!(!p.instructions[0].is_opcode());
+        assert_eq!(p.instructions[1].get_label_name().as_deref(), Some("again"));
+        assert!(p.instructions[1].is_opcode());
+        assert_eq!(p.instructions[2].get_label_name().as_deref(), Some("end"));
+    }
 }
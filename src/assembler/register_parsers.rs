@@ -1,21 +1,61 @@
 use crate::assembler::Token;
+use crate::vm::REGISTER_COUNT;
 
 use nom::{
+    branch::alt,
     bytes::complete::tag,
-    character::complete::{digit1, multispace0},
+    character::complete::{alphanumeric1, multispace0},
+    combinator::recognize,
+    multi::many1,
     sequence::{delimited, preceded},
     IResult,
 };
 
+/// Conventional names that can be used in place of register numbers
+pub const REGISTER_NAMES: [(&str, u8); 9] = [
+    // Return values
+    ("v0", 0),
+    ("v1", 1),
+    // Arguments
+    ("a0", 2),
+    ("a1", 3),
+    ("a2", 4),
+    ("a3", 5),
+    // Frame pointer, return address and stack pointer
+    ("fp", 29),
+    ("ra", 30),
+    ("sp", 31),
+];
+
+/// Returns the number of a conventionally named register
+pub fn named_register(name: &str) -> Option<u8> {
+    REGISTER_NAMES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, num)| *num)
+}
+
+/// Parses `$` followed by a register number or name. Numbers within the register file and
+/// conventional names are resolved here; anything else is left as a name for the assembler,
+/// which knows about aliases and reports registers that don't exist.
 pub fn register(input: &str) -> IResult<&str, Token> {
-    let (leftover, register) =
-        delimited(multispace0, preceded(tag("$"), digit1), multispace0)(input)?;
-    Ok((
-        leftover,
-        Token::Register {
-            reg_num: register.parse::<u8>().unwrap(),
+    let (leftover, register) = delimited(
+        multispace0,
+        preceded(tag("$"), recognize(many1(alt((alphanumeric1, tag("_")))))),
+        multispace0,
+    )(input)?;
+    let reg_num = match register.parse::<usize>() {
+        Ok(n) if n < REGISTER_COUNT => Some(n as u8),
+        Ok(_) => None,
+        Err(_) => named_register(register),
+    };
+    let token = match reg_num {
+        Some(reg_num) => Token::Register { reg_num },
+        None => Token::RegisterName {
+            name: register.to_string(),
         },
-    ))
+    };
+    Ok((leftover, token))
 }
 
 mod tests {
@@ -28,6 +68,32 @@ mod tests {
         let result = register("0");
         assert_eq!(result.is_ok(), false);
         let result = register("$a");
-        assert_eq!(result.is_ok(), false);
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Token::RegisterName {
+                    name: "a".to_string()
+                }
+            ))
+        );
+    }
+    #[test]
+    fn test_parse_named_register() {
+        assert_eq!(register("$sp"), Ok(("", Token::Register { reg_num: 31 })));
+        assert_eq!(register("$a1 "), Ok(("", Token::Register { reg_num: 3 })));
+    }
+    #[test]
+    fn test_parse_register_out_of_range() {
+        assert_eq!(
+            register("$200"),
+            Ok((
+                "",
+                Token::RegisterName {
+                    name: "200".to_string()
+                }
+            ))
+        );
+        assert_eq!(register("$31"), Ok(("", Token::Register { reg_num: 31 })));
     }
 }
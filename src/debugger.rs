@@ -0,0 +1,406 @@
+use crate::assembler::{SymbolTable, PROGRAM_START};
+use crate::disassembler::DecodedInstruction;
+use crate::instruction::Opcode;
+use crate::vm::{ExitReason, VmError, VM};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::Range;
+
+/// Something whose value the debugger watches
+#[derive(Debug, PartialEq, Clone)]
+pub enum Watch {
+    Register(usize),
+    /// Bytes of the heap, which may not be allocated yet
+    Heap(Range<usize>),
+}
+
+impl Watch {
+    /// The current value, as bytes. The part of a heap range that isn't allocated is left out.
+    fn read(&self, vm: &VM) -> Vec<u8> {
+        match self {
+            Watch::Register(register) => vm
+                .register(*register)
+                .map_or(vec![], |value| value.to_be_bytes().to_vec()),
+            Watch::Heap(range) => {
+                let heap = vm.heap();
+                heap.get(range.start.min(heap.len())..range.end.min(heap.len()))
+                    .map_or(vec![], <[u8]>::to_vec)
+            }
+        }
+    }
+
+    fn format_value(&self, value: &[u8]) -> String {
+        match (self, value) {
+            (Watch::Register(_), [a, b, c, d]) => i32::from_be_bytes([*a, *b, *c, *d]).to_string(),
+            _ => {
+                let bytes: Vec<String> = value.iter().map(|b| format!("{:02x}", b)).collect();
+                format!("[{}]", bytes.join(" "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watch::Register(register) => write!(f, "${}", register),
+            Watch::Heap(range) => write!(f, "heap[{}..{}]", range.start, range.end),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Watchpoint {
+    watch: Watch,
+    /// The value when it was last checked
+    value: Vec<u8>,
+}
+
+/// Why the debugger gave control back
+#[derive(Debug, PartialEq, Clone)]
+pub enum Stop {
+    /// The instructions asked for were executed
+    Stepped,
+    /// The next instruction has a breakpoint
+    Breakpoint {
+        address: usize,
+    },
+    /// The last instruction changed a watched value
+    Watchpoint {
+        watch: Watch,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+    Exited(ExitReason),
+    Failed(VmError),
+    /// Going backwards reached the oldest instruction of the journal
+    NoHistory,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Stepped => write!(f, "Stepped"),
+            Stop::Breakpoint { address } => write!(f, "Breakpoint at {:#06x}", address),
+            Stop::Watchpoint { watch, old, new } => write!(
+                f,
+                "{} changed from {} to {}",
+                watch,
+                watch.format_value(old),
+                watch.format_value(new)
+            ),
+            Stop::Exited(reason) => write!(f, "{}", reason),
+            Stop::Failed(error) => write!(f, "{}", error),
+            Stop::NoHistory => write!(f, "No more instructions in the journal"),
+        }
+    }
+}
+
+/// Executes the program of a VM under control: it stops at breakpoints, which are addresses of
+/// instructions, and as soon as the value of a watchpoint changes
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<usize>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// Returns false if there already was a breakpoint at `address`
+    pub fn add_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    /// Returns false if there was no breakpoint at `address`
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Starts watching a value from what it is in `vm` now. Returns the number of the
+    /// watchpoint, which `remove_watchpoint` takes.
+    pub fn add_watchpoint(&mut self, watch: Watch, vm: &VM) -> usize {
+        let value = watch.read(vm);
+        self.watchpoints.push(Watchpoint { watch, value });
+        self.watchpoints.len() - 1
+    }
+
+    /// Returns false if there is no watchpoint `number`. The following watchpoints are
+    /// renumbered.
+    pub fn remove_watchpoint(&mut self, number: usize) -> bool {
+        if number < self.watchpoints.len() {
+            self.watchpoints.remove(number);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = &Watch> {
+        self.watchpoints.iter().map(|w| &w.watch)
+    }
+
+    /// Starts the program from its entry point and runs it until it stops
+    pub fn run(&mut self, vm: &mut VM) -> Stop {
+        if let Err(error) = vm.start() {
+            return Stop::Failed(error);
+        }
+        self.refresh_watchpoints(vm);
+        if self.breakpoints.contains(&vm.pc()) {
+            return Stop::Breakpoint { address: vm.pc() };
+        }
+        self.resume(vm)
+    }
+
+    /// Executes one instruction
+    pub fn step(&mut self, vm: &mut VM) -> Stop {
+        match vm.run_once() {
+            Ok(Some(reason)) => Stop::Exited(reason),
+            Ok(None) => self.changed_watchpoint(vm).unwrap_or(Stop::Stepped),
+            Err(error) => Stop::Failed(error),
+        }
+    }
+
+    /// Executes one instruction like `step`, except that a `CALLEXT` is executed up to its
+    /// return
+    pub fn next(&mut self, vm: &mut VM) -> Stop {
+        let address = vm.pc();
+        if instruction_at(vm, address).map(|i| i.opcode) != Some(Opcode::CALLEXT) {
+            return self.step(vm);
+        }
+        let depth = vm.call_depth();
+        loop {
+            match self.step(vm) {
+                Stop::Stepped if vm.pc() == address + 4 && vm.call_depth() == depth => {
+                    return Stop::Stepped
+                }
+                Stop::Stepped if self.breakpoints.contains(&vm.pc()) => {
+                    return Stop::Breakpoint { address: vm.pc() }
+                }
+                Stop::Stepped => {}
+                stop => return stop,
+            }
+        }
+    }
+
+    /// Executes instructions until a breakpoint or watchpoint is hit, or the program stops.
+    /// A breakpoint on the current instruction doesn't count, so that execution can go on
+    /// after stopping there.
+    pub fn resume(&mut self, vm: &mut VM) -> Stop {
+        loop {
+            match self.step(vm) {
+                Stop::Stepped if self.breakpoints.contains(&vm.pc()) => {
+                    return Stop::Breakpoint { address: vm.pc() }
+                }
+                Stop::Stepped => {}
+                stop => return stop,
+            }
+        }
+    }
+
+    /// Undoes the last instruction, which the VM must have recorded in its journal
+    pub fn reverse_step(&mut self, vm: &mut VM) -> Stop {
+        if !vm.undo() {
+            return Stop::NoHistory;
+        }
+        self.changed_watchpoint(vm).unwrap_or(Stop::Stepped)
+    }
+
+    /// Undoes instructions until getting back to a breakpoint, or undoing a change of a
+    /// watchpoint
+    pub fn reverse_continue(&mut self, vm: &mut VM) -> Stop {
+        loop {
+            match self.reverse_step(vm) {
+                Stop::Stepped if self.breakpoints.contains(&vm.pc()) => {
+                    return Stop::Breakpoint { address: vm.pc() }
+                }
+                Stop::Stepped => {}
+                stop => return stop,
+            }
+        }
+    }
+
+    /// The instructions around the program counter, disassembled. The current one is marked
+    /// with `=>`, those with a breakpoint with `*`, and labels of `symbols` are shown.
+    pub fn listing(&self, vm: &VM, symbols: &SymbolTable, context: usize) -> Vec<String> {
+        let pc = vm.pc();
+        let first = pc.saturating_sub(context * 4).max(vm.entry_point().min(pc));
+        let label = |address: usize| {
+            symbols
+                .label_at(address as u32)
+                .map_or_else(|| format!("{:#06x}", address), str::to_string)
+        };
+        (first..=pc + context * 4)
+            .step_by(4)
+            .filter(|address| *address >= PROGRAM_START)
+            .filter_map(|address| {
+                let decoded = instruction_at(vm, address)?;
+                let marker = match (address == pc, self.breakpoints.contains(&address)) {
+                    (true, true) => "*=>",
+                    (true, false) => " =>",
+                    (false, true) => "*  ",
+                    (false, false) => "   ",
+                };
+                let name = symbols
+                    .label_at(address as u32)
+                    .map_or(String::new(), |name| format!("{}: ", name));
+                let text = decoded.to_assembly(address, |target| label(target as usize));
+                Some(format!("{} {:#06x}  {}{}", marker, address, name, text))
+            })
+            .collect()
+    }
+
+    fn refresh_watchpoints(&mut self, vm: &VM) {
+        for watchpoint in &mut self.watchpoints {
+            watchpoint.value = watchpoint.watch.read(vm);
+        }
+    }
+
+    /// Finds the first watchpoint whose value changed and records the new value of every one
+    fn changed_watchpoint(&mut self, vm: &VM) -> Option<Stop> {
+        let mut stop = None;
+        for watchpoint in &mut self.watchpoints {
+            let value = watchpoint.watch.read(vm);
+            if value != watchpoint.value {
+                let old = std::mem::replace(&mut watchpoint.value, value.clone());
+                stop = stop.or(Some(Stop::Watchpoint {
+                    watch: watchpoint.watch.clone(),
+                    old,
+                    new: value,
+                }));
+            }
+        }
+        stop
+    }
+}
+
+fn instruction_at(vm: &VM, address: usize) -> Option<DecodedInstruction> {
+    let bytes = vm.program().get(address..address + 4)?;
+    Some(DecodedInstruction::decode(&[
+        bytes[0], bytes[1], bytes[2], bytes[3],
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn loaded(source: &str) -> (VM, Assembler) {
+        let mut asm = Assembler::new();
+        let program = asm.assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.load(&program).unwrap();
+        (vm, asm)
+    }
+
+    #[test]
+    fn test_breakpoints_and_steps() {
+        let (mut vm, asm) = loaded("load $0 #3\ntop: dec $0\nbgt $0 $1 @top\nhlt");
+        let top = asm.symbols.label_value("top").unwrap() as usize;
+        let mut debugger = Debugger::new();
+        assert!(debugger.add_breakpoint(top));
+        assert!(!debugger.add_breakpoint(top));
+        assert_eq!(debugger.run(&mut vm), Stop::Breakpoint { address: top });
+        assert_eq!(vm.register(0), Some(3));
+        assert_eq!(debugger.resume(&mut vm), Stop::Breakpoint { address: top });
+        assert_eq!(vm.register(0), Some(2));
+        assert_eq!(debugger.step(&mut vm), Stop::Stepped);
+        assert_eq!(vm.pc(), top + 4);
+
+        let listing = debugger.listing(&vm, &asm.symbols, 1);
+        assert_eq!(listing.len(), 3);
+        assert_eq!(listing[0], format!("*   {:#06x}  top: dec $0", top));
+        assert_eq!(listing[1], format!(" => {:#06x}  bgt $0 $1 @top", top + 4));
+
+        assert!(debugger.remove_breakpoint(top));
+        assert_eq!(debugger.resume(&mut vm), Stop::Exited(ExitReason::Halted));
+        assert_eq!(vm.register(0), Some(0));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let (mut vm, _) = loaded("load $1 #8\naloc $1\nload $2 #1\ninc $3\nhlt");
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(Watch::Register(2), &vm);
+        debugger.add_watchpoint(Watch::Heap(4..6), &vm);
+        let stop = debugger.run(&mut vm);
+        assert_eq!(
+            stop,
+            Stop::Watchpoint {
+                watch: Watch::Heap(4..6),
+                old: vec![],
+                new: vec![0, 0]
+            }
+        );
+        assert_eq!(stop.to_string(), "heap[4..6] changed from [] to [00 00]");
+        let stop = debugger.resume(&mut vm);
+        assert_eq!(stop.to_string(), "$2 changed from 0 to 1");
+        assert!(debugger.remove_watchpoint(0));
+        assert!(!debugger.remove_watchpoint(1));
+        assert_eq!(debugger.resume(&mut vm), Stop::Exited(ExitReason::Halted));
+    }
+
+    #[test]
+    fn test_reverse_execution() {
+        let (mut vm, asm) = loaded("load $0 #3\ntop: dec $0\nbgt $0 $1 @top\nload $2 #9\nhlt");
+        let top = asm.symbols.label_value("top").unwrap() as usize;
+        vm.set_journal(Some(100));
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.run(&mut vm), Stop::Exited(ExitReason::Halted));
+        debugger.add_breakpoint(top);
+        debugger.add_watchpoint(Watch::Register(2), &vm);
+        assert_eq!(
+            debugger.reverse_step(&mut vm),
+            Stop::Stepped,
+            "undoing HLT changes nothing watched"
+        );
+        assert_eq!(
+            debugger.reverse_continue(&mut vm).to_string(),
+            "$2 changed from 9 to 0"
+        );
+        assert_eq!(
+            debugger.reverse_continue(&mut vm),
+            Stop::Breakpoint { address: top }
+        );
+        assert_eq!(vm.register(0), Some(1));
+        debugger.remove_breakpoint(top);
+        assert_eq!(debugger.reverse_continue(&mut vm), Stop::NoHistory);
+        assert_eq!(vm.register(0), Some(0));
+        assert_eq!(vm.pc(), vm.entry_point());
+        assert_eq!(
+            debugger.resume(&mut vm).to_string(),
+            "$2 changed from 0 to 9"
+        );
+    }
+
+    #[test]
+    fn test_next_steps_over_calls() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".module main\n.import lib f\ncallext @lib.f\nhlt")
+            .unwrap();
+        let lib = Assembler::new()
+            .assemble_object(".module lib\n.export f/0\nf: inc $0\ninc $0\nret")
+            .unwrap();
+        let mut vm = VM::new();
+        vm.load(&program).unwrap();
+        vm.load_module(&lib.to_bytes()).unwrap();
+        let mut debugger = Debugger::new();
+        vm.start().unwrap();
+        let call = vm.pc();
+        assert_eq!(debugger.next(&mut vm), Stop::Stepped);
+        assert_eq!(vm.pc(), call + 4);
+        assert_eq!(vm.register(0), Some(2));
+
+        vm.start().unwrap();
+        assert_eq!(debugger.step(&mut vm), Stop::Stepped);
+        assert_eq!(vm.call_depth(), 1);
+    }
+}
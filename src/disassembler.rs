@@ -0,0 +1,358 @@
+use crate::assembler::object_file::ModuleTable;
+use crate::assembler::operand_parsers::escape_bytes;
+use crate::assembler::{code_end, data_length, ELF_HEADER_PREFIX, PROGRAM_START};
+use crate::instruction::{Opcode, OperandKind};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DisassemblerError {
+    InvalidHeader,
+    TruncatedData,
+    UnterminatedString { address: usize },
+    TruncatedInstruction { address: usize },
+    UnknownOpcode { address: usize, byte: u8 },
+    NonZeroPadding { address: usize },
+    BranchOutsideCode { address: usize, target: isize },
+    InvalidModuleTable,
+    ExportNotFound { name: String },
+    UnknownImport { address: usize },
+    UnknownNative { address: usize },
+}
+
+impl fmt::Display for DisassemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisassemblerError::InvalidHeader => write!(f, "the header is missing or incorrect"),
+            DisassemblerError::TruncatedData => {
+                write!(f, "the data section is longer than the program")
+            }
+            DisassemblerError::UnterminatedString { address } => {
+                write!(f, "{:#06x}: string without a terminating zero", address)
+            }
+            DisassemblerError::TruncatedInstruction { address } => {
+                write!(
+                    f,
+                    "{:#06x}: the program ends in the middle of an instruction",
+                    address
+                )
+            }
+            DisassemblerError::UnknownOpcode { address, byte } => {
+                write!(f, "{:#06x}: unknown opcode {}", address, byte)
+            }
+            DisassemblerError::NonZeroPadding { address } => {
+                write!(f, "{:#06x}: unused operand bytes are not zero", address)
+            }
+            DisassemblerError::BranchOutsideCode { address, target } => write!(
+                f,
+                "{:#06x}: branch to {:#06x}, which is not an instruction",
+                address, target
+            ),
+            DisassemblerError::InvalidModuleTable => {
+                write!(f, "the module table after the code is malformed")
+            }
+            DisassemblerError::ExportNotFound { name } => write!(
+                f,
+                "export '{}' is not the address of an instruction or a string",
+                name
+            ),
+            DisassemblerError::UnknownImport { address } => {
+                write!(
+                    f,
+                    "{:#06x}: CALLEXT of an import that isn't declared",
+                    address
+                )
+            }
+            DisassemblerError::UnknownNative { address } => {
+                write!(f, "{:#06x}: CALLN of a native that isn't declared", address)
+            }
+        }
+    }
+}
+
+/// An instruction decoded from bytecode
+#[derive(Debug, PartialEq, Clone)]
+pub struct DecodedInstruction {
+    pub opcode: Opcode,
+    pub operands: Vec<(OperandKind, i32)>,
+}
+
+impl DecodedInstruction {
+    /// Decodes the 4 bytes of an instruction
+    pub fn decode(bytes: &[u8; 4]) -> DecodedInstruction {
+        let opcode = Opcode::from(bytes[0]);
+        let mut operands = vec![];
+        let mut i = 1;
+        for kind in opcode.operands() {
+            let value = match kind {
+                OperandKind::Register => i32::from(bytes[i]),
+                OperandKind::Integer => {
+                    i32::from(u16::from(bytes[i]) << 8 | u16::from(bytes[i + 1]))
+                }
+                OperandKind::BranchOffset => i32::from(bytes[i] as i8),
+            };
+            operands.push((*kind, value));
+            i += kind.width();
+        }
+        DecodedInstruction { opcode, operands }
+    }
+
+    /// Where a branch starting at `address` goes
+    pub fn branch_target(&self, address: usize) -> Option<isize> {
+        self.operands
+            .iter()
+            .find(|(kind, _)| *kind == OperandKind::BranchOffset)
+            .map(|(_, offset)| address as isize + *offset as isize * 4)
+    }
+
+    /// Renders the instruction as assembly. `target_name` gives the text used for a branch
+    /// target, given its address.
+    pub fn to_assembly<F>(&self, address: usize, target_name: F) -> String
+    where
+        F: Fn(isize) -> String,
+    {
+        let mut text = format!("{:?}", self.opcode).to_lowercase();
+        for (kind, value) in &self.operands {
+            text.push(' ');
+            match kind {
+                OperandKind::Register => text.push_str(&format!("${}", value)),
+                OperandKind::Integer => text.push_str(&format!("#{}", value)),
+                OperandKind::BranchOffset => {
+                    let target = address as isize + *value as isize * 4;
+                    text.push('@');
+                    text.push_str(&target_name(target));
+                }
+            }
+        }
+        text
+    }
+}
+
+/// Turns a program produced by the assembler back into source. Strings in the data section
+/// become `.asciiz` directives, every branch target gets a label and the module table becomes
+/// `.module`, `.import`, `.native` and `.export` directives, so that assembling the result gives
+/// back the same bytes.
+pub fn disassemble(program: &[u8]) -> Result<String, DisassemblerError> {
+    if program.len() < PROGRAM_START || program[..ELF_HEADER_PREFIX.len()] != ELF_HEADER_PREFIX {
+        return Err(DisassemblerError::InvalidHeader);
+    }
+    let code_start = data_length(program)
+        .map(|length| PROGRAM_START + length)
+        .filter(|start| *start <= program.len())
+        .ok_or(DisassemblerError::TruncatedData)?;
+    let code_end = code_end(program);
+    if code_end < code_start || code_end > program.len() {
+        return Err(DisassemblerError::TruncatedData);
+    }
+    let module = match &program[code_end..] {
+        [] => None,
+        table => Some(ModuleTable::from_bytes(table).ok_or(DisassemblerError::InvalidModuleTable)?),
+    };
+
+    // Exported functions keep their names, other labels get made up ones
+    let mut labels = BTreeMap::new();
+    if let Some(module) = &module {
+        for export in &module.exports {
+            labels.insert(export.address as usize, export.name.clone());
+        }
+    }
+    let mut placed = vec![];
+
+    let mut strings = vec![];
+    let mut address = PROGRAM_START;
+    for string in program[PROGRAM_START..code_start].split_inclusive(|b| *b == 0) {
+        match string.split_last() {
+            Some((0, text)) => strings.push((address, text)),
+            _ => return Err(DisassemblerError::UnterminatedString { address }),
+        }
+        placed.push(address);
+        address += string.len();
+    }
+
+    let mut instructions = vec![];
+    for (n, chunk) in program[code_start..code_end].chunks(4).enumerate() {
+        let address = code_start + n * 4;
+        if chunk.len() < 4 {
+            return Err(DisassemblerError::TruncatedInstruction { address });
+        }
+        let decoded = DecodedInstruction::decode(&[chunk[0], chunk[1], chunk[2], chunk[3]]);
+        if decoded.opcode == Opcode::IGL {
+            return Err(DisassemblerError::UnknownOpcode {
+                address,
+                byte: chunk[0],
+            });
+        }
+        let used: usize = 1 + decoded
+            .opcode
+            .operands()
+            .iter()
+            .map(|k| k.width())
+            .sum::<usize>();
+        if chunk[used..].iter().any(|b| *b != 0) {
+            return Err(DisassemblerError::NonZeroPadding { address });
+        }
+        placed.push(address);
+        instructions.push((address, decoded));
+    }
+
+    for (address, decoded) in &instructions {
+        if let Some(target) = decoded.branch_target(*address) {
+            let inside = target >= code_start as isize
+                && (target as usize) < code_end
+                && (target as usize - code_start).is_multiple_of(4);
+            if !inside {
+                return Err(DisassemblerError::BranchOutsideCode {
+                    address: *address,
+                    target,
+                });
+            }
+            labels.entry(target as usize).or_insert_with(String::new);
+        }
+    }
+    if let Some((_, name)) = labels.iter().find(|(a, _)| !placed.contains(a)) {
+        return Err(DisassemblerError::ExportNotFound { name: name.clone() });
+    }
+    let mut n = 0;
+    let unnamed: Vec<usize> = labels
+        .iter()
+        .filter(|(_, name)| name.is_empty())
+        .map(|(address, _)| *address)
+        .collect();
+    for address in unnamed {
+        let name = unused_name("L", &mut n, &labels);
+        labels.insert(address, name);
+    }
+
+    let mut lines = vec![];
+    if let Some(module) = &module {
+        if !module.name.is_empty() {
+            lines.push(format!(".module {}", module.name));
+        }
+        for import in &module.imports {
+            lines.push(format!(".import {} {}", import.module, import.function));
+        }
+        for native in &module.natives {
+            lines.push(format!(".native {}/{}", native.name, native.arity));
+        }
+        for export in &module.exports {
+            lines.push(format!(".export {}/{}", export.name, export.arity));
+        }
+    }
+    let mut n = 0;
+    for (address, text) in strings {
+        let label = match labels.get(&address) {
+            Some(label) => label.clone(),
+            None => unused_name("S", &mut n, &labels),
+        };
+        lines.push(format!("{}: .asciiz '{}'", label, escape_bytes(text)));
+    }
+    for (address, decoded) in &instructions {
+        let text = if decoded.opcode == Opcode::CALLEXT {
+            let import = module
+                .as_ref()
+                .and_then(|m| m.imports.get(decoded.operands[0].1 as usize))
+                .ok_or(DisassemblerError::UnknownImport { address: *address })?;
+            format!("callext @{}.{}", import.module, import.function)
+        } else if decoded.opcode == Opcode::CALLN {
+            let native = module
+                .as_ref()
+                .and_then(|m| m.natives.get(decoded.operands[0].1 as usize))
+                .ok_or(DisassemblerError::UnknownNative { address: *address })?;
+            format!("calln @{}", native.name)
+        } else {
+            decoded.to_assembly(*address, |target| labels[&(target as usize)].clone())
+        };
+        match labels.get(address) {
+            Some(label) => lines.push(format!("{}: {}", label, text)),
+            None => lines.push(text),
+        }
+    }
+    let mut source = lines.join("\n");
+    source.push('\n');
+    Ok(source)
+}
+
+/// Makes up a label name that isn't one of `labels`
+fn unused_name(prefix: &str, n: &mut usize, labels: &BTreeMap<usize, String>) -> String {
+    loop {
+        let name = format!("{}{}", prefix, n);
+        *n += 1;
+        if !labels.values().any(|l| *l == name) {
+            return name;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn assert_round_trip(source: &str) -> String {
+        let mut asm = Assembler::new();
+        let program = asm.assemble(source).unwrap();
+        let disassembled = disassemble(&program).unwrap();
+        let reassembled = asm.assemble(&disassembled).unwrap();
+        assert_eq!(program, reassembled);
+        disassembled
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = "msg: .asciiz 'Hello, world!\\n'\n.asciiz 'caf\u{e9}'\nload $0 #500\nload $1 @msg\ntop: dec $0\nadd $0 $1 $2\nbgt $0 $1 @top\nbeq $0 $1 @end\neq $0 $1\njeq $3\naloc $0\nend: hlt";
+        let disassembled = assert_round_trip(source);
+        assert!(disassembled
+            .starts_with("S0: .asciiz 'Hello, world!\\n'\nS1: .asciiz 'caf\\xc3\\xa9'\n"));
+        assert!(disassembled.contains("L0: dec $0\n"));
+        assert!(disassembled.contains("bgt $0 $1 @L0\n"));
+        assert!(disassembled.ends_with("L1: hlt\n"));
+    }
+
+    #[test]
+    fn test_round_trip_module() {
+        let source = ".module math\n.import io print\n.native log/1\n.export double/1\n.export L0/0\nL0: hlt\ndouble: add $2 $2 $0\nbeq $0 $1 @1f\ncallext @io.print\ncalln @log\n1: ret";
+        let disassembled = assert_round_trip(source);
+        assert!(disassembled
+            .starts_with(".module math\n.import io print\n.native log/1\n.export double/1\n"));
+        assert!(disassembled.contains("callext @io.print\ncalln @log\n"));
+        assert!(disassembled.contains("L1: ret\n"));
+    }
+
+    #[test]
+    fn test_round_trip_without_data() {
+        assert_round_trip("load $0 #10\nload $1 #20\nadd $0 $1 $2\nhlt");
+    }
+
+    #[test]
+    fn test_disassemble_errors() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble("load $0 #1\nhlt").unwrap();
+        assert_eq!(
+            disassemble(&program[..10]),
+            Err(DisassemblerError::InvalidHeader)
+        );
+        assert_eq!(
+            disassemble(&program[..program.len() - 1]),
+            Err(DisassemblerError::TruncatedInstruction {
+                address: PROGRAM_START + 4
+            })
+        );
+        let mut bad = program.clone();
+        bad[PROGRAM_START] = 200;
+        assert_eq!(
+            disassemble(&bad),
+            Err(DisassemblerError::UnknownOpcode {
+                address: PROGRAM_START,
+                byte: 200
+            })
+        );
+        let mut bad = program.clone();
+        bad[PROGRAM_START + 7] = 1;
+        assert_eq!(
+            disassemble(&bad),
+            Err(DisassemblerError::NonZeroPadding {
+                address: PROGRAM_START + 4
+            })
+        );
+    }
+}
@@ -20,6 +20,16 @@ pub enum Opcode {
     ALOC,
     INC,
     DEC,
+    BEQ,
+    BNE,
+    BGT,
+    BLT,
+    BGE,
+    BLE,
+    CALLEXT,
+    RET,
+    CALLN,
+    SYSCALL,
     IGL,
 }
 
@@ -46,6 +56,16 @@ impl From<u8> for Opcode {
             17 => Opcode::ALOC,
             18 => Opcode::INC,
             19 => Opcode::DEC,
+            20 => Opcode::BEQ,
+            21 => Opcode::BNE,
+            22 => Opcode::BGT,
+            23 => Opcode::BLT,
+            24 => Opcode::BGE,
+            25 => Opcode::BLE,
+            26 => Opcode::CALLEXT,
+            27 => Opcode::RET,
+            28 => Opcode::CALLN,
+            29 => Opcode::SYSCALL,
             _ => Opcode::IGL,
         }
     }
@@ -74,6 +94,16 @@ impl From<Opcode> for u8 {
             Opcode::ALOC => 17,
             Opcode::INC => 18,
             Opcode::DEC => 19,
+            Opcode::BEQ => 20,
+            Opcode::BNE => 21,
+            Opcode::BGT => 22,
+            Opcode::BLT => 23,
+            Opcode::BGE => 24,
+            Opcode::BLE => 25,
+            Opcode::CALLEXT => 26,
+            Opcode::RET => 27,
+            Opcode::CALLN => 28,
+            Opcode::SYSCALL => 29,
             Opcode::IGL => 100,
         }
     }
@@ -102,11 +132,106 @@ impl From<&str> for Opcode {
             "aloc" => Opcode::ALOC,
             "inc" => Opcode::INC,
             "dec" => Opcode::DEC,
+            "beq" => Opcode::BEQ,
+            "bne" => Opcode::BNE,
+            "bgt" => Opcode::BGT,
+            "blt" => Opcode::BLT,
+            "bge" => Opcode::BGE,
+            "ble" => Opcode::BLE,
+            "callext" => Opcode::CALLEXT,
+            "ret" => Opcode::RET,
+            "calln" => Opcode::CALLN,
+            "syscall" => Opcode::SYSCALL,
             _ => Opcode::IGL,
         }
     }
 }
 
+/// What the bytes following an opcode hold
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OperandKind {
+    /// One byte, the number of a register
+    Register,
+    /// Two bytes, a big endian 16 bit integer
+    Integer,
+    /// One byte, a signed offset in instructions from the start of a branch
+    BranchOffset,
+}
+
+impl OperandKind {
+    pub fn width(self) -> usize {
+        match self {
+            OperandKind::Register | OperandKind::BranchOffset => 1,
+            OperandKind::Integer => 2,
+        }
+    }
+}
+
+impl Opcode {
+    /// The operands of the opcode in the order they are encoded. Every instruction is 4 bytes
+    /// long, whatever the operands leave unused is zero padding.
+    pub fn operands(self) -> &'static [OperandKind] {
+        use OperandKind::*;
+        match self {
+            Opcode::HLT | Opcode::RET | Opcode::IGL => &[],
+            Opcode::CALLEXT | Opcode::CALLN | Opcode::SYSCALL => &[Integer],
+            Opcode::LOAD => &[Register, Integer],
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => {
+                &[Register, Register, Register]
+            }
+            Opcode::JMP
+            | Opcode::JMPF
+            | Opcode::JMPB
+            | Opcode::JEQ
+            | Opcode::JNEQ
+            | Opcode::ALOC
+            | Opcode::INC
+            | Opcode::DEC => &[Register],
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTQ | Opcode::LTQ => {
+                &[Register, Register]
+            }
+            Opcode::BEQ | Opcode::BNE | Opcode::BGT | Opcode::BLT | Opcode::BGE | Opcode::BLE => {
+                &[Register, Register, BranchOffset]
+            }
+        }
+    }
+
+    /// True for the fused compare-and-branch opcodes, whose last operand byte is a signed
+    /// offset (in instructions) relative to the branch itself
+    pub fn is_branch(self) -> bool {
+        matches!(
+            self,
+            Opcode::BEQ | Opcode::BNE | Opcode::BGT | Opcode::BLT | Opcode::BGE | Opcode::BLE
+        )
+    }
+
+    /// Returns the branch that jumps when this comparison sets the equal flag
+    pub fn branch_on_true(self) -> Option<Opcode> {
+        match self {
+            Opcode::EQ => Some(Opcode::BEQ),
+            Opcode::NEQ => Some(Opcode::BNE),
+            Opcode::GT => Some(Opcode::BGT),
+            Opcode::LT => Some(Opcode::BLT),
+            Opcode::GTQ => Some(Opcode::BGE),
+            Opcode::LTQ => Some(Opcode::BLE),
+            _ => None,
+        }
+    }
+
+    /// Returns the branch that jumps when this comparison clears the equal flag
+    pub fn branch_on_false(self) -> Option<Opcode> {
+        match self {
+            Opcode::EQ => Some(Opcode::BNE),
+            Opcode::NEQ => Some(Opcode::BEQ),
+            Opcode::GT => Some(Opcode::BLE),
+            Opcode::LT => Some(Opcode::BGE),
+            Opcode::GTQ => Some(Opcode::BLT),
+            Opcode::LTQ => Some(Opcode::BGT),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Instruction {
     opcode: Opcode,
@@ -144,4 +269,53 @@ mod tests {
         let opcode = Opcode::from("illegal");
         assert_eq!(opcode, Opcode::IGL);
     }
+    #[test]
+    fn test_branch_opcodes_round_trip() {
+        for op in &[
+            Opcode::BEQ,
+            Opcode::BNE,
+            Opcode::BGT,
+            Opcode::BLT,
+            Opcode::BGE,
+            Opcode::BLE,
+        ] {
+            let byte: u8 = (*op).into();
+            assert_eq!(Opcode::from(byte), *op);
+            assert!(op.is_branch());
+        }
+        assert_eq!(Opcode::from("bge"), Opcode::BGE);
+        assert!(!Opcode::JEQ.is_branch());
+    }
+    #[test]
+    fn test_operands_fit_in_instruction() {
+        for byte in 0..=255u8 {
+            let width: usize = Opcode::from(byte)
+                .operands()
+                .iter()
+                .map(|o| o.width())
+                .sum();
+            assert!(width <= 3);
+        }
+        assert_eq!(
+            Opcode::LOAD.operands(),
+            &[OperandKind::Register, OperandKind::Integer]
+        );
+    }
+    #[test]
+    fn test_call_opcodes() {
+        assert_eq!(Opcode::from("callext"), Opcode::CALLEXT);
+        assert_eq!(Opcode::from(27), Opcode::RET);
+        assert_eq!(u8::from(Opcode::CALLEXT), 26);
+        assert_eq!(Opcode::CALLEXT.operands(), &[OperandKind::Integer]);
+        assert_eq!(Opcode::from("calln"), Opcode::CALLN);
+        assert_eq!(Opcode::from(28), Opcode::CALLN);
+        assert_eq!(Opcode::from("syscall"), Opcode::SYSCALL);
+        assert_eq!(u8::from(Opcode::SYSCALL), 29);
+    }
+    #[test]
+    fn test_compare_to_branch() {
+        assert_eq!(Opcode::GT.branch_on_true(), Some(Opcode::BGT));
+        assert_eq!(Opcode::GT.branch_on_false(), Some(Opcode::BLE));
+        assert_eq!(Opcode::ADD.branch_on_true(), None);
+    }
 }
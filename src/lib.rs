@@ -0,0 +1,27 @@
+//! A BEAM inspired VM, with the assembler and tools around it.
+//!
+//! ```
+//! use bumbam::assembler::Assembler;
+//! use bumbam::vm::{ExitReason, VmBuilder};
+//!
+//! let program = Assembler::new().assemble("load $0 #21\nadd $0 $0 $0\nhlt").unwrap();
+//! let mut vm = VmBuilder::new().max_instructions(1000).build();
+//! vm.load(&program).unwrap();
+//! assert_eq!(vm.run(), Ok(ExitReason::Halted));
+//! assert_eq!(vm.register(0), Some(42));
+//! ```
+
+#![cfg_attr(test, allow(clippy::bool_assert_comparison))]
+
+extern crate nom;
+
+pub mod assembler;
+pub mod debugger;
+pub mod disassembler;
+pub mod instruction;
+pub mod linker;
+pub mod native;
+pub mod repl;
+pub mod syscall;
+pub mod verifier;
+pub mod vm;
@@ -0,0 +1,222 @@
+use crate::assembler::object_file::{ObjectFile, Section};
+use crate::assembler::{elf_header, ELF_TYPE_EXEC, PROGRAM_START};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum LinkerError {
+    DuplicateSymbol {
+        name: String,
+        first: String,
+        second: String,
+    },
+    UndefinedSymbol {
+        name: String,
+        object: String,
+    },
+    InvalidRelocation {
+        object: String,
+    },
+    ModuleObject {
+        object: String,
+    },
+}
+
+impl fmt::Display for LinkerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkerError::DuplicateSymbol {
+                name,
+                first,
+                second,
+            } => write!(f, "'{}' is exported by both {} and {}", name, first, second),
+            LinkerError::UndefinedSymbol { name, object } => {
+                write!(f, "{}: undefined reference to '{}'", object, name)
+            }
+            LinkerError::InvalidRelocation { object } => {
+                write!(f, "{}: relocation outside of the code section", object)
+            }
+            LinkerError::ModuleObject { object } => write!(
+                f,
+                "{}: modules are loaded into the VM by name rather than linked",
+                object
+            ),
+        }
+    }
+}
+
+/// Combines object files into an executable. The data sections of all objects come first, in
+/// the order the objects were added, followed by their code sections in the same order, so the
+/// program starts with the code of the first object.
+#[derive(Debug, Default)]
+pub struct Linker {
+    objects: Vec<(String, ObjectFile)>,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker { objects: vec![] }
+    }
+
+    /// Adds an object file, `name` being used in error messages
+    pub fn add_object(&mut self, name: &str, object: ObjectFile) {
+        self.objects.push((name.to_string(), object));
+    }
+
+    pub fn link(&self) -> Result<Vec<u8>, Vec<LinkerError>> {
+        let data_length: usize = self.objects.iter().map(|(_, o)| o.data.len()).sum();
+        let mut data_start = PROGRAM_START;
+        let mut code_start = PROGRAM_START + data_length;
+        let mut bases = vec![];
+        for (_, object) in &self.objects {
+            bases.push((data_start as u32, code_start as u32));
+            data_start += object.data.len();
+            code_start += object.code.len();
+        }
+
+        let mut errors: Vec<LinkerError> = self
+            .objects
+            .iter()
+            .filter(|(_, object)| object.module.is_some())
+            .map(|(name, _)| LinkerError::ModuleObject {
+                object: name.clone(),
+            })
+            .collect();
+        let mut exports: HashMap<&str, (&str, u32)> = HashMap::new();
+        for ((name, object), (data_base, code_base)) in self.objects.iter().zip(&bases) {
+            for symbol in object.symbols.iter().filter(|s| s.exported) {
+                let address = match symbol.section {
+                    Section::Data => data_base + symbol.offset,
+                    Section::Code => code_base + symbol.offset,
+                    Section::External => continue,
+                };
+                if let Some((first, _)) = exports.get(symbol.name.as_str()) {
+                    errors.push(LinkerError::DuplicateSymbol {
+                        name: symbol.name.clone(),
+                        first: first.to_string(),
+                        second: name.clone(),
+                    });
+                } else {
+                    exports.insert(&symbol.name, (name, address));
+                }
+            }
+        }
+
+        let mut program = elf_header(ELF_TYPE_EXEC, data_length);
+        for (_, object) in &self.objects {
+            program.extend_from_slice(&object.data);
+        }
+        for ((name, object), (data_base, code_base)) in self.objects.iter().zip(&bases) {
+            let mut code = object.code.clone();
+            for relocation in &object.relocations {
+                let symbol = match object.symbols.get(relocation.symbol as usize) {
+                    Some(symbol) => symbol,
+                    None => {
+                        errors.push(LinkerError::InvalidRelocation {
+                            object: name.clone(),
+                        });
+                        continue;
+                    }
+                };
+                let address = match symbol.section {
+                    Section::Data => data_base + symbol.offset,
+                    Section::Code => code_base + symbol.offset,
+                    Section::External => match exports.get(symbol.name.as_str()) {
+                        Some((_, address)) => *address,
+                        None => {
+                            errors.push(LinkerError::UndefinedSymbol {
+                                name: symbol.name.clone(),
+                                object: name.clone(),
+                            });
+                            continue;
+                        }
+                    },
+                };
+                let offset = relocation.offset as usize;
+                match code.get_mut(offset..offset + 2) {
+                    Some(field) => {
+                        field[0] = (address >> 8) as u8;
+                        field[1] = address as u8;
+                    }
+                    None => errors.push(LinkerError::InvalidRelocation {
+                        object: name.clone(),
+                    }),
+                }
+            }
+            program.append(&mut code);
+        }
+        if errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::{ExitReason, VM};
+
+    fn object(source: &str) -> ObjectFile {
+        Assembler::new().assemble_object(source).unwrap()
+    }
+
+    #[test]
+    fn test_link_matches_single_file() {
+        let main =
+            object(".global back\n.extern double\nload $0 #21\nload $1 @double\njmp $1\nback: hlt");
+        let lib =
+            object(".global double\n.extern back\ndouble: add $0 $0 $0\nload $1 @back\njmp $1");
+        let mut linker = Linker::new();
+        linker.add_object("main.o", main);
+        linker.add_object("lib.o", lib);
+        let linked = linker.link().unwrap();
+
+        let single = Assembler::new()
+            .assemble("load $0 #21\nload $1 @double\njmp $1\nback: hlt\ndouble: add $0 $0 $0\nload $1 @back\njmp $1")
+            .unwrap();
+        assert_eq!(linked, single);
+
+        let mut vm = VM::new();
+        vm.add_bytes(linked);
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(vm.registers[0], 42);
+    }
+
+    #[test]
+    fn test_link_data_sections() {
+        let main =
+            object(".extern greeting\nmsg: .asciiz 'a'\nload $0 @msg\nload $1 @greeting\nhlt");
+        let lib = object(".global greeting\ngreeting: .asciiz 'hello'\nhlt");
+        let mut linker = Linker::new();
+        linker.add_object("main.o", main);
+        linker.add_object("lib.o", lib);
+        let linked = linker.link().unwrap();
+        let code = PROGRAM_START + 8;
+        assert_eq!(&linked[PROGRAM_START..code], b"a\0hello\0");
+        assert_eq!(&linked[code..code + 8], &[1, 0, 0, 65, 1, 1, 0, 67]);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let mut linker = Linker::new();
+        linker.add_object("a.o", object(".global f\nf: hlt"));
+        linker.add_object("b.o", object(".global f\n.extern g\nf: load $0 @g"));
+        assert_eq!(
+            linker.link(),
+            Err(vec![
+                LinkerError::DuplicateSymbol {
+                    name: "f".to_string(),
+                    first: "a.o".to_string(),
+                    second: "b.o".to_string()
+                },
+                LinkerError::UndefinedSymbol {
+                    name: "g".to_string(),
+                    object: "b.o".to_string()
+                }
+            ])
+        );
+    }
+}
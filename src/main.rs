@@ -1,60 +1,274 @@
-use std::fs::File;
-use std::io::prelude::*;
+use std::fs;
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 #[macro_use]
 extern crate clap;
 
-extern crate nom;
+use bumbam::assembler::object_file::ObjectFile;
+use bumbam::syscall::Capabilities;
+use bumbam::{assembler, disassembler, linker, repl, vm};
+use clap::App;
+use std::fmt::Display;
 
-pub mod assembler;
-pub mod instruction;
-pub mod repl;
-pub mod vm;
+/// Prints every error and exits
+fn exit_with_errors<E: Display>(errors: Vec<E>) -> ! {
+    for error in errors {
+        println!("{}", error);
+    }
+    std::process::exit(1);
+}
 
-use clap::App;
+/// Assembles a file, printing any errors and exiting if there are some
+fn assemble_or_exit(asm: &mut assembler::Assembler, filename: &str) -> Vec<u8> {
+    match asm.assemble_file(Path::new(filename)) {
+        Ok(program) => program,
+        Err(errors) => exit_with_errors(errors),
+    }
+}
 
-/// Starts a REPL that will run until the user kills it
-fn start_repl() {
-    let mut repl = repl::REPL::new();
-    repl.run();
+/// Writes a file, exiting if that fails
+fn write_or_exit<C: AsRef<[u8]>>(path: &str, contents: C) {
+    if let Err(e) = fs::write(path, contents) {
+        println!("Unable to write {}: {}", path, e);
+        std::process::exit(1);
+    }
 }
 
-/// Attempts to read a file and return the contents. Exits if unable to read the file for any reason.
-fn read_file(tmp: &str) -> String {
-    let filename = Path::new(tmp);
-    match File::open(Path::new(&filename)) {
-        Ok(mut fh) => {
-            let mut contents = String::new();
-            match fh.read_to_string(&mut contents) {
-                Ok(_) => contents,
-                Err(e) => {
-                    println!("There was an error reading file: {:?}", e);
-                    std::process::exit(1);
-                }
+/// Writes the bytecode of an assembly file to `output`, as an object file if `object` is set,
+/// and its listing to `listing` if given
+fn assemble(input: &str, output: &str, listing: Option<&str>, object: bool) {
+    let mut asm = assembler::Assembler::new();
+    if object {
+        match asm.assemble_object_file(Path::new(input)) {
+            Ok(object) => write_or_exit(output, object.to_bytes()),
+            Err(errors) => exit_with_errors(errors),
+        }
+    } else {
+        let program = assemble_or_exit(&mut asm, input);
+        write_or_exit(output, program);
+    }
+    if let (Some(path), Some(listing)) = (listing, &asm.listing) {
+        write_or_exit(path, listing.to_string());
+    }
+}
+
+/// Reads a file, exiting if that fails
+fn read_or_exit(path: &str) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            println!("Unable to read {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Reads a module to load, assembling it first if it is a source file
+fn module_or_exit(path: &str) -> Vec<u8> {
+    if !path.ends_with(".iasm") {
+        return read_or_exit(path);
+    }
+    let mut asm = assembler::Assembler::new();
+    match asm.assemble_object_file(Path::new(path)) {
+        Ok(object) => object.to_bytes(),
+        Err(errors) => exit_with_errors(errors),
+    }
+}
+
+/// Reads a program to run, assembling it first unless it already is bytecode
+fn program_or_exit(asm: &mut assembler::Assembler, path: &str) -> Vec<u8> {
+    let contents = read_or_exit(path);
+    if contents.starts_with(&assembler::ELF_HEADER_PREFIX) {
+        return contents;
+    }
+    assemble_or_exit(asm, path)
+}
+
+/// Links object files into an executable written to `output`
+fn link<'a>(inputs: impl Iterator<Item = &'a str>, output: &str) {
+    let mut linker = linker::Linker::new();
+    for input in inputs {
+        match ObjectFile::from_bytes(&read_or_exit(input)) {
+            Some(object) => linker.add_object(input, object),
+            None => {
+                println!("{} is not an object file", input);
+                std::process::exit(1);
             }
         }
+    }
+    match linker.link() {
+        Ok(program) => write_or_exit(output, program),
+        Err(errors) => exit_with_errors(errors),
+    }
+}
+
+/// Prints the assembly source of a bytecode file
+fn disassemble(input: &str) {
+    let program = read_or_exit(input);
+    match disassembler::disassemble(&program) {
+        Ok(source) => print!("{}", source),
         Err(e) => {
-            println!("File not found: {:?}", e);
-            std::process::exit(1)
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parses the value of a numeric flag, exiting if it isn't a number
+fn number_or_exit<T: std::str::FromStr>(matches: &clap::ArgMatches, arg: &str) -> Option<T> {
+    let value = matches.value_of(arg)?;
+    match value.parse() {
+        Ok(number) => Some(number),
+        Err(_) => {
+            println!("{} is not a number", value);
+            std::process::exit(1);
         }
     }
 }
 
+/// Reads the limits of the VM from the command line flags
+fn limits(matches: &clap::ArgMatches) -> vm::VmLimits {
+    vm::VmLimits {
+        max_instructions: number_or_exit(matches, "MAX_INSTRUCTIONS"),
+        max_heap_bytes: number_or_exit(matches, "MAX_HEAP"),
+        max_stack_depth: number_or_exit(matches, "MAX_STACK_DEPTH"),
+        max_processes: number_or_exit(matches, "MAX_PROCESSES"),
+        max_duration: number_or_exit(matches, "TIMEOUT").map(Duration::from_millis),
+    }
+}
+
+/// What programs run from the command line may do. Files are only allowed with
+/// `--allow-files`.
+fn capabilities(matches: &clap::ArgMatches) -> Capabilities {
+    if matches.is_present("ALLOW_FILES") {
+        Capabilities::all()
+    } else {
+        Capabilities::sandboxed()
+    }
+}
+
+/// How many instructions a program served to REPLs runs before they can look at it
+const RUN_SLICE: u64 = 1024;
+
+/// Serves REPLs attached to `vm` on the addresses given on the command line, returning false
+/// if there are none
+fn listen(matches: &clap::ArgMatches, vm: &vm::SharedVm) -> bool {
+    let mut listening = false;
+    if let Some(address) = matches.value_of("LISTEN_TCP") {
+        let listener = TcpListener::bind(address).unwrap_or_else(|e| {
+            println!("Unable to listen on {}: {}", address, e);
+            std::process::exit(1);
+        });
+        let vm = Arc::clone(vm);
+        let address = address.to_string();
+        // The program is already running, and goes on without REPLs
+        thread::spawn(move || {
+            if let Err(e) = repl::server::serve_tcp(listener, vm) {
+                println!("Unable to serve the REPL on {}: {}", address, e);
+            }
+        });
+        listening = true;
+    }
+    if let Some(path) = matches.value_of("LISTEN_UNIX") {
+        listening |= listen_unix(path, vm);
+    }
+    listening
+}
+
+#[cfg(unix)]
+fn listen_unix(path: &str, vm: &vm::SharedVm) -> bool {
+    let listener = UnixListener::bind(path).unwrap_or_else(|e| {
+        println!("Unable to listen on {}: {}", path, e);
+        std::process::exit(1);
+    });
+    let vm = Arc::clone(vm);
+    let path = path.to_string();
+    thread::spawn(move || {
+        if let Err(e) = repl::server::serve_unix(listener, vm) {
+            println!("Unable to serve the REPL on {}: {}", path, e);
+        }
+    });
+    true
+}
+
+#[cfg(not(unix))]
+fn listen_unix(_path: &str, _vm: &vm::SharedVm) -> bool {
+    println!("Unix sockets are not available on this platform");
+    std::process::exit(1);
+}
+
+/// The REPL keeps its history in the home directory, when there is one
+const HISTORY_FILE: &str = ".bumbam_history";
+
+/// Starts a REPL that will run until the user kills it
+fn start_repl() {
+    let mut repl = repl::REPL::new();
+    if let Some(home) = std::env::var_os("HOME") {
+        repl.set_history_file(Path::new(&home).join(HISTORY_FILE));
+    }
+    repl.run();
+}
+
+#[allow(deprecated)]
 fn main() {
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
+    if let Some(matches) = matches.subcommand_matches("assemble") {
+        assemble(
+            matches.value_of("INPUT_FILE").unwrap(),
+            matches.value_of("OUTPUT_FILE").unwrap(),
+            matches.value_of("LISTING_FILE"),
+            matches.is_present("OBJECT"),
+        );
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("link") {
+        link(
+            matches.values_of("OBJECT_FILES").unwrap(),
+            matches.value_of("OUTPUT_FILE").unwrap(),
+        );
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("disassemble") {
+        disassemble(matches.value_of("INPUT_FILE").unwrap());
+        return;
+    }
     let target_file = matches.value_of("INPUT_FILE");
     match target_file {
         Some(filename) => {
-            let program = read_file(filename);
-            let mut asm = assembler::Assembler::new();
             let mut vm = vm::VM::new();
-            let program = asm.assemble(&program);
-            if let Some(p) = program {
-                vm.add_bytes(p);
-                vm.run();
-                std::process::exit(0);
+            let mut asm = assembler::Assembler::new();
+            let program = program_or_exit(&mut asm, filename);
+            let modules = matches.values_of("MODULES").into_iter().flatten();
+            for image in std::iter::once(program).chain(modules.map(module_or_exit)) {
+                if let Err(e) = vm.load_module(&image) {
+                    println!("Unable to load module: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            vm.set_limits(limits(&matches));
+            vm.set_capabilities(capabilities(&matches));
+            let vm = Arc::new(Mutex::new(vm));
+            let result = if listen(&matches, &vm) {
+                vm::run_shared(&vm, RUN_SLICE)
+            } else {
+                vm::lock(&vm).run()
+            };
+            match result {
+                Ok(reason) => {
+                    println!("{}", reason);
+                    std::process::exit(if reason.is_success() { 0 } else { 1 });
+                }
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
             }
         }
         None => {
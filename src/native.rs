@@ -0,0 +1,94 @@
+use std::fmt;
+
+/// A function of the host application. It gets the values of registers `$0` to `$arity - 1`
+/// and its result is put in `$0`, while an error stops the program.
+pub type NativeFunction = Box<dyn FnMut(&[i32]) -> Result<i32, String> + Send>;
+
+struct RegisteredNative {
+    name: String,
+    arity: u8,
+    function: NativeFunction,
+}
+
+/// The functions of the host that bytecode can call with `CALLN`, once declared with `.native`.
+/// They are bound by name when a program or module is loaded.
+#[derive(Default)]
+pub struct NativeRegistry {
+    natives: Vec<RegisteredNative>,
+}
+
+impl fmt::Debug for NativeRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.natives
+                    .iter()
+                    .map(|n| format!("{}/{}", n.name, n.arity)),
+            )
+            .finish()
+    }
+}
+
+impl NativeRegistry {
+    pub fn new() -> NativeRegistry {
+        NativeRegistry::default()
+    }
+
+    /// Registers a function, replacing any registered under the same name
+    pub fn register<F>(&mut self, name: &str, arity: u8, function: F)
+    where
+        F: FnMut(&[i32]) -> Result<i32, String> + Send + 'static,
+    {
+        let native = RegisteredNative {
+            name: name.to_string(),
+            arity,
+            function: Box::new(function),
+        };
+        match self.index_of(name) {
+            Some(index) => self.natives[index] = native,
+            None => self.natives.push(native),
+        }
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.natives.iter().position(|n| n.name == name)
+    }
+
+    pub fn arity(&self, index: usize) -> Option<u8> {
+        self.natives.get(index).map(|n| n.arity)
+    }
+
+    pub fn name(&self, index: usize) -> Option<&str> {
+        self.natives.get(index).map(|n| n.name.as_str())
+    }
+
+    /// Calls the function at `index` with as many `args` as its arity
+    pub fn call(&mut self, index: usize, args: &[i32]) -> Option<Result<i32, String>> {
+        let native = self.natives.get_mut(index)?;
+        Some((native.function)(
+            &args[..usize::from(native.arity).min(args.len())],
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let mut registry = NativeRegistry::new();
+        registry.register("add", 2, |args| Ok(args[0] + args[1]));
+        registry.register("fail", 0, |_| Err("no".to_string()));
+        assert_eq!(registry.index_of("fail"), Some(1));
+        assert_eq!(registry.arity(0), Some(2));
+        assert_eq!(registry.call(0, &[1, 2, 3]), Some(Ok(3)));
+        assert_eq!(registry.call(1, &[]), Some(Err("no".to_string())));
+        assert_eq!(registry.call(2, &[]), None);
+
+        registry.register("add", 1, |args| Ok(args[0] + 1));
+        assert_eq!(registry.index_of("add"), Some(0));
+        assert_eq!(registry.call(0, &[1, 2]), Some(Ok(2)));
+        assert_eq!(format!("{:?}", registry), r#"["add/1", "fail/0"]"#);
+    }
+}
@@ -1,131 +1,605 @@
-use crate::assembler::program_parsers::program;
-use crate::assembler::Assembler;
-use crate::vm::VM;
+use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::object_file::{ModuleTable, ObjectFile, Section};
+use crate::assembler::{elf_header, Assembler, ELF_TYPE_EXEC, PROGRAM_START};
+use crate::debugger::{Debugger, Stop};
+use crate::disassembler::{disassemble, DecodedInstruction};
+use crate::repl::command_parser::{parse_number, Command, COMMANDS};
+use crate::repl::display::{hexdump, vm_state};
+use crate::syscall::Capabilities;
+use crate::verifier::verify_code;
+use crate::vm::{lock, SharedVm, VmBuilder, VM};
 use core::num::ParseIntError;
 use std;
-use std::fs::File;
+use std::fs::{self, OpenOptions};
 use std::io;
-use std::io::Read;
-use std::io::Write;
-use std::path::Path;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub mod command_parser;
+pub mod display;
+pub mod server;
+
+/// How many instructions the REPL can undo, until changed with `.journal`
+const JOURNAL_SIZE: usize = 10_000;
+/// How many instructions typed code may execute before the REPL takes back control
+const MAX_APPENDED_INSTRUCTIONS: usize = 1_000_000;
+/// Starts a line of bytecode in hexadecimal, when not in hex mode
+const HEX_PREFIX: char = '!';
+/// The answer to attached sessions trying to change the VM
+const READ_ONLY: &str = "An attached session can only look at the VM, not change it";
+
+/// Writes a line to whoever uses the REPL. The line is formatted first, since it often
+/// borrows the REPL itself. There is nothing to do if they went away.
+macro_rules! say {
+    ($repl:expr, $($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        let _ = writeln!($repl.output, "{}", line);
+    }};
+}
 
 /// Core structure for the REPL for the Assembler
 pub struct REPL {
     command_buffer: Vec<String>,
-    // The VM the REPL will use to execute code
-    vm: VM,
+    /// Where lines are kept across sessions, if anywhere
+    history_file: Option<PathBuf>,
+    // The VM the REPL will use to execute code, which the host may be running
+    vm: SharedVm,
+    /// Its symbols are those of the last program loaded, and the labels typed since
     asm: Assembler,
+    debugger: Debugger,
+    /// Whether lines are bytecode in hexadecimal rather than assembly
+    hex_mode: bool,
+    /// The lines of the block being typed, if any
+    block: Option<Vec<String>>,
+    /// Whether the block is a macro typed at the prompt, which its `.endm` ends
+    macro_block: bool,
+    /// Where the answers go
+    output: Box<dyn Write + Send>,
+    /// Set by `.quit`, which ends `run`
+    quit: bool,
+    /// Whether the VM is run by a host, which the session may look at but not change. Files
+    /// named by the user may then not be read nor written with the privileges of the host.
+    attached: bool,
+}
+
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl REPL {
-    /// Creates and returns a new assembly REPL
+    /// Creates and returns a new assembly REPL, with a VM of its own
     pub fn new() -> REPL {
+        let vm = VmBuilder::new()
+            .capabilities(Capabilities::sandboxed())
+            .journal(JOURNAL_SIZE)
+            .build();
+        let mut repl = REPL::attach(Arc::new(Mutex::new(vm)), io::stdout());
+        repl.attached = false;
+        let vm = Arc::clone(&repl.vm);
+        repl.clear_program(&mut lock(&vm));
+        repl
+    }
+
+    /// Creates a REPL for a VM the host is running, answering on `output`. The program of the
+    /// VM is left as it is, and its labels aren't known. The session can only look at the VM:
+    /// typed code and the commands that change the VM or run it are refused, so the program
+    /// of the host ends as it would have without it. Whoever uses it may not be trusted with
+    /// the files of the host either, so the commands naming files are refused.
+    pub fn attach<W: Write + Send + 'static>(vm: SharedVm, output: W) -> REPL {
         REPL {
-            vm: VM::new(),
+            vm,
             asm: Assembler::new(),
+            debugger: Debugger::new(),
+            hex_mode: false,
+            block: None,
+            macro_block: false,
             command_buffer: vec![],
+            history_file: None,
+            output: Box::new(output),
+            quit: false,
+            attached: true,
         }
     }
+
+    /// Keeps the history in a file, starting with the lines it already has
+    pub fn set_history_file<P: Into<PathBuf>>(&mut self, path: P) {
+        let path = path.into();
+        if let Ok(history) = fs::read_to_string(&path) {
+            self.command_buffer = history.lines().map(str::to_string).collect();
+        }
+        self.history_file = Some(path);
+    }
+
+    /// Reads lines from stdin until `.quit` or the end of the input
     pub fn run(&mut self) {
-        println!("Welcome to BumBam <3");
-        loop {
-            // This allocates a new String in which to store whatever the user types each iteration.
-            // TODO: Figure out how create this outside of the loop and re-use it every iteration
-            let mut buffer = String::new();
-
-            // Blocking call until the user types in a command
-            let stdin = io::stdin();
-
-            // Annoyingly, `print!` does not automatically flush stdout like `println!` does, so we
-            // have to do that there for the user to see our `>>> ` prompt.
-            print!(">>> ");
-            io::stdout().flush().expect("Unable to flush stdout");
-
-            // Here we'll look at the string the user gave us.
-            stdin
-                .read_line(&mut buffer)
-                .expect("Unable to read line from user");
-            let buffer = buffer.trim();
-
-            match buffer {
-                "quit" => {
-                    println!("ByeBumBam!");
-                    std::process::exit(0);
-                }
-                "history" => {
-                    for command in &self.command_buffer {
-                        println!("{}", command);
+        let stdin = io::stdin();
+        self.run_with(stdin.lock());
+    }
+
+    /// Reads lines from `input` until `.quit` or the end of the input, answering on the output
+    /// of the REPL
+    pub fn run_with<R: BufRead>(&mut self, mut input: R) {
+        say!(self, "Welcome to BumBam <3, type .help for the commands");
+        let mut buffer = String::new();
+        while !self.quit {
+            // `write!` does not flush like `writeln!` may, so we have to do that there for the
+            // user to see our `>>> ` prompt.
+            let prompt = if self.block.is_some() {
+                "... "
+            } else if self.hex_mode {
+                "hex> "
+            } else {
+                ">>> "
+            };
+            let prompted = write!(self.output, "{}", prompt).and_then(|()| self.output.flush());
+            buffer.clear();
+            // Blocking call until the user types in a command, nothing left means they are gone
+            match input.read_line(&mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(_) if prompted.is_err() => return,
+                Ok(_) => self.handle_line(buffer.trim()),
+            }
+        }
+    }
+
+    /// Executes a line typed by the user. The VM is locked until it is done, so that the host
+    /// doesn't run it meanwhile.
+    fn handle_line(&mut self, line: &str) {
+        let mut command = Command::parse(line);
+        let first_word = line.split_whitespace().next();
+        // A macro typed at the prompt is a block of its own
+        if self.block.is_none() && first_word == Some(".macro") {
+            self.block = Some(vec![line.to_string()]);
+            self.macro_block = true;
+            return self.add_to_history(line);
+        }
+        if let Some(block) = &mut self.block {
+            // Directives start like commands, so only the ones ending the block are commands
+            if !matches!(command, Some(Ok(Command::End)) | Some(Ok(Command::Cancel))) {
+                block.push(line.to_string());
+                if !(self.macro_block && first_word == Some(".endm")) {
+                    return self.add_to_history(line);
+                }
+                command = Some(Ok(Command::End));
+            }
+        }
+        let shared = Arc::clone(&self.vm);
+        let vm = &mut lock(&shared);
+        match command {
+            Some(Ok(command)) if command.uses_files() && self.attached => {
+                say!(self, "Files can't be used from an attached session");
+            }
+            Some(Ok(command)) if command.changes_vm() && self.attached => {
+                say!(self, "{}", READ_ONLY)
+            }
+            Some(Ok(command)) => self.execute_command(vm, command),
+            Some(Err(e)) => say!(self, "{}", e),
+            None if self.hex_mode || line.starts_with(HEX_PREFIX) => {
+                self.execute_hex(vm, line.trim_start_matches(HEX_PREFIX));
+            }
+            None => self.execute_assembly(vm, line),
+        }
+        // This is the line we add to store a copy of each command
+        self.add_to_history(line);
+    }
+
+    fn execute_command(&mut self, vm: &mut VM, command: Command) {
+        match &command {
+            Command::Help => {
+                for help in COMMANDS {
+                    let usage = format!("{} {}", help.name, help.usage);
+                    say!(self, "{:<24} {}", usage, help.about);
+                }
+            }
+            Command::Quit => {
+                say!(self, "ByeBumBam!");
+                self.quit = true;
+            }
+            Command::History => {
+                for command in &self.command_buffer {
+                    say!(self, "{}", command);
+                }
+            }
+            Command::Program => {
+                say!(
+                    self,
+                    "Listing instructions currently in VM's program vector:"
+                );
+                for instruction in vm.program() {
+                    say!(self, "{}", instruction);
+                }
+                say!(self, "End of Program Listing");
+            }
+            Command::Hex => {
+                self.hex_mode = !self.hex_mode;
+                if self.hex_mode {
+                    say!(
+                        self,
+                        "Type bytecode in hexadecimal, .hex to go back to assembly"
+                    );
+                }
+            }
+            Command::Registers => {
+                for line in vm_state(vm) {
+                    say!(self, "{}", line);
+                }
+            }
+            Command::Heap { offset, length } => {
+                let heap = vm.heap();
+                match heap.get(*offset..offset.saturating_add(*length).min(heap.len())) {
+                    Some([]) if heap.is_empty() => say!(self, "The heap is empty"),
+                    Some([]) => say!(self, "The range is empty"),
+                    Some(bytes) => {
+                        for line in hexdump(bytes, *offset) {
+                            say!(self, "{}", line);
+                        }
                     }
+                    None => say!(self, "The heap is only {} bytes long", heap.len()),
                 }
-                "program" => {
-                    println!("Listing instructions currently in VM's program vector:");
-                    for instruction in &self.vm.program {
-                        println!("{}", instruction);
+            }
+            Command::Set { register, value } => {
+                vm.set_register(*register, *value);
+            }
+            Command::Load { path } => {
+                let mut asm = Assembler::new();
+                let program = match asm.assemble_file(Path::new(path)) {
+                    Ok(program) => program,
+                    Err(errors) => return self.print_errors(errors),
+                };
+                match vm.load(&program) {
+                    Ok(()) => {
+                        let entry_point = vm.entry_point();
+                        vm.set_pc(entry_point);
+                        self.asm = asm;
+                        say!(self, "Loaded {}", path);
                     }
-                    println!("End of Program Listing");
-                }
-                "registers" => {
-                    println!("Listing registers and all contents:");
-                    println!("{:#?}", self.vm);
-                    println!("End of Register Listing")
-                }
-                "load_file" => {
-                    print!("Please enter the path to the file you wish to load: ");
-                    io::stdout().flush().expect("Unable to flush stdout");
-                    let mut tmp = String::new();
-                    stdin
-                        .read_line(&mut tmp)
-                        .expect("Unable to read line from user");
-                    let tmp = tmp.trim();
-                    let filename = Path::new(&tmp);
-                    let mut f = File::open(Path::new(&filename)).expect("File not found");
-                    let mut contents = String::new();
-                    f.read_to_string(&mut contents)
-                        .expect("There was an error reading from the file");
-                    let program = match program(&contents) {
-                        // Rusts pattern matching is pretty powerful an can even be nested
-                        Ok((_, program)) => program,
-                        Err(e) => {
-                            println!("Unable to parse input: {:?}", e);
-                            continue;
-                        }
-                    };
-                    self.vm
-                        .program
-                        .append(&mut program.to_bytes(&self.asm.symbols));
-                }
-                "clear_program" => {
-                    println!("Clearing the program vector...");
-                    self.vm.program.clear();
-                    println!("Done.");
-                }
-                _ => {
-                    // You can assign the result of a match to a variable
-                    // Rust can convert types using `Into` and `From`
-                    let program = match program(buffer) {
-                        // Rusts pattern matching is pretty powerful an can even be nested
-                        Ok((_, program)) => program,
-                        Err(_) => {
-                            println!("Unable to parse input");
-                            continue;
-                        }
-                    };
-                    // The `program` is `pub` anyways so you can just `append` to the `Vec`
-                    self.vm
-                        .program
-                        .append(&mut program.to_bytes(&self.asm.symbols));
-                    self.vm.run_once();
+                    Err(e) => {
+                        say!(self, "Unable to load {}: {}", path, e);
+                        self.clear_program(vm);
+                    }
+                }
+            }
+            Command::Append { path } => {
+                let object = match Assembler::new().assemble_object_file(Path::new(path)) {
+                    Ok(object) => object,
+                    Err(errors) => return self.print_errors(errors),
+                };
+                match appended_code(&object, vm.program().len()) {
+                    Ok(code) => {
+                        say!(self, "Appended {} bytes of code", code.len());
+                        vm.add_bytes(code);
+                    }
+                    Err(e) => say!(self, "Unable to append {}: {}", path, e),
+                }
+            }
+            Command::Run => {
+                let stop = self.debugger.run(vm);
+                self.show_stop(vm, stop);
+            }
+            Command::Break { location: None } => {
+                for address in self.debugger.breakpoints() {
+                    say!(self, "{}", self.describe_address(address));
+                }
+            }
+            Command::Break {
+                location: Some(location),
+            } => match self.address_of(location) {
+                Some(address) => {
+                    self.debugger.add_breakpoint(address);
+                    say!(self, "Breakpoint at {}", self.describe_address(address));
+                }
+                None => say!(self, "{} is neither an address nor a label", location),
+            },
+            Command::Delete { location } => {
+                let removed = self
+                    .address_of(location)
+                    .is_some_and(|address| self.debugger.remove_breakpoint(address));
+                if !removed {
+                    say!(self, "There is no breakpoint at {}", location);
+                }
+            }
+            Command::Watch { watch: None } => {
+                for (number, watch) in self.debugger.watchpoints().enumerate() {
+                    say!(self, "{}: {}", number, watch);
+                }
+            }
+            Command::Watch { watch: Some(watch) } => {
+                let number = self.debugger.add_watchpoint(watch.clone(), vm);
+                say!(self, "Watchpoint {}: {}", number, watch);
+            }
+            Command::Unwatch { number } => {
+                if !self.debugger.remove_watchpoint(*number) {
+                    say!(self, "There is no watchpoint {}", number);
+                }
+            }
+            Command::Step => {
+                let stop = self.debugger.step(vm);
+                self.show_stop(vm, stop);
+            }
+            Command::Next => {
+                let stop = self.debugger.next(vm);
+                self.show_stop(vm, stop);
+            }
+            Command::Continue => {
+                let stop = self.debugger.resume(vm);
+                self.show_stop(vm, stop);
+            }
+            Command::List => self.show_listing(vm),
+            Command::ReverseStep => {
+                let stop = self.debugger.reverse_step(vm);
+                self.show_stop(vm, stop);
+            }
+            Command::ReverseContinue => {
+                let stop = self.debugger.reverse_continue(vm);
+                self.show_stop(vm, stop);
+            }
+            Command::Journal { size: None } => match vm.journal_capacity() {
+                Some(capacity) => say!(
+                    self,
+                    "{} of the last {} instructions can be undone",
+                    vm.journal_length(),
+                    capacity
+                ),
+                None => say!(self, "The journal is off"),
+            },
+            Command::Journal { size: Some(0) } => vm.set_journal(None),
+            Command::Journal { size } => vm.set_journal(*size),
+            Command::LoadModule { path } | Command::UpgradeModule { path } => {
+                let image = match self.read_module(path) {
+                    Some(image) => image,
+                    None => return,
+                };
+                let loaded = if let Command::LoadModule { .. } = command {
+                    vm.load_module(&image)
+                } else {
+                    vm.upgrade_module(&image)
+                };
+                if let Err(e) = loaded {
+                    say!(self, "Unable to load module: {}", e);
+                }
+            }
+            Command::Purge { module } => match vm.purge_module(module) {
+                Ok(true) => say!(self, "Purged, the code running the old version was stopped"),
+                Ok(false) => say!(self, "Purged"),
+                Err(e) => say!(self, "{}", e),
+            },
+            Command::Save { path } => {
+                if !vm.modules().is_empty() {
+                    return say!(self, "Programs with modules loaded can't be saved");
+                }
+                let source = match disassemble(vm.program()) {
+                    Ok(source) => source,
+                    Err(e) => return say!(self, "Unable to disassemble the program: {}", e),
+                };
+                match fs::write(path, source) {
+                    Ok(()) => say!(self, "Saved the program to {}", path),
+                    Err(e) => say!(self, "Unable to write {}: {}", path, e),
+                }
+            }
+            Command::Snapshot { path } => match fs::write(path, vm.snapshot()) {
+                Ok(()) => say!(self, "Saved the state of the VM to {}", path),
+                Err(e) => say!(self, "Unable to write {}: {}", path, e),
+            },
+            Command::Restore { path } => {
+                let snapshot = match fs::read(path) {
+                    Ok(snapshot) => snapshot,
+                    Err(e) => return say!(self, "Unable to read {}: {}", path, e),
+                };
+                match vm.restore(&snapshot) {
+                    Ok(()) => {
+                        // The labels were those of a program that is gone
+                        self.asm = Assembler::new();
+                        say!(self, "Restored the state of the VM from {}", path);
+                    }
+                    Err(e) => say!(self, "Unable to restore {}: {}", path, e),
+                }
+            }
+            Command::Block => {
+                self.block = Some(vec![]);
+                say!(
+                    self,
+                    "Type the lines of the block, then .end to run them or .cancel"
+                );
+            }
+            Command::End => {
+                self.macro_block = false;
+                match self.block.take() {
+                    Some(lines) => self.execute_assembly(vm, &lines.join("\n")),
+                    None => say!(self, "There is no block to end, .block starts one"),
+                }
+            }
+            Command::Cancel => {
+                self.macro_block = false;
+                if self.block.take().is_none() {
+                    say!(self, "There is no block to cancel");
+                }
+            }
+            Command::Labels => {
+                for (name, address) in self.asm.symbols.labels() {
+                    say!(self, "{:#06x}  {}", address, name);
+                }
+            }
+            Command::ClearProgram => {
+                say!(self, "Clearing the program vector...");
+                self.clear_program(vm);
+                say!(self, "Done.");
+            }
+        }
+    }
+
+    /// Prints why the debugger stopped and, if the program can go on, where it is
+    fn show_stop(&mut self, vm: &VM, stop: Stop) {
+        if stop != Stop::Stepped {
+            say!(self, "{}", stop);
+        }
+        if !matches!(stop, Stop::Exited(_) | Stop::Failed(_)) {
+            self.show_listing(vm);
+        }
+    }
+
+    fn show_listing(&mut self, vm: &VM) {
+        for line in self.debugger.listing(vm, &self.asm.symbols, 2) {
+            say!(self, "{}", line);
+        }
+    }
+
+    /// Reads an address, or the name of a label of the program
+    fn address_of(&self, location: &str) -> Option<usize> {
+        parse_number(location).or_else(|| {
+            self.asm
+                .symbols
+                .label_value(location)
+                .map(|address| address as usize)
+        })
+    }
+
+    fn describe_address(&self, address: usize) -> String {
+        match self.asm.symbols.label_at(address as u32) {
+            Some(label) => format!("{:#06x} ({})", address, label),
+            None => format!("{:#06x}", address),
+        }
+    }
+
+    /// Starts over with an executable without data or code, which typed instructions are
+    /// appended to
+    fn clear_program(&mut self, vm: &mut VM) {
+        vm.load(&elf_header(ELF_TYPE_EXEC, 0))
+            .expect("An empty program is always valid");
+        self.asm = Assembler::new();
+        let entry_point = vm.entry_point();
+        vm.set_pc(entry_point);
+    }
+
+    /// Records a line, also in the history file if there is one. The file is no longer
+    /// written once it fails.
+    fn add_to_history(&mut self, line: &str) {
+        if line.is_empty() {
+            return;
+        }
+        self.command_buffer.push(line.to_string());
+        if let Some(path) = &self.history_file {
+            let written = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+            if let Err(e) = written {
+                say!(
+                    self,
+                    "Unable to write the history to {}: {}",
+                    path.display(),
+                    e
+                );
+                self.history_file = None;
+            }
+        }
+    }
+
+    fn print_errors<E: std::fmt::Display>(&mut self, errors: Vec<E>) {
+        for error in errors {
+            say!(self, "{}", error);
+        }
+    }
+
+    /// Reads a module, assembling it first if it is a source file. Errors are printed.
+    fn read_module(&mut self, path: &str) -> Option<Vec<u8>> {
+        if path.ends_with(".iasm") {
+            return match Assembler::new().assemble_object_file(Path::new(path)) {
+                Ok(object) => Some(object.to_bytes()),
+                Err(errors) => {
+                    self.print_errors(errors);
+                    None
+                }
+            };
+        }
+        match std::fs::read(path) {
+            Ok(image) => Some(image),
+            Err(e) => {
+                say!(self, "Unable to read {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Assembles code after the program and executes it. It can use the labels defined so far,
+    /// and the labels it defines can be used by the code typed next.
+    fn execute_assembly(&mut self, vm: &mut VM, source: &str) {
+        if self.attached {
+            return say!(self, "{}", READ_ONLY);
+        }
+        let address = vm.program().len();
+        match self.asm.assemble_appended(source, address) {
+            Ok(code) => self.run_appended(vm, code),
+            Err(errors) => {
+                let unknown = errors
+                    .iter()
+                    .any(|e| matches!(e, AssemblerError::UnknownDirective { .. }));
+                self.print_errors(errors);
+                if unknown {
+                    say!(self, "Type .help for the commands");
                 }
             }
+        }
+    }
 
-            // This is the line we add to store a copy of each command
-            self.command_buffer.push(buffer.to_string());
+    /// Appends instructions to the program and executes them until they run past its end, as
+    /// they may jump to code typed before. Loops that don't end are stopped after a while.
+    fn run_appended(&mut self, vm: &mut VM, bytes: Vec<u8>) {
+        vm.set_pc(vm.program().len());
+        vm.add_bytes(bytes);
+        for _ in 0..MAX_APPENDED_INSTRUCTIONS {
+            if vm.pc() >= vm.program().len() {
+                return;
+            }
+            match vm.run_once() {
+                Ok(Some(reason)) => return say!(self, "{}", reason),
+                Ok(None) => {}
+                Err(e) => return say!(self, "{}", e),
+            }
         }
+        say!(
+            self,
+            "Stopped after {} instructions, .continue goes on",
+            MAX_APPENDED_INSTRUCTIONS
+        );
     }
+
+    /// Executes instructions typed as bytes, after showing what they disassemble to. They go
+    /// through the verifier first, since the VM trusts the operands of verified code.
+    fn execute_hex(&mut self, vm: &mut VM, input: &str) {
+        if self.attached {
+            return say!(self, "{}", READ_ONLY);
+        }
+        let bytes = match self.parse_hex(input) {
+            Ok(bytes) => bytes,
+            Err(e) => return say!(self, "Unable to parse hexadecimal bytes: {}", e),
+        };
+        if !bytes.len().is_multiple_of(4) {
+            return say!(
+                self,
+                "Instructions are 4 bytes long, got {} bytes",
+                bytes.len()
+            );
+        }
+        let address = vm.program().len();
+        if let Err(e) = verify_code(&bytes, address, &ModuleTable::default()) {
+            return say!(self, "{}", e);
+        }
+        for (n, chunk) in bytes.chunks(4).enumerate() {
+            let decoded = DecodedInstruction::decode(&[chunk[0], chunk[1], chunk[2], chunk[3]]);
+            let text = decoded.to_assembly(address + n * 4, |target| format!("{:#06x}", target));
+            say!(self, "{:#06x}  {}", address + n * 4, text);
+        }
+        self.run_appended(vm, bytes);
+    }
+
     /// Accepts a hexadecimal string WITHOUT a leading `0x` and returns a Vec of u8
     /// Example for a LOAD command: 00 01 03 E8
-    fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, ParseIntError> {
-        let split = i.split(' ').collect::<Vec<&str>>();
+    fn parse_hex(&self, i: &str) -> Result<Vec<u8>, ParseIntError> {
+        let split = i.split_whitespace().collect::<Vec<&str>>();
         let mut results: Vec<u8> = vec![];
         for hex_string in split {
             let byte = u8::from_str_radix(hex_string, 16);
@@ -141,3 +615,210 @@ impl REPL {
         Ok(results)
     }
 }
+
+/// The code of an object file, relocated to run at the end of a program `program_length`
+/// bytes long. The code can't bring data with it: data goes before the code of a program, where
+/// it would move every address that was already assembled.
+fn appended_code(object: &ObjectFile, program_length: usize) -> Result<Vec<u8>, String> {
+    if !object.data.is_empty() {
+        return Err("only code can be appended, use .load for files with data".to_string());
+    }
+    if object.module.is_some() {
+        return Err("modules are loaded with .load_module".to_string());
+    }
+    if let Some(symbol) = object
+        .symbols
+        .iter()
+        .find(|s| s.section == Section::External)
+    {
+        return Err(format!("'{}' is .extern", symbol.name));
+    }
+    // The code was assembled to start right after the header
+    let shift = program_length - PROGRAM_START;
+    let mut code = object.code.clone();
+    for relocation in &object.relocations {
+        let field = relocation.offset as usize;
+        let address = (usize::from(code[field]) << 8 | usize::from(code[field + 1])) + shift;
+        if address > usize::from(u16::MAX) {
+            return Err("the program is too long".to_string());
+        }
+        code[field] = (address >> 8) as u8;
+        code[field + 1] = address as u8;
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::ExitReason;
+
+    #[test]
+    fn test_appended_code() {
+        let mut vm = VM::new();
+        vm.load(&Assembler::new().assemble("load $0 #1\nhlt").unwrap())
+            .unwrap();
+        let object = Assembler::new()
+            .assemble_object("top: inc $0\nload $1 @top\nhlt")
+            .unwrap();
+        let start = vm.program().len();
+        vm.add_bytes(appended_code(&object, start).unwrap());
+        vm.set_pc(start);
+        for _ in 0..2 {
+            assert_eq!(vm.run_once(), Ok(None));
+        }
+        assert_eq!(vm.register(0), Some(1));
+        assert_eq!(vm.register(1), Some(start as i32));
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+
+        let data = Assembler::new()
+            .assemble_object("msg: .asciiz 'hi'\nhlt")
+            .unwrap();
+        assert!(appended_code(&data, start).is_err());
+    }
+
+    #[test]
+    fn test_execute_hex() {
+        let mut repl = REPL::new();
+        assert_eq!(
+            repl.parse_hex("01 00  03 E8"),
+            Ok(vec![0x01, 0x00, 0x03, 0xe8])
+        );
+        assert!(repl.parse_hex("01 0g").is_err());
+        repl.handle_line("!01 00 03 E8 12 00 00 00");
+        assert_eq!(lock(&repl.vm).register(0), Some(1001));
+        // Register 40 doesn't exist, so the verifier refuses it
+        repl.handle_line("!01 28 00 01");
+        assert_eq!(lock(&repl.vm).program().len(), PROGRAM_START + 8);
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("bumbam_repl_{}_{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_history_file() {
+        let path = temp_path("history");
+        fs::write(&path, "load $0 #1\n.registers\n").unwrap();
+        let mut repl = REPL::new();
+        repl.set_history_file(&path);
+        repl.add_to_history(".heap 0 4");
+        repl.add_to_history("");
+        assert_eq!(
+            repl.command_buffer,
+            vec!["load $0 #1", ".registers", ".heap 0 4"]
+        );
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "load $0 #1\n.registers\n.heap 0 4\n"
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_save_and_restore() {
+        let mut repl = REPL::new();
+        repl.handle_line(".block");
+        repl.handle_line("load $0 #20");
+        repl.handle_line("load $1 #22");
+        repl.handle_line(".end");
+        repl.handle_line("!01 02 00 03 02 00 01 00");
+        let source = temp_path("save.iasm");
+        repl.handle_line(&format!(".save '{}'", source.display()));
+        assert_eq!(
+            Assembler::new().assemble_file(&source).unwrap(),
+            lock(&repl.vm).program()
+        );
+
+        let snapshot = temp_path("snapshot");
+        repl.handle_line(&format!(".snapshot '{}'", snapshot.display()));
+        let registers = *lock(&repl.vm).registers();
+        repl.handle_line(".clear_program");
+        repl.handle_line(&format!(".restore '{}'", snapshot.display()));
+        assert_eq!(lock(&repl.vm).registers(), &registers);
+        assert_eq!(lock(&repl.vm).register(0), Some(42));
+        fs::remove_file(source).unwrap();
+        fs::remove_file(snapshot).unwrap();
+    }
+
+    #[test]
+    fn test_block_mode() {
+        let mut repl = REPL::new();
+        for line in [
+            ".block",
+            "load $1 #5",
+            "top: inc $0",
+            "lt $0 $1",
+            "jeq @top",
+            ".end",
+        ] {
+            repl.handle_line(line);
+        }
+        assert_eq!(lock(&repl.vm).register(0), Some(5));
+        let top = repl.asm.symbols.label_value("top").unwrap();
+        assert_eq!(top as usize, PROGRAM_START + 4);
+
+        // Labels outlive the block they were defined in
+        repl.handle_line("load $2 @top");
+        assert_eq!(lock(&repl.vm).register(2), Some(top as i32));
+
+        let length = lock(&repl.vm).program().len();
+        for line in [".block", ".equ n 3", "hlt", ".cancel"] {
+            repl.handle_line(line);
+        }
+        assert!(repl.block.is_none());
+        assert_eq!(lock(&repl.vm).program().len(), length);
+        assert_eq!(repl.command_buffer.len(), 11);
+    }
+
+    #[test]
+    fn test_directives_at_the_prompt() {
+        let mut repl = REPL::new();
+        for line in [
+            ".equ SIZE 4",
+            "load $0 #SIZE",
+            ".alias count $5",
+            "inc $count",
+        ] {
+            repl.handle_line(line);
+        }
+        assert_eq!(lock(&repl.vm).register(0), Some(4));
+        assert_eq!(lock(&repl.vm).register(5), Some(1));
+        // Neither a command nor a directive, so nothing is appended
+        let length = lock(&repl.vm).program().len();
+        repl.handle_line(".regsters");
+        assert_eq!(lock(&repl.vm).program().len(), length);
+    }
+
+    #[test]
+    fn test_local_labels_and_macros_at_the_prompt() {
+        let mut repl = REPL::new();
+        for line in [
+            "start: load $1 #3",
+            ".loop: inc $2",
+            "blt $2 $1 @.loop",
+            ".macro bump r",
+            "inc \\r",
+            ".endm",
+            "bump $4",
+        ] {
+            repl.handle_line(line);
+        }
+        assert_eq!(lock(&repl.vm).register(2), Some(3));
+        assert!(repl.asm.symbols.label_value("start.loop").is_some());
+        assert!(repl.block.is_none());
+        assert_eq!(lock(&repl.vm).register(4), Some(1));
+    }
+
+    #[test]
+    fn test_quit_ends_the_session() {
+        let vm = Arc::new(Mutex::new(VM::new()));
+        let mut repl = REPL::attach(Arc::clone(&vm), io::sink());
+        repl.run_with(&b".registers\n.quit\n.registers\n"[..]);
+        assert_eq!(repl.command_buffer, vec![".registers", ".quit"]);
+        // The end of the input ends it too
+        let mut repl = REPL::attach(Arc::clone(&vm), io::sink());
+        repl.run_with(&b".registers\n"[..]);
+        assert_eq!(repl.command_buffer, vec![".registers"]);
+    }
+}
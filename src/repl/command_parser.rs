@@ -0,0 +1,498 @@
+use crate::debugger::Watch;
+use crate::vm::REGISTER_COUNT;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// What REPL commands start with, so that they can't be mistaken for assembly
+pub const COMMAND_PREFIX: char = '.';
+
+/// A command as `.help` shows it
+pub struct CommandHelp {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub about: &'static str,
+}
+
+const fn help(name: &'static str, usage: &'static str, about: &'static str) -> CommandHelp {
+    CommandHelp { name, usage, about }
+}
+
+/// Every command of the REPL
+pub const COMMANDS: &[CommandHelp] = &[
+    help(".help", "", "Lists the commands"),
+    help(".quit", "", "Leaves the REPL"),
+    help(".history", "", "Lists what was typed so far"),
+    help(".program", "", "Lists the bytes of the program"),
+    help(
+        ".registers",
+        "",
+        "Shows the registers and the state of the VM",
+    ),
+    help(
+        ".heap",
+        "<offset> <length>",
+        "Shows bytes of the heap in hexadecimal",
+    ),
+    help(".set", "<$r> <value>", "Changes the value of a register"),
+    help(".clear_program", "", "Empties the program"),
+    help(
+        ".hex",
+        "",
+        "Toggles typing bytecode in hexadecimal, which a line starting with ! also is",
+    ),
+    help(".load", "<path>", "Assembles a file, replacing the program"),
+    help(
+        ".append",
+        "<path>",
+        "Assembles a file and appends its code to the program",
+    ),
+    help(".run", "", "Runs the program from its entry point"),
+    help(
+        ".load_module",
+        "<path>",
+        "Loads a module, as source or object file",
+    ),
+    help(
+        ".upgrade_module",
+        "<path>",
+        "Loads a new version of a loaded module",
+    ),
+    help(".purge", "<module>", "Removes the old version of a module"),
+    help(
+        ".break",
+        "[<address|label>]",
+        "Sets a breakpoint, or lists them",
+    ),
+    help(".delete", "<address|label>", "Removes a breakpoint"),
+    help(
+        ".watch",
+        "[$r | heap <offset> <length>]",
+        "Watches a register or bytes of the heap, or lists watchpoints",
+    ),
+    help(".unwatch", "<number>", "Removes a watchpoint"),
+    help(".step", "", "Executes one instruction"),
+    help(
+        ".next",
+        "",
+        "Executes one instruction, stepping over CALLEXT",
+    ),
+    help(
+        ".continue",
+        "",
+        "Runs until a breakpoint or watchpoint is hit",
+    ),
+    help(".list", "", "Shows the instructions around the current one"),
+    help(".reverse_step", "", "Undoes one instruction"),
+    help(
+        ".reverse_continue",
+        "",
+        "Undoes instructions until a breakpoint or watchpoint is hit",
+    ),
+    help(
+        ".journal",
+        "[<size>]",
+        "Sets how many instructions can be undone, 0 for none, or shows it",
+    ),
+    help(
+        ".save",
+        "<path>",
+        "Writes the program as a source file that assembles back to it",
+    ),
+    help(
+        ".snapshot",
+        "<path>",
+        "Writes the state of the VM to a file, to carry on later",
+    ),
+    help(
+        ".restore",
+        "<path>",
+        "Puts back the state of the VM from a file",
+    ),
+    help(
+        ".block",
+        "",
+        "Starts lines of assembly that are assembled together, so they can jump forward",
+    ),
+    help(".end", "", "Assembles and runs the lines of a block"),
+    help(".cancel", "", "Forgets the lines of a block"),
+    help(".labels", "", "Lists the labels defined so far"),
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help,
+    Quit,
+    History,
+    Program,
+    Registers,
+    Heap {
+        offset: usize,
+        length: usize,
+    },
+    Set {
+        register: usize,
+        value: i32,
+    },
+    ClearProgram,
+    Hex,
+    Load {
+        path: String,
+    },
+    Append {
+        path: String,
+    },
+    Run,
+    LoadModule {
+        path: String,
+    },
+    UpgradeModule {
+        path: String,
+    },
+    Purge {
+        module: String,
+    },
+    /// Lists the breakpoints without a location
+    Break {
+        location: Option<String>,
+    },
+    Delete {
+        location: String,
+    },
+    /// Lists the watchpoints without a value to watch
+    Watch {
+        watch: Option<Watch>,
+    },
+    Unwatch {
+        number: usize,
+    },
+    Step,
+    Next,
+    Continue,
+    List,
+    ReverseStep,
+    ReverseContinue,
+    /// Shows the journal without a size
+    Journal {
+        size: Option<usize>,
+    },
+    Save {
+        path: String,
+    },
+    Snapshot {
+        path: String,
+    },
+    Restore {
+        path: String,
+    },
+    Block,
+    End,
+    Cancel,
+    Labels,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    /// A known command with the wrong arguments
+    Usage { name: String, usage: &'static str },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Usage { name, usage } => write!(f, "Usage: {} {}", name, usage),
+        }
+    }
+}
+
+impl Command {
+    /// True for the commands that read or write a file named by the user
+    pub fn uses_files(&self) -> bool {
+        matches!(
+            self,
+            Command::Load { .. }
+                | Command::Append { .. }
+                | Command::LoadModule { .. }
+                | Command::UpgradeModule { .. }
+                | Command::Save { .. }
+                | Command::Snapshot { .. }
+                | Command::Restore { .. }
+        )
+    }
+
+    /// True for the commands that change the VM or run its program
+    pub fn changes_vm(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::ClearProgram
+                | Command::Load { .. }
+                | Command::Append { .. }
+                | Command::Run
+                | Command::LoadModule { .. }
+                | Command::UpgradeModule { .. }
+                | Command::Purge { .. }
+                | Command::Step
+                | Command::Next
+                | Command::Continue
+                | Command::ReverseStep
+                | Command::ReverseContinue
+                | Command::Journal { size: Some(_) }
+                | Command::Restore { .. }
+        )
+    }
+
+    /// Parses a line of the REPL, returning `None` if it isn't a command
+    pub fn parse(line: &str) -> Option<Result<Command, CommandError>> {
+        let line = line.trim();
+        if !line.starts_with(COMMAND_PREFIX) {
+            return None;
+        }
+        let words = tokenize(line);
+        let args: Vec<&str> = words[1..].iter().map(String::as_str).collect();
+        let name = words[0].as_str();
+        let command = match (name, args.as_slice()) {
+            (".help", []) => Some(Command::Help),
+            (".quit", []) => Some(Command::Quit),
+            (".history", []) => Some(Command::History),
+            (".program", []) => Some(Command::Program),
+            (".registers", []) => Some(Command::Registers),
+            (".heap", [offset, length]) => parse_number(offset)
+                .zip(parse_number(length))
+                .map(|(offset, length)| Command::Heap { offset, length }),
+            (".set", [register, value]) => parse_register(register)
+                .zip(parse_integer(value))
+                .map(|(register, value)| Command::Set { register, value }),
+            (".clear_program", []) => Some(Command::ClearProgram),
+            (".hex", []) => Some(Command::Hex),
+            (".load", [path]) => Some(Command::Load {
+                path: path.to_string(),
+            }),
+            (".append", [path]) => Some(Command::Append {
+                path: path.to_string(),
+            }),
+            (".run", []) => Some(Command::Run),
+            (".load_module", [path]) => Some(Command::LoadModule {
+                path: path.to_string(),
+            }),
+            (".upgrade_module", [path]) => Some(Command::UpgradeModule {
+                path: path.to_string(),
+            }),
+            (".purge", [module]) => Some(Command::Purge {
+                module: module.to_string(),
+            }),
+            (".break", []) => Some(Command::Break { location: None }),
+            (".break", [location]) => Some(Command::Break {
+                location: Some(location.to_string()),
+            }),
+            (".delete", [location]) => Some(Command::Delete {
+                location: location.to_string(),
+            }),
+            (".watch", []) => Some(Command::Watch { watch: None }),
+            (".watch", [register]) => parse_register(register).map(|register| Command::Watch {
+                watch: Some(Watch::Register(register)),
+            }),
+            (".watch", ["heap", offset, length]) => parse_number(offset)
+                .zip(parse_number(length))
+                .and_then(|(offset, length)| Some(offset..offset.checked_add(length)?))
+                .map(|range| Command::Watch {
+                    watch: Some(Watch::Heap(range)),
+                }),
+            (".unwatch", [number]) => {
+                parse_number(number).map(|number| Command::Unwatch { number })
+            }
+            (".step", []) => Some(Command::Step),
+            (".next", []) => Some(Command::Next),
+            (".continue", []) => Some(Command::Continue),
+            (".list", []) => Some(Command::List),
+            (".reverse_step", []) => Some(Command::ReverseStep),
+            (".reverse_continue", []) => Some(Command::ReverseContinue),
+            (".journal", []) => Some(Command::Journal { size: None }),
+            (".journal", [size]) => {
+                parse_number(size).map(|size| Command::Journal { size: Some(size) })
+            }
+            (".save", [path]) => Some(Command::Save {
+                path: path.to_string(),
+            }),
+            (".snapshot", [path]) => Some(Command::Snapshot {
+                path: path.to_string(),
+            }),
+            (".restore", [path]) => Some(Command::Restore {
+                path: path.to_string(),
+            }),
+            (".block", []) => Some(Command::Block),
+            (".end", []) => Some(Command::End),
+            (".cancel", []) => Some(Command::Cancel),
+            (".labels", []) => Some(Command::Labels),
+            _ => None,
+        };
+        // Words that aren't commands are directives or local labels, left to the assembler
+        let help = COMMANDS.iter().find(|c| c.name == name)?;
+        Some(command.ok_or_else(|| CommandError::Usage {
+            name: name.to_string(),
+            usage: help.usage,
+        }))
+    }
+}
+
+/// Parses a decimal number, or a hexadecimal one starting with `0x`
+pub fn parse_number(word: &str) -> Option<usize> {
+    match word.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => word.parse().ok(),
+    }
+}
+
+/// Parses a number like `parse_number`, which may be negative, as registers hold them
+fn parse_integer(word: &str) -> Option<i32> {
+    match word.strip_prefix('-') {
+        Some(number) => i32::try_from(parse_number(number)?).ok().map(|n| -n),
+        None => u32::try_from(parse_number(word)?).ok().map(|n| n as i32),
+    }
+}
+
+/// Parses a register as written in assembly, like `$3`
+fn parse_register(word: &str) -> Option<usize> {
+    word.strip_prefix('$')
+        .and_then(|number| number.parse().ok())
+        .filter(|register| *register < REGISTER_COUNT)
+}
+
+/// Splits a line into words. Quotes group words, for paths with spaces.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut quote = None;
+    let mut in_word = false;
+    for c in line.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => word.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                in_word = true;
+            }
+            None if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            None => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize(".load  a.iasm "), vec![".load", "a.iasm"]);
+        assert_eq!(
+            tokenize(".load 'my dir/a.iasm' \"\""),
+            vec![".load", "my dir/a.iasm", ""]
+        );
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(Command::parse("load $0 #1"), None);
+        assert_eq!(Command::parse(" .registers"), Some(Ok(Command::Registers)));
+        assert_eq!(
+            Command::parse(".load test.iasm"),
+            Some(Ok(Command::Load {
+                path: "test.iasm".to_string()
+            }))
+        );
+        assert_eq!(
+            Command::parse(".load"),
+            Some(Err(CommandError::Usage {
+                name: ".load".to_string(),
+                usage: "<path>"
+            }))
+        );
+        assert_eq!(
+            Command::parse(".quit now"),
+            Some(Err(CommandError::Usage {
+                name: ".quit".to_string(),
+                usage: ""
+            }))
+        );
+        assert_eq!(
+            Command::parse(".watch heap 0x10 4"),
+            Some(Ok(Command::Watch {
+                watch: Some(Watch::Heap(16..20))
+            }))
+        );
+        assert_eq!(
+            Command::parse(".watch heap 0xffffffffffffffff 1"),
+            Some(Err(CommandError::Usage {
+                name: ".watch".to_string(),
+                usage: "[$r | heap <offset> <length>]"
+            }))
+        );
+        assert_eq!(
+            Command::parse(".watch $2"),
+            Some(Ok(Command::Watch {
+                watch: Some(Watch::Register(2))
+            }))
+        );
+        assert!(matches!(
+            Command::parse(".watch $32"),
+            Some(Err(CommandError::Usage { .. }))
+        ));
+        assert!(matches!(
+            Command::parse(".unwatch first"),
+            Some(Err(CommandError::Usage { .. }))
+        ));
+        assert_eq!(
+            Command::parse(".set $3 -12"),
+            Some(Ok(Command::Set {
+                register: 3,
+                value: -12
+            }))
+        );
+        assert_eq!(
+            Command::parse(".set $3 0xffffffff"),
+            Some(Ok(Command::Set {
+                register: 3,
+                value: -1
+            }))
+        );
+        assert!(matches!(
+            Command::parse(".set $3 0x100000000"),
+            Some(Err(CommandError::Usage { .. }))
+        ));
+        assert_eq!(
+            Command::parse(".heap 0 0x20"),
+            Some(Ok(Command::Heap {
+                offset: 0,
+                length: 32
+            }))
+        );
+        assert_eq!(
+            Command::parse(".snapshot 'my state'"),
+            Some(Ok(Command::Snapshot {
+                path: "my state".to_string()
+            }))
+        );
+        assert_eq!(Command::parse(".equ SIZE 4"), None);
+        assert_eq!(Command::parse(".loop: inc $0"), None);
+    }
+
+    #[test]
+    fn test_every_command_has_help() {
+        for help in COMMANDS {
+            assert!(help.name.starts_with(COMMAND_PREFIX));
+            assert!(Command::parse(help.name).is_some());
+        }
+    }
+}
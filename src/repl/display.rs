@@ -0,0 +1,98 @@
+use crate::vm::{REGISTER_COUNT, VM};
+
+/// How many registers are shown on a line
+const REGISTER_COLUMNS: usize = 4;
+/// How many bytes a line of a hexdump shows
+const HEXDUMP_WIDTH: usize = 16;
+
+/// The registers in a grid, in decimal and hexadecimal, followed by the rest of the state of
+/// the VM
+pub fn vm_state(vm: &VM) -> Vec<String> {
+    let rows = REGISTER_COUNT / REGISTER_COLUMNS;
+    let mut lines: Vec<String> = (0..rows)
+        .map(|row| {
+            let cells: Vec<String> = (0..REGISTER_COLUMNS)
+                .map(|column| {
+                    let register = column * rows + row;
+                    let value = vm.registers()[register];
+                    format!(
+                        "{:>3} {:>11} {:08x}",
+                        format!("${}", register),
+                        value,
+                        value
+                    )
+                })
+                .collect();
+            cells.join("   ")
+        })
+        .collect();
+    lines.push(format!(
+        "pc: {:#06x}   equal flag: {}   remainder: {}   heap: {} bytes   call depth: {}",
+        vm.pc(),
+        vm.equal_flag(),
+        vm.remainder(),
+        vm.heap().len(),
+        vm.call_depth()
+    ));
+    lines
+}
+
+/// Shows `bytes` in hexadecimal and as ASCII, 16 per line, with the offset of each line
+/// counted from `start`
+pub fn hexdump(bytes: &[u8], start: usize) -> Vec<String> {
+    bytes
+        .chunks(HEXDUMP_WIDTH)
+        .enumerate()
+        .map(|(n, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let text: String = chunk
+                .iter()
+                .map(|b| match b {
+                    0x20..=0x7e => *b as char,
+                    _ => '.',
+                })
+                .collect();
+            format!(
+                "{:#06x}  {:<width$}  |{}|",
+                start + n * HEXDUMP_WIDTH,
+                hex.join(" "),
+                text,
+                width = HEXDUMP_WIDTH * 3 - 1
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vm_state() {
+        let mut vm = VM::new();
+        vm.set_register(1, -1);
+        vm.set_register(9, 42);
+        let lines = vm_state(&vm);
+        assert_eq!(lines.len(), 9);
+        assert_eq!(
+            lines[1],
+            " $1          -1 ffffffff    $9          42 0000002a   $17           0 00000000   $25           0 00000000"
+        );
+        assert!(lines[8].starts_with("pc: 0x0000   equal flag: false"));
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let bytes: Vec<u8> = (0x5e..0x72).collect();
+        let lines = hexdump(&bytes, 0x20);
+        assert_eq!(
+            lines,
+            vec![
+                "0x0020  5e 5f 60 61 62 63 64 65 66 67 68 69 6a 6b 6c 6d  |^_`abcdefghijklm|",
+                "0x0030  6e 6f 70 71                                      |nopq|",
+            ]
+        );
+        assert!(hexdump(&[], 0).is_empty());
+        assert_eq!(hexdump(&[0, 0x7f], 0)[0].split('|').nth(1), Some(".."));
+    }
+}
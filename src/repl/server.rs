@@ -0,0 +1,162 @@
+use crate::repl::REPL;
+use crate::vm::SharedVm;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::thread;
+
+/// A connection a REPL session is served on, read and written from two handles
+trait Connection: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn try_clone(&self) -> io::Result<UnixStream> {
+        UnixStream::try_clone(self)
+    }
+}
+
+/// Serves a REPL attached to `vm` on every connection to `listener`, each in a thread of its
+/// own, while the host keeps running the VM with `run_shared`. Sessions can look at the
+/// registers, heap, program and breakpoints of the VM, but not change it. The VM runs a single
+/// process and has no mailboxes, so there are none to look at. Anyone who can connect can
+/// read the memory of the program, so only listeners on a loopback address are accepted.
+pub fn serve_tcp(listener: TcpListener, vm: SharedVm) -> io::Result<()> {
+    if !listener.local_addr()?.ip().is_loopback() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the REPL is only served on loopback addresses",
+        ));
+    }
+    if let Some(error) = listener.take_error()? {
+        return Err(error);
+    }
+    serve(listener.incoming(), vm);
+    Ok(())
+}
+
+/// Serves a REPL attached to `vm` on every connection to `listener`, like `serve_tcp`. Who can
+/// connect is up to the permissions of the socket file.
+#[cfg(unix)]
+pub fn serve_unix(listener: UnixListener, vm: SharedVm) -> io::Result<()> {
+    if let Some(error) = listener.take_error()? {
+        return Err(error);
+    }
+    serve(listener.incoming(), vm);
+    Ok(())
+}
+
+/// Connections that can't be accepted are skipped, the others are served until the client
+/// quits or goes away
+fn serve<C, I>(connections: I, vm: SharedVm)
+where
+    C: Connection,
+    I: Iterator<Item = io::Result<C>>,
+{
+    for connection in connections.flatten() {
+        let vm = Arc::clone(&vm);
+        thread::spawn(move || {
+            if let Ok(output) = connection.try_clone() {
+                REPL::attach(vm, output).run_with(BufReader::new(connection));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::{lock, run_shared, ExitReason, VM};
+    use std::io::BufRead;
+    use std::sync::Mutex;
+
+    /// Reads what the REPL answers up to its next prompt
+    fn answer<R: BufRead>(reader: &mut R) -> String {
+        let mut answer = vec![];
+        while !answer.ends_with(b">>> ") {
+            let mut byte = [0];
+            reader.read_exact(&mut byte).unwrap();
+            answer.push(byte[0]);
+        }
+        String::from_utf8(answer).unwrap()
+    }
+
+    #[test]
+    fn test_serve_tcp() {
+        // Counts to a million, long enough for the client to type meanwhile
+        let program = Assembler::new()
+            .assemble("load $1 #1000\nmul $1 $1 $1\ntop: inc $0\nlt $0 $1\njeq @top\nhlt")
+            .unwrap();
+        let mut vm = VM::new();
+        vm.load(&program).unwrap();
+        let vm = Arc::new(Mutex::new(vm));
+        let host = {
+            let vm = Arc::clone(&vm);
+            thread::spawn(move || run_shared(&vm, 100))
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let served = Arc::clone(&vm);
+        thread::spawn(move || serve_tcp(listener, served));
+
+        let mut client = TcpStream::connect(address).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        assert!(answer(&mut reader).starts_with("Welcome"));
+        client.write_all(b".registers\n").unwrap();
+        assert!(answer(&mut reader).contains("pc: "));
+
+        // Clients look at the program, they don't take it over
+        for line in [
+            "load $0 #7\n",
+            "!01 00 00 07\n",
+            ".set $0 1\n",
+            ".run\n",
+            ".step\n",
+            ".clear_program\n",
+        ] {
+            client.write_all(line.as_bytes()).unwrap();
+            assert!(answer(&mut reader).contains("can only look at the VM"));
+        }
+        assert_eq!(host.join().unwrap(), Ok(ExitReason::Halted));
+        assert_eq!(lock(&vm).register(0), Some(1_000_000));
+        assert_eq!(lock(&vm).program(), &program[..]);
+
+        // Clients don't get to use the files of the host
+        let path = std::env::temp_dir().join("bumbam_serve_tcp.iasm");
+        let _ = std::fs::remove_file(&path);
+        for line in [
+            format!(".save {}\n", path.display()),
+            format!(".load {}\n", path.display()),
+        ] {
+            client.write_all(line.as_bytes()).unwrap();
+            assert!(answer(&mut reader).contains("Files can't be used"));
+        }
+        assert!(!path.exists());
+
+        client.write_all(b".quit\n").unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "ByeBumBam!\n");
+    }
+
+    #[test]
+    fn test_serve_tcp_only_on_loopback() {
+        let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+        let vm = Arc::new(Mutex::new(VM::new()));
+        assert_eq!(
+            serve_tcp(listener, vm).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
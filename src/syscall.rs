@@ -0,0 +1,180 @@
+use std::fmt;
+
+/// The handle of the first file opened with `Open`, after stdin, stdout and stderr
+pub const FIRST_FILE_HANDLE: usize = 3;
+
+/// The services of the `SYSCALL` instruction, which takes the number of the service. Arguments
+/// are read from `$0`, `$1` and `$2`, and the result is put in `$0`, a negative value meaning the
+/// operation failed. Handles 0, 1 and 2 are stdin, stdout and stderr, files opened with `Open`
+/// get the following ones. Buffers are on the heap, given as an offset and a length, while
+/// strings are `.asciiz` strings of the program, given by their address.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Service {
+    /// Reads up to `$2` bytes from handle `$0` into the heap at `$1`, returns how many were read
+    Read,
+    /// Writes `$2` bytes of the heap at `$1` to handle `$0`, returns how many were written
+    Write,
+    /// Writes the string at address `$1` to handle `$0`, returns how many bytes were written
+    Print,
+    /// Opens the file named by the string at `$0`, for reading if `$1` is 0, for writing if it is
+    /// 1 and for appending if it is 2, returns the handle
+    Open,
+    /// Closes handle `$0`
+    Close,
+    /// Returns the seconds since the Unix epoch, and puts the milliseconds in `$1`
+    Time,
+    /// Returns a random number, which is never negative
+    Random,
+}
+
+impl Service {
+    pub fn from_number(number: u16) -> Option<Service> {
+        match number {
+            0 => Some(Service::Read),
+            1 => Some(Service::Write),
+            2 => Some(Service::Print),
+            3 => Some(Service::Open),
+            4 => Some(Service::Close),
+            5 => Some(Service::Time),
+            6 => Some(Service::Random),
+            _ => None,
+        }
+    }
+
+    /// What a service needs to be allowed. Reading and writing depend on the handle, so they
+    /// are checked when the handle is known.
+    pub fn capability(self) -> Option<Capabilities> {
+        match self {
+            Service::Read | Service::Write | Service::Print | Service::Close => None,
+            Service::Open => Some(Capabilities::FILES),
+            Service::Time => Some(Capabilities::CLOCK),
+            Service::Random => Some(Capabilities::RANDOM),
+        }
+    }
+}
+
+/// What reading, writing or closing a handle needs
+pub fn handle_capability(handle: i32) -> Capabilities {
+    match handle {
+        0 => Capabilities::STDIN,
+        1 => Capabilities::STDOUT,
+        2 => Capabilities::STDERR,
+        _ => Capabilities::FILES,
+    }
+}
+
+/// What the host allows bytecode to do with `SYSCALL`. A VM starts without any capability.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    pub const STDIN: Capabilities = Capabilities(1);
+    pub const STDOUT: Capabilities = Capabilities(1 << 1);
+    pub const STDERR: Capabilities = Capabilities(1 << 2);
+    /// Opening files, and reading and writing them
+    pub const FILES: Capabilities = Capabilities(1 << 3);
+    pub const CLOCK: Capabilities = Capabilities(1 << 4);
+    pub const RANDOM: Capabilities = Capabilities(1 << 5);
+
+    pub fn none() -> Capabilities {
+        Capabilities(0)
+    }
+
+    pub fn all() -> Capabilities {
+        Capabilities(0b11_1111)
+    }
+
+    /// Everything but files, for programs that shouldn't touch the filesystem
+    pub fn sandboxed() -> Capabilities {
+        Capabilities::STDIN
+            .with(Capabilities::STDOUT)
+            .with(Capabilities::STDERR)
+            .with(Capabilities::CLOCK)
+            .with(Capabilities::RANDOM)
+    }
+
+    pub fn with(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub fn without(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & !other.0)
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = [
+            (Capabilities::STDIN, "stdin"),
+            (Capabilities::STDOUT, "stdout"),
+            (Capabilities::STDERR, "stderr"),
+            (Capabilities::FILES, "files"),
+            (Capabilities::CLOCK, "clock"),
+            (Capabilities::RANDOM, "random"),
+        ];
+        let names: Vec<&str> = names
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect();
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+/// A xorshift* generator for the `Random` service, which doesn't need to be more than that
+#[derive(Debug, Clone)]
+pub struct Random(u64);
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        // Xorshift gets stuck on zero
+        Random(seed.max(1))
+    }
+
+    pub fn next_i32(&mut self) -> i32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as i32
+    }
+
+    /// What `new` takes to continue the sequence from here
+    pub fn state(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let sandboxed = Capabilities::sandboxed();
+        assert!(sandboxed.contains(Capabilities::STDOUT.with(Capabilities::CLOCK)));
+        assert!(!sandboxed.contains(Capabilities::FILES));
+        assert!(Capabilities::all().contains(sandboxed));
+        assert_eq!(Capabilities::all().without(Capabilities::FILES), sandboxed);
+        assert!(!Capabilities::default().contains(Capabilities::STDIN));
+        assert_eq!(
+            Capabilities::STDIN.with(Capabilities::FILES).to_string(),
+            "stdin, files"
+        );
+        assert_eq!(Service::from_number(3), Some(Service::Open));
+        assert_eq!(Service::from_number(7), None);
+    }
+
+    #[test]
+    fn test_random_is_deterministic() {
+        let mut a = Random::new(42);
+        let mut b = Random::new(42);
+        let numbers: Vec<i32> = (0..4).map(|_| a.next_i32()).collect();
+        assert_eq!(numbers, (0..4).map(|_| b.next_i32()).collect::<Vec<i32>>());
+        assert_ne!(numbers[0], numbers[1]);
+        assert_ne!(Random::new(0).next_i32(), 0);
+    }
+}
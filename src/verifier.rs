@@ -0,0 +1,256 @@
+use crate::assembler::object_file::ModuleTable;
+use crate::assembler::{code_end, data_length, ELF_HEADER_PREFIX, PROGRAM_START};
+use crate::disassembler::DecodedInstruction;
+use crate::instruction::{Opcode, OperandKind};
+use crate::syscall::Service;
+use crate::vm::REGISTER_COUNT;
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum VerifierError {
+    InvalidHeader,
+    TruncatedData,
+    InvalidModuleTable,
+    TruncatedInstruction { address: usize },
+    UnknownOpcode { address: usize, byte: u8 },
+    InvalidRegister { address: usize, register: u8 },
+    BranchOutsideCode { address: usize, target: isize },
+    UnknownImport { address: usize, import: u16 },
+    UnknownNative { address: usize, native: u16 },
+    UnknownService { address: usize, service: u16 },
+}
+
+impl fmt::Display for VerifierError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifierError::InvalidHeader => write!(f, "the header is missing or incorrect"),
+            VerifierError::TruncatedData => {
+                write!(f, "the data section is longer than the program")
+            }
+            VerifierError::InvalidModuleTable => {
+                write!(f, "the module table after the code is malformed")
+            }
+            VerifierError::TruncatedInstruction { address } => write!(
+                f,
+                "{:#06x}: the code ends in the middle of an instruction",
+                address
+            ),
+            VerifierError::UnknownOpcode { address, byte } => {
+                write!(f, "{:#06x}: unknown opcode {}", address, byte)
+            }
+            VerifierError::InvalidRegister { address, register } => {
+                write!(f, "{:#06x}: there is no register ${}", address, register)
+            }
+            VerifierError::BranchOutsideCode { address, target } => write!(
+                f,
+                "{:#06x}: branch to {:#06x}, which is not an instruction",
+                address, target
+            ),
+            VerifierError::UnknownImport { address, import } => write!(
+                f,
+                "{:#06x}: CALLEXT of import {}, which isn't declared",
+                address, import
+            ),
+            VerifierError::UnknownNative { address, native } => write!(
+                f,
+                "{:#06x}: CALLN of native {}, which isn't declared",
+                address, native
+            ),
+            VerifierError::UnknownService { address, service } => {
+                write!(
+                    f,
+                    "{:#06x}: SYSCALL of unknown service {}",
+                    address, service
+                )
+            }
+        }
+    }
+}
+
+/// Checks an executable before it is run: its sections must fit in it and its code must pass
+/// `verify_code`
+pub fn verify(program: &[u8]) -> Result<(), VerifierError> {
+    let code = code_section(program)?;
+    let table = match &program[code.end..] {
+        [] => ModuleTable::default(),
+        table => ModuleTable::from_bytes(table).ok_or(VerifierError::InvalidModuleTable)?,
+    };
+    verify_code(&program[code.clone()], code.start, &table)
+}
+
+/// Checks the header of an executable and that its sections fit in it, and returns where its
+/// code is
+pub fn code_section(program: &[u8]) -> Result<Range<usize>, VerifierError> {
+    if program.len() < PROGRAM_START || program[..ELF_HEADER_PREFIX.len()] != ELF_HEADER_PREFIX {
+        return Err(VerifierError::InvalidHeader);
+    }
+    let code_start = data_length(program)
+        .and_then(|length| PROGRAM_START.checked_add(length))
+        .filter(|start| *start <= program.len())
+        .ok_or(VerifierError::TruncatedData)?;
+    let code_end = code_end(program);
+    if code_end < code_start || code_end > program.len() {
+        return Err(VerifierError::TruncatedData);
+    }
+    Ok(code_start..code_end)
+}
+
+/// Checks that every instruction of a code section can be executed without reading outside of
+/// the program or the registers: opcodes must be known, registers must exist, branches must
+/// land on an instruction of the same section, `CALLEXT` and `CALLN` must name one of the
+/// imports and natives of the module `table` and `SYSCALL` one of the services. Jumps to a
+/// register are checked by the VM when they are executed. `start` is the address of the code,
+/// used in errors.
+pub fn verify_code(code: &[u8], start: usize, table: &ModuleTable) -> Result<(), VerifierError> {
+    for (n, chunk) in code.chunks(4).enumerate() {
+        let address = start + n * 4;
+        if chunk.len() < 4 {
+            return Err(VerifierError::TruncatedInstruction { address });
+        }
+        let decoded = DecodedInstruction::decode(&[chunk[0], chunk[1], chunk[2], chunk[3]]);
+        if decoded.opcode == Opcode::IGL {
+            return Err(VerifierError::UnknownOpcode {
+                address,
+                byte: chunk[0],
+            });
+        }
+        for (kind, value) in &decoded.operands {
+            match kind {
+                OperandKind::Register if *value as usize >= REGISTER_COUNT => {
+                    return Err(VerifierError::InvalidRegister {
+                        address,
+                        register: *value as u8,
+                    });
+                }
+                OperandKind::Integer
+                    if decoded.opcode == Opcode::CALLEXT
+                        && *value as usize >= table.imports.len() =>
+                {
+                    return Err(VerifierError::UnknownImport {
+                        address,
+                        import: *value as u16,
+                    });
+                }
+                OperandKind::Integer
+                    if decoded.opcode == Opcode::CALLN
+                        && *value as usize >= table.natives.len() =>
+                {
+                    return Err(VerifierError::UnknownNative {
+                        address,
+                        native: *value as u16,
+                    });
+                }
+                OperandKind::Integer
+                    if decoded.opcode == Opcode::SYSCALL
+                        && Service::from_number(*value as u16).is_none() =>
+                {
+                    return Err(VerifierError::UnknownService {
+                        address,
+                        service: *value as u16,
+                    });
+                }
+                _ => {}
+            }
+        }
+        if let Some(target) = decoded.branch_target(address) {
+            let offset = target - start as isize;
+            if offset < 0 || offset as usize >= code.len() || offset % 4 != 0 {
+                return Err(VerifierError::BranchOutsideCode { address, target });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_verify_assembled_programs() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("msg: .asciiz 'hi'\nload $31 @msg\ntop: dec $0\nbgt $0 $1 @top\nhlt")
+            .unwrap();
+        assert_eq!(verify(&program), Ok(()));
+        let module = asm
+            .assemble(".module m\n.import io print\ncallext @io.print\nhlt")
+            .unwrap();
+        assert_eq!(verify(&module), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_errors() {
+        let program = Assembler::new().assemble("load $0 #1\nhlt").unwrap();
+        let code = PROGRAM_START;
+        assert_eq!(verify(&program[..10]), Err(VerifierError::InvalidHeader));
+        assert_eq!(
+            verify(&program[..program.len() - 1]),
+            Err(VerifierError::TruncatedInstruction { address: code + 4 })
+        );
+        let mut bad = program.clone();
+        bad[code] = 200;
+        assert_eq!(
+            verify(&bad),
+            Err(VerifierError::UnknownOpcode {
+                address: code,
+                byte: 200
+            })
+        );
+        let mut bad = program.clone();
+        bad[code + 1] = 32;
+        assert_eq!(
+            verify(&bad),
+            Err(VerifierError::InvalidRegister {
+                address: code,
+                register: 32
+            })
+        );
+        assert_eq!(
+            verify_code(&[20, 0, 1, 2, 0, 0, 0, 0], code, &ModuleTable::default()),
+            Err(VerifierError::BranchOutsideCode {
+                address: code,
+                target: code as isize + 8
+            })
+        );
+        assert_eq!(
+            verify_code(&[20, 0, 1, 0xff], code, &ModuleTable::default()),
+            Err(VerifierError::BranchOutsideCode {
+                address: code,
+                target: code as isize - 4
+            })
+        );
+        let module = Assembler::new()
+            .assemble_object(".module m\n.import io print\n.native now/0\nhlt")
+            .unwrap()
+            .module
+            .unwrap();
+        assert_eq!(
+            verify_code(&[26, 0, 1, 0], code, &module),
+            Err(VerifierError::UnknownImport {
+                address: code,
+                import: 1
+            })
+        );
+        assert_eq!(
+            verify_code(&[28, 0, 1, 0], code, &module),
+            Err(VerifierError::UnknownNative {
+                address: code,
+                native: 1
+            })
+        );
+        assert_eq!(
+            verify_code(&[26, 0, 0, 0, 28, 0, 0, 0], code, &module),
+            Ok(())
+        );
+        assert_eq!(
+            verify_code(&[29, 0, 6, 0, 29, 0, 7, 0], code, &module),
+            Err(VerifierError::UnknownService {
+                address: code + 4,
+                service: 7
+            })
+        );
+    }
+}
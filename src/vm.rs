@@ -1,207 +1,1214 @@
-use crate::assembler::ELF_HEADER_PREFIX;
+use crate::assembler::object_file::{Import, ModuleTable, Native, ObjectFile, Section};
+use crate::assembler::{code_end, data_length, ELF_HEADER_PREFIX, PROGRAM_START};
 use crate::instruction::Opcode;
+use crate::native::NativeRegistry;
+use crate::syscall::{handle_capability, Capabilities, Random, Service, FIRST_FILE_HANDLE};
+use crate::verifier::{code_section, verify, verify_code, VerifierError};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::iter;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub mod snapshot;
+
+/// How many registers the VM has
+pub const REGISTER_COUNT: usize = 32;
+
+/// How many instructions are executed between two checks of `VmLimits::max_duration`
+const CLOCK_CHECK_INTERVAL: u64 = 1024;
+
+/// Resources a program may use before the VM stops it. `None` means no limit, which is the
+/// default.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct VmLimits {
+    pub max_instructions: Option<u64>,
+    /// The largest heap `ALOC` may allocate
+    pub max_heap_bytes: Option<usize>,
+    /// How many `CALLEXT` calls may be nested
+    pub max_stack_depth: Option<usize>,
+    /// How many processes may run at once. The VM runs a single process, the program, so only
+    /// a limit of 0 stops it, before its first instruction.
+    pub max_processes: Option<usize>,
+    /// How long `run` may take. Unlike the other limits, where the program stops depends on how
+    /// fast the machine is.
+    pub max_duration: Option<Duration>,
+}
+
+/// Why the VM stopped running a program, when it wasn't because of a fault
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExitReason {
+    Halted,
+    /// The program counter went past the end of the program
+    EndOfProgram,
+    /// The code being run was purged with `purge_module`
+    Purged,
+    InstructionLimit,
+    HeapLimit {
+        requested: i32,
+    },
+    StackLimit,
+    ProcessLimit,
+    TimeLimit,
+}
+
+impl ExitReason {
+    /// Whether the program stopped by itself rather than because of a limit
+    pub fn is_success(&self) -> bool {
+        matches!(self, ExitReason::Halted | ExitReason::EndOfProgram)
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitReason::Halted => write!(f, "HLT encountered"),
+            ExitReason::EndOfProgram => write!(f, "End of program reached"),
+            ExitReason::Purged => write!(f, "Stopped: the code being run was purged"),
+            ExitReason::InstructionLimit => write!(f, "Stopped: instruction limit reached"),
+            ExitReason::HeapLimit { requested } => {
+                write!(
+                    f,
+                    "Stopped: ALOC of {} bytes exceeds the heap limit",
+                    requested
+                )
+            }
+            ExitReason::StackLimit => write!(f, "Stopped: call stack limit reached"),
+            ExitReason::ProcessLimit => write!(f, "Stopped: process limit reached"),
+            ExitReason::TimeLimit => write!(f, "Stopped: time limit reached"),
+        }
+    }
+}
+
+/// A fault that stops the program
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmError {
+    InvalidProgram {
+        error: VerifierError,
+    },
+    IllegalOpcode {
+        address: usize,
+    },
+    /// A jump to a register that doesn't hold the address of an instruction
+    InvalidJump {
+        address: usize,
+        target: i64,
+    },
+    /// The program counter was moved to what isn't an instruction
+    NotAnInstruction {
+        address: usize,
+    },
+    DivisionByZero {
+        address: usize,
+    },
+    /// An `ALOC` of a negative number of bytes
+    NegativeAllocation {
+        address: usize,
+        requested: i32,
+    },
+    UndefinedFunction {
+        module: String,
+        function: String,
+    },
+    UnknownImport {
+        slot: usize,
+    },
+    UnknownNative {
+        index: usize,
+    },
+    EmptyCallStack {
+        address: usize,
+    },
+    /// A native function returned an error
+    NativeFailed {
+        name: String,
+        message: String,
+    },
+    UnknownService {
+        service: u16,
+    },
+    /// A `SYSCALL` the host didn't allow
+    CapabilityDenied {
+        service: Service,
+        capability: Capabilities,
+    },
+    /// A `SYSCALL` buffer that isn't inside the heap
+    BadBuffer {
+        offset: i32,
+        length: i32,
+    },
+    /// A `SYSCALL` string that isn't inside the program or isn't terminated
+    BadString {
+        address: i32,
+    },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::InvalidProgram { error } => write!(f, "Invalid program: {}", error),
+            VmError::IllegalOpcode { address } => {
+                write!(
+                    f,
+                    "{:#06x}: unrecognized opcode found! Terminating!",
+                    address
+                )
+            }
+            VmError::InvalidJump { address, target } => {
+                write!(
+                    f,
+                    "{:#06x}: jump to {}, which is not an instruction",
+                    address, target
+                )
+            }
+            VmError::NotAnInstruction { address } => {
+                write!(f, "{:#06x}: not an instruction of the program", address)
+            }
+            VmError::DivisionByZero { address } => write!(f, "{:#06x}: division by zero", address),
+            VmError::NegativeAllocation { address, requested } => {
+                write!(f, "{:#06x}: ALOC of {} bytes", address, requested)
+            }
+            VmError::UndefinedFunction { module, function } => {
+                write!(f, "Undefined function {}:{}", module, function)
+            }
+            VmError::UnknownImport { slot } => write!(f, "Unknown import {}", slot),
+            VmError::UnknownNative { index } => write!(f, "Unknown native {}", index),
+            VmError::EmptyCallStack { address } => {
+                write!(
+                    f,
+                    "{:#06x}: RET encountered with an empty call stack",
+                    address
+                )
+            }
+            VmError::NativeFailed { name, message } => write!(f, "{}: {}", name, message),
+            VmError::UnknownService { service } => write!(f, "Unknown service {}", service),
+            VmError::CapabilityDenied {
+                service,
+                capability,
+            } => write!(
+                f,
+                "{:?} needs the {} capability, which the VM doesn't have",
+                service, capability
+            ),
+            VmError::BadBuffer { offset, length } => {
+                write!(f, "{} bytes at {} are not inside the heap", length, offset)
+            }
+            VmError::BadString { address } => {
+                write!(f, "there is no string at address {}", address)
+            }
+        }
+    }
+}
+
+/// Where the output of the VM goes
+pub struct Output(Box<dyn Write + Send>);
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Output")
+    }
+}
+
+/// What the `Read` service reads from handle 0
+pub struct Input(Box<dyn Read + Send>);
+
+impl fmt::Debug for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Input")
+    }
+}
+
+/// Configures a VM for embedding: its limits, the native functions it offers, what `SYSCALL`
+/// may do and where its input and output go, the standard streams by default
+#[derive(Debug, Default)]
+pub struct VmBuilder {
+    limits: VmLimits,
+    natives: NativeRegistry,
+    capabilities: Capabilities,
+    output: Option<Output>,
+    error_output: Option<Output>,
+    input: Option<Input>,
+    random_seed: Option<u64>,
+    journal: Option<usize>,
+}
+
+impl VmBuilder {
+    pub fn new() -> VmBuilder {
+        VmBuilder::default()
+    }
+
+    pub fn limits(mut self, limits: VmLimits) -> VmBuilder {
+        self.limits = limits;
+        self
+    }
+
+    pub fn max_instructions(mut self, max: u64) -> VmBuilder {
+        self.limits.max_instructions = Some(max);
+        self
+    }
+
+    pub fn max_heap_bytes(mut self, max: usize) -> VmBuilder {
+        self.limits.max_heap_bytes = Some(max);
+        self
+    }
+
+    pub fn max_stack_depth(mut self, max: usize) -> VmBuilder {
+        self.limits.max_stack_depth = Some(max);
+        self
+    }
+
+    pub fn max_processes(mut self, max: usize) -> VmBuilder {
+        self.limits.max_processes = Some(max);
+        self
+    }
+
+    pub fn max_duration(mut self, max: Duration) -> VmBuilder {
+        self.limits.max_duration = Some(max);
+        self
+    }
+
+    pub fn natives(mut self, natives: NativeRegistry) -> VmBuilder {
+        self.natives = natives;
+        self
+    }
+
+    /// Registers a native function, see `NativeRegistry::register`
+    pub fn native<F>(mut self, name: &str, arity: u8, function: F) -> VmBuilder
+    where
+        F: FnMut(&[i32]) -> Result<i32, String> + Send + 'static,
+    {
+        self.natives.register(name, arity, function);
+        self
+    }
+
+    pub fn output<W: Write + Send + 'static>(mut self, output: W) -> VmBuilder {
+        self.output = Some(Output(Box::new(output)));
+        self
+    }
+
+    pub fn capabilities(mut self, capabilities: Capabilities) -> VmBuilder {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Where handle 2 goes
+    pub fn error_output<W: Write + Send + 'static>(mut self, output: W) -> VmBuilder {
+        self.error_output = Some(Output(Box::new(output)));
+        self
+    }
+
+    /// What handle 0 reads
+    pub fn input<R: Read + Send + 'static>(mut self, input: R) -> VmBuilder {
+        self.input = Some(Input(Box::new(input)));
+        self
+    }
+
+    /// Makes the `Random` service return the same numbers on every run
+    pub fn random_seed(mut self, seed: u64) -> VmBuilder {
+        self.random_seed = Some(seed);
+        self
+    }
+
+    /// Keeps a journal of the last `capacity` instructions, which `VM::undo` undoes
+    pub fn journal(mut self, capacity: usize) -> VmBuilder {
+        self.journal = Some(capacity);
+        self
+    }
+
+    pub fn build(self) -> VM {
+        let mut vm = VM::new();
+        vm.set_journal(self.journal);
+        vm.limits = self.limits;
+        vm.natives = self.natives;
+        vm.capabilities = self.capabilities;
+        if let Some(output) = self.output {
+            vm.output = output;
+        }
+        if let Some(output) = self.error_output {
+            vm.error_output = output;
+        }
+        if let Some(input) = self.input {
+            vm.input = input;
+        }
+        if let Some(seed) = self.random_seed {
+            vm.random = Random::new(seed);
+        }
+        vm
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ModuleError {
+    /// The bytes are neither an executable nor an object file
+    InvalidImage,
+    /// An object file without a `.module` table
+    NotAModule,
+    /// Modules are placed after the program, which must be loaded first
+    NoProgram,
+    ProgramAlreadyLoaded,
+    DuplicateModule {
+        name: String,
+    },
+    NotLoaded {
+        name: String,
+    },
+    /// A module can't be upgraded again before its old version is purged
+    OldCodeNotPurged {
+        name: String,
+    },
+    /// Modules call each other through imports, `.extern` labels are for the linker
+    ExternalSymbol {
+        name: String,
+    },
+    /// Once placed after the program, an address of the module no longer fits in 16 bits
+    AddressOutOfRange {
+        module: String,
+    },
+    /// The code was rejected by the verifier
+    InvalidCode {
+        error: VerifierError,
+    },
+    /// A `.native` function the host didn't register
+    UndefinedNative {
+        name: String,
+    },
+    NativeArity {
+        name: String,
+        declared: u8,
+        registered: u8,
+    },
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModuleError::InvalidImage => write!(f, "not an executable or object file"),
+            ModuleError::NotAModule => write!(f, "the object file has no .module directive"),
+            ModuleError::NoProgram => write!(f, "a program must be loaded before modules"),
+            ModuleError::ProgramAlreadyLoaded => write!(f, "a program is already loaded"),
+            ModuleError::DuplicateModule { name } => {
+                write!(f, "module '{}' is already loaded", name)
+            }
+            ModuleError::NotLoaded { name } => write!(f, "module '{}' is not loaded", name),
+            ModuleError::OldCodeNotPurged { name } => {
+                write!(
+                    f,
+                    "the old version of module '{}' must be purged first",
+                    name
+                )
+            }
+            ModuleError::ExternalSymbol { name } => {
+                write!(f, "'{}' is .extern, modules must be linked first", name)
+            }
+            ModuleError::AddressOutOfRange { module } => {
+                write!(f, "module '{}' does not fit in the address space", module)
+            }
+            ModuleError::InvalidCode { error } => write!(f, "invalid code: {}", error),
+            ModuleError::UndefinedNative { name } => {
+                write!(f, "native function '{}' is not registered", name)
+            }
+            ModuleError::NativeArity {
+                name,
+                declared,
+                registered,
+            } => write!(
+                f,
+                "native function '{}' is declared with {} arguments but registered with {}",
+                name, declared, registered
+            ),
+        }
+    }
+}
+
+/// A module loaded into the VM and the addresses of the functions it exports
+#[derive(Debug, Clone)]
+pub struct LoadedModule {
+    pub name: String,
+    pub exports: Vec<(String, u8, usize)>,
+    /// Where the current version of the module is in the program
+    pub code: Range<usize>,
+    /// Where the previous version is, until it is purged
+    pub old_code: Option<Range<usize>>,
+}
+
+/// The file opened with `SYSCALL` that has `handle`
+fn file_mut(files: &mut [Option<File>], handle: i32) -> Option<&mut File> {
+    let index = usize::try_from(handle)
+        .ok()?
+        .checked_sub(FIRST_FILE_HANDLE)?;
+    files.get_mut(index)?.as_mut()
+}
+
+/// Where the code of an image placed in the program at `range` is, according to its header
+fn placed_code(program: &[u8], range: Range<usize>) -> Range<usize> {
+    let image = &program[range.clone()];
+    let end = (range.start + code_end(image)).min(range.end);
+    let start = (range.start + PROGRAM_START + data_length(image).unwrap_or(0)).min(end);
+    start..end
+}
+
+fn exports_of(table: ModuleTable) -> Vec<(String, u8, usize)> {
+    table
+        .exports
+        .into_iter()
+        .map(|e| (e.name, e.arity, e.address as usize))
+        .collect()
+}
+
+/// What an instruction changed, to undo it
+#[derive(Debug, Clone)]
+struct JournalEntry {
+    pc: usize,
+    /// The old values of the registers it changed
+    registers: Vec<(usize, i32)>,
+    equal_flag: bool,
+    remainder: usize,
+    heap_length: usize,
+    /// The old bytes of the heap it overwrote, by offset
+    heap_writes: Vec<(usize, Vec<u8>)>,
+    call_depth: usize,
+    /// The top of the call stack, in case it returned
+    call_top: Option<usize>,
+}
+
+/// The undo log of the last instructions executed
+#[derive(Debug, Clone)]
+struct Journal {
+    capacity: usize,
+    entries: VecDeque<JournalEntry>,
+    /// What the instruction being executed overwrote so far
+    heap_writes: Vec<(usize, Vec<u8>)>,
+}
+
+/// A function called with `CALLEXT`. Modules refer to the slot by index once loaded, and the
+/// target is filled in as soon as a module exporting the function is loaded.
+#[derive(Debug, Clone)]
+pub struct ImportSlot {
+    pub module: String,
+    pub function: String,
+    pub target: Option<usize>,
+}
 
 #[derive(Debug)]
 pub struct VM {
     /// Array that simulates having hardware registers
-    pub registers: [i32; 32],
+    pub(crate) registers: [i32; REGISTER_COUNT],
     /// Program counter that tracks which byte is being executed
     pc: usize,
     /// The bytecode of the program being run
-    pub program: Vec<u8>,
+    pub(crate) program: Vec<u8>,
     /// Guess what
     heap: Vec<u8>,
     /// Contains the remainder of modulo division ops
     remainder: usize,
     /// Contains the result of the last comparison operation
     equal_flag: bool,
+    /// Modules loaded with `load_module`
+    modules: Vec<LoadedModule>,
+    /// Parts of the program left by purged code, sorted, which modules loaded later reuse
+    free: Vec<Range<usize>>,
+    /// Every function called with `CALLEXT`, by any module
+    imports: Vec<ImportSlot>,
+    /// Addresses that `RET` goes back to
+    call_stack: Vec<usize>,
+    /// Set when purging a module stopped the running code
+    killed: bool,
+    /// Whether the program went through the verifier since it was last changed
+    verified: bool,
+    /// The code section the program counter is in, looked up again when it leaves it
+    section: Option<Range<usize>>,
+    limits: VmLimits,
+    /// How many instructions were executed since the program was started
+    executed: u64,
+    /// Functions of the host called with `CALLN`
+    natives: NativeRegistry,
+    /// What `SYSCALL` is allowed to do
+    capabilities: Capabilities,
+    output: Output,
+    error_output: Output,
+    input: Input,
+    /// Files opened with `SYSCALL`, the first has handle `FIRST_FILE_HANDLE`
+    files: Vec<Option<File>>,
+    random: Random,
+    /// Kept only when asked for, since it slows execution down
+    journal: Option<Journal>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
-            registers: [0; 32],
+            registers: [0; REGISTER_COUNT],
             program: vec![],
             heap: vec![],
             pc: 0,
             remainder: 0,
             equal_flag: false,
+            modules: vec![],
+            free: vec![],
+            imports: vec![],
+            call_stack: vec![],
+            killed: false,
+            verified: false,
+            section: None,
+            limits: VmLimits::default(),
+            executed: 0,
+            natives: NativeRegistry::new(),
+            capabilities: Capabilities::none(),
+            output: Output(Box::new(io::stdout())),
+            error_output: Output(Box::new(io::stderr())),
+            input: Input(Box::new(io::stdin())),
+            files: vec![],
+            random: Random::new(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |t| t.as_nanos() as u64),
+            ),
+            journal: None,
         }
     }
-    /// Loops as long as instructions can be executed.
-    pub fn run(&mut self) {
-        if self.verify_header() {
-            self.pc = 64 + self.get_starting_offset();
-            let mut is_done = false;
-            while !is_done {
-                is_done = self.execute_instruction();
+    /// Replaces whatever the VM was running with an executable, as `load_module` would load it
+    /// into a new VM. What the host configured, such as limits, natives, capabilities and
+    /// streams, is kept, while files the program opened are closed.
+    pub fn load(&mut self, image: &[u8]) -> Result<(), ModuleError> {
+        self.registers = [0; REGISTER_COUNT];
+        self.pc = 0;
+        self.program.clear();
+        self.heap.clear();
+        self.remainder = 0;
+        self.equal_flag = false;
+        self.modules.clear();
+        self.free.clear();
+        self.imports.clear();
+        self.call_stack.clear();
+        self.killed = false;
+        self.verified = false;
+        self.section = None;
+        self.executed = 0;
+        self.files.clear();
+        self.clear_journal();
+        self.load_module(image)
+    }
+
+    /// Runs the program from its entry point as long as instructions can be executed, within
+    /// the limits of the VM, and returns why it stopped.
+    pub fn run(&mut self) -> Result<ExitReason, VmError> {
+        self.start()?;
+        let started = Instant::now();
+        loop {
+            if let Some(reason) = self.execute_instruction()? {
+                return Ok(reason);
+            }
+            // Reading the clock on every instruction would slow everything down
+            if let Some(max) = self.limits.max_duration {
+                if self.executed.is_multiple_of(CLOCK_CHECK_INTERVAL) && started.elapsed() > max {
+                    return Ok(ExitReason::TimeLimit);
+                }
             }
-        } else {
-            println!("Header incorrect, exiting...");
         }
     }
-    /// Processes the header of bytecode the VM wants to execute
-    fn verify_header(&self) -> bool {
-        if self.program[0..19] != ELF_HEADER_PREFIX {
-            return false;
+    /// Gets the program ready to run from its entry point, as `run` does before executing it.
+    /// Hosts that execute instructions one at a time with `run_once` start with it.
+    pub fn start(&mut self) -> Result<(), VmError> {
+        self.verify_program()
+            .map_err(|error| VmError::InvalidProgram { error })?;
+        self.pc = self.get_starting_offset();
+        self.killed = false;
+        self.executed = 0;
+        self.clear_journal();
+        Ok(())
+    }
+
+    /// Verifies the code sections of the program unless `load_module` already did. Once
+    /// verified, instructions can be executed without checking their operands. The calls of
+    /// loaded code are bound, so they are checked against the import slots and the natives of
+    /// the VM rather than against a module table.
+    fn verify_program(&mut self) -> Result<(), VerifierError> {
+        if !self.verified {
+            code_section(&self.program[..self.program_end()])?;
+            let table = ModuleTable {
+                imports: self
+                    .imports
+                    .iter()
+                    .map(|slot| Import {
+                        module: slot.module.clone(),
+                        function: slot.function.clone(),
+                    })
+                    .collect(),
+                natives: (0..)
+                    .map_while(|index| {
+                        Some(Native {
+                            name: self.natives.name(index)?.to_string(),
+                            arity: self.natives.arity(index)?,
+                        })
+                    })
+                    .collect(),
+                ..ModuleTable::default()
+            };
+            for section in self.code_sections() {
+                verify_code(&self.program[section.clone()], section.start, &table)?;
+            }
+            self.verified = true;
+            self.section = None;
         }
-        true
+        Ok(())
+    }
+
+    /// Where the program ends, which is where the first module placed after it starts, or the
+    /// first purged one did
+    fn program_end(&self) -> usize {
+        self.placed_modules()
+            .chain(&self.free)
+            .map(|range| range.start)
+            .min()
+            .unwrap_or(self.program.len())
+    }
+
+    /// Where the modules placed after the program are, old versions included until purged
+    fn placed_modules(&self) -> impl Iterator<Item = &Range<usize>> + Clone + '_ {
+        self.modules
+            .iter()
+            .flat_map(|m| iter::once(&m.code).chain(&m.old_code))
+            .filter(|range| range.start > 0)
+    }
+
+    /// The sections instructions are executed from: the code of the program, that of the
+    /// modules and code appended after the modules. Purged code isn't any of them.
+    fn code_sections(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        let modules = self.placed_modules();
+        let appended = modules
+            .clone()
+            .chain(&self.free)
+            .map(|range| range.end)
+            .max()
+            .unwrap_or(self.program.len())..self.program.len();
+        iter::once(placed_code(&self.program, 0..self.program_end()))
+            .chain(modules.map(move |range| placed_code(&self.program, range.clone())))
+            .chain(iter::once(appended))
     }
+
+    /// The code section in which an instruction starts at `address`, if one does
+    fn section_of(&self, address: usize) -> Option<Range<usize>> {
+        self.code_sections()
+            .find(|s| s.contains(&address) && (address - s.start).is_multiple_of(4))
+    }
+
+    /// Moves the program counter to the target of a jump, which must be an instruction or the
+    /// end of a code section, where the program stops
+    fn jump(&mut self, address: usize, target: i64) -> Result<(), VmError> {
+        let invalid = || VmError::InvalidJump { address, target };
+        let target = usize::try_from(target).map_err(|_| invalid())?;
+        match self.section_of(target) {
+            Some(section) => self.section = Some(section),
+            None if self.code_sections().any(|s| s.end == target) => {}
+            None => return Err(invalid()),
+        }
+        self.pc = target;
+        Ok(())
+    }
+    /// The code section starts after the header and the data section
     fn get_starting_offset(&self) -> usize {
-        1
-        // let mut rdr = Cursor::new(&self.program[65..69]);
-        // rdr.read_u32::<LittleEndian>().unwrap() as usize
+        PROGRAM_START + data_length(&self.program).unwrap_or(0)
     }
 
-    fn execute_instruction(&mut self) -> bool {
+    /// Executes the instruction at the program counter, recording what it changes if the VM
+    /// keeps a journal
+    fn execute_instruction(&mut self) -> Result<Option<ExitReason>, VmError> {
+        if self.journal.is_none() {
+            return self.execute();
+        }
+        let registers = self.registers;
+        let mut entry = JournalEntry {
+            pc: self.pc,
+            registers: vec![],
+            equal_flag: self.equal_flag,
+            remainder: self.remainder,
+            heap_length: self.heap.len(),
+            heap_writes: vec![],
+            call_depth: self.call_stack.len(),
+            call_top: self.call_stack.last().copied(),
+        };
+        let executed = self.executed;
+        let result = self.execute();
+        let changed = self.registers;
+        if let Some(journal) = &mut self.journal {
+            entry.heap_writes = std::mem::take(&mut journal.heap_writes);
+            // Nothing changed if the VM stopped before executing anything
+            if self.executed != executed && journal.capacity > 0 {
+                entry.registers = (0..REGISTER_COUNT)
+                    .filter(|r| registers[*r] != changed[*r])
+                    .map(|r| (r, registers[r]))
+                    .collect();
+                if journal.entries.len() == journal.capacity {
+                    journal.entries.pop_front();
+                }
+                journal.entries.push_back(entry);
+            }
+        }
+        result
+    }
+
+    /// Executes the instruction at the program counter. Returns why the program stopped if it
+    /// did, `Ok(None)` meaning it can go on.
+    fn execute(&mut self) -> Result<Option<ExitReason>, VmError> {
+        // Purging may have taken away the code the program counter is in
+        if self.killed {
+            return Ok(Some(ExitReason::Purged));
+        }
         // If our program counter has exceeded the length of the program itself, something has
         // gone awry
         if self.pc >= self.program.len() {
-            return true;
+            return Ok(Some(ExitReason::EndOfProgram));
+        }
+        self.verify_program()
+            .map_err(|error| VmError::InvalidProgram { error })?;
+        // Only verified code is executed: jumps are checked, but the program counter can still
+        // run past the end of a section or be moved by the host
+        if !self.section.as_ref().is_some_and(|s| s.contains(&self.pc)) {
+            self.section = self.section_of(self.pc);
+            if self.section.is_none() {
+                if self.code_sections().any(|s| s.end == self.pc) {
+                    return Ok(Some(ExitReason::EndOfProgram));
+                }
+                return Err(VmError::NotAnInstruction { address: self.pc });
+            }
+        }
+        if self.limits.max_processes == Some(0) {
+            return Ok(Some(ExitReason::ProcessLimit));
+        }
+        if let Some(max) = self.limits.max_instructions {
+            if self.executed >= max {
+                return Ok(Some(ExitReason::InstructionLimit));
+            }
         }
+        self.executed += 1;
+        let address = self.pc;
         match self.decode_opcode() {
             Opcode::HLT => {
-                println!("HLT encountered");
-                return true;
+                return Ok(Some(ExitReason::Halted));
             }
             Opcode::LOAD => {
                 let register = self.next_8_bits() as usize; // We cast to usize so we can use it as an index into the array
-                let number = self.next_16_bits() as u16;
+                let number = self.next_16_bits();
                 self.registers[register] = number as i32; // Our registers are i32s, so we need to cast it. We'll cover that later.
             }
             Opcode::ADD => {
                 let register1 = self.registers[self.next_8_bits() as usize];
                 let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
+                self.registers[self.next_8_bits() as usize] = register1.wrapping_add(register2);
             }
             Opcode::SUB => {
                 let register1 = self.registers[self.next_8_bits() as usize];
                 let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
+                self.registers[self.next_8_bits() as usize] = register1.wrapping_sub(register2);
             }
             Opcode::MUL => {
                 let register1 = self.registers[self.next_8_bits() as usize];
                 let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
+                self.registers[self.next_8_bits() as usize] = register1.wrapping_mul(register2);
             }
             Opcode::DIV => {
                 let register1 = self.registers[self.next_8_bits() as usize];
                 let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
-                self.remainder = (register1 % register2) as usize;
+                if register2 == 0 {
+                    return Err(VmError::DivisionByZero { address });
+                }
+                self.registers[self.next_8_bits() as usize] = register1.wrapping_div(register2);
+                self.remainder = register1.wrapping_rem(register2) as usize;
             }
             Opcode::JMP => {
                 let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
+                self.jump(address, target.into())?;
             }
+            // Relative jumps are computed as i64, where they can't overflow
             Opcode::JMPF => {
                 let value = self.registers[self.next_8_bits() as usize];
-                self.pc += value as usize;
+                self.jump(address, self.pc as i64 + i64::from(value))?;
             }
             Opcode::JMPB => {
                 let value = self.registers[self.next_8_bits() as usize];
-                self.pc -= value as usize;
+                self.jump(address, self.pc as i64 - i64::from(value))?;
             }
             Opcode::EQ => {
                 let register1 = self.registers[self.next_8_bits() as usize];
                 let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 == register2 {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
-                }
+                self.equal_flag = register1 == register2;
                 self.next_8_bits();
             }
             Opcode::NEQ => {
                 let register1 = self.registers[self.next_8_bits() as usize];
                 let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 != register2 {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
-                }
+                self.equal_flag = register1 != register2;
                 self.next_8_bits();
             }
             Opcode::GT => {
                 let register1 = self.registers[self.next_8_bits() as usize];
                 let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 > register2 {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
-                }
+                self.equal_flag = register1 > register2;
                 self.next_8_bits();
             }
             Opcode::LT => {
                 let register1 = self.registers[self.next_8_bits() as usize];
                 let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 < register2 {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
-                }
+                self.equal_flag = register1 < register2;
                 self.next_8_bits();
             }
             Opcode::GTQ => {
                 let register1 = self.registers[self.next_8_bits() as usize];
                 let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 >= register2 {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
-                }
+                self.equal_flag = register1 >= register2;
                 self.next_8_bits();
             }
             Opcode::LTQ => {
                 let register1 = self.registers[self.next_8_bits() as usize];
                 let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 <= register2 {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
-                }
+                self.equal_flag = register1 <= register2;
                 self.next_8_bits();
             }
             Opcode::JEQ => {
                 let register = self.next_8_bits() as usize;
                 let target = self.registers[register];
+                self.next_16_bits();
                 if self.equal_flag {
-                    self.pc = target as usize
+                    self.jump(address, target.into())?;
                 }
             }
             Opcode::JNEQ => {
                 let register = self.next_8_bits() as usize;
                 let target = self.registers[register];
+                self.next_16_bits();
                 if !self.equal_flag {
-                    self.pc = target as usize
+                    self.jump(address, target.into())?;
                 }
             }
             Opcode::ALOC => {
                 let register = self.next_8_bits() as usize;
-                let size = self.registers[register] as usize;
+                let requested = self.registers[register];
+                let size = usize::try_from(requested)
+                    .map_err(|_| VmError::NegativeAllocation { address, requested })?;
+                if self.limits.max_heap_bytes.is_some_and(|max| size > max) {
+                    return Ok(Some(ExitReason::HeapLimit { requested }));
+                }
+                self.record_heap_write(0..self.heap.len());
                 self.heap = vec![0; size];
                 self.next_16_bits();
             }
             Opcode::INC => {
                 let register = self.next_8_bits() as usize;
-                self.registers[register] += 1;
+                self.registers[register] = self.registers[register].wrapping_add(1);
                 self.next_16_bits();
             }
             Opcode::DEC => {
                 let register = self.next_8_bits() as usize;
-                self.registers[register] -= 1;
+                self.registers[register] = self.registers[register].wrapping_sub(1);
                 self.next_16_bits();
             }
-            _ => {
-                println!("Unrecognized opcode found! Terminating!");
-                return true;
+            Opcode::BEQ => {
+                let (register1, register2, target) = self.decode_branch();
+                if register1 == register2 {
+                    self.pc = target;
+                }
+            }
+            Opcode::BNE => {
+                let (register1, register2, target) = self.decode_branch();
+                if register1 != register2 {
+                    self.pc = target;
+                }
+            }
+            Opcode::BGT => {
+                let (register1, register2, target) = self.decode_branch();
+                if register1 > register2 {
+                    self.pc = target;
+                }
+            }
+            Opcode::BLT => {
+                let (register1, register2, target) = self.decode_branch();
+                if register1 < register2 {
+                    self.pc = target;
+                }
+            }
+            Opcode::BGE => {
+                let (register1, register2, target) = self.decode_branch();
+                if register1 >= register2 {
+                    self.pc = target;
+                }
+            }
+            Opcode::BLE => {
+                let (register1, register2, target) = self.decode_branch();
+                if register1 <= register2 {
+                    self.pc = target;
+                }
+            }
+            Opcode::CALLEXT => {
+                let slot = self.next_16_bits() as usize;
+                self.next_8_bits();
+                match self.imports.get(slot) {
+                    Some(ImportSlot {
+                        target: Some(target),
+                        ..
+                    }) => {
+                        if Some(self.call_stack.len()) == self.limits.max_stack_depth {
+                            return Ok(Some(ExitReason::StackLimit));
+                        }
+                        let back = self.pc;
+                        self.jump(address, *target as i64)?;
+                        self.call_stack.push(back);
+                    }
+                    Some(ImportSlot {
+                        module, function, ..
+                    }) => {
+                        return Err(VmError::UndefinedFunction {
+                            module: module.clone(),
+                            function: function.clone(),
+                        });
+                    }
+                    None => return Err(VmError::UnknownImport { slot }),
+                }
+            }
+            Opcode::CALLN => {
+                let index = self.next_16_bits() as usize;
+                self.next_8_bits();
+                match self.natives.call(index, &self.registers) {
+                    Some(Ok(result)) => self.registers[0] = result,
+                    Some(Err(message)) => {
+                        return Err(VmError::NativeFailed {
+                            name: self.natives.name(index).unwrap_or_default().to_string(),
+                            message,
+                        });
+                    }
+                    None => return Err(VmError::UnknownNative { index }),
+                }
             }
+            Opcode::SYSCALL => {
+                let number = self.next_16_bits();
+                self.next_8_bits();
+                self.syscall(number)?;
+            }
+            Opcode::RET => match self.call_stack.pop() {
+                Some(back) => self.jump(address, back as i64)?,
+                None => return Err(VmError::EmptyCallStack { address }),
+            },
+            _ => return Err(VmError::IllegalOpcode { address }),
         }
-        false
+        Ok(None)
     }
-    /// Executes one instruction. Meant to allow for more controlled execution of the VM
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    /// Runs a service for `SYSCALL`. Failures the program can handle, such as a file that
+    /// doesn't exist, put -1 in `$0`, while doing what the VM isn't allowed to or passing a
+    /// buffer outside of the heap stops the program.
+    fn syscall(&mut self, number: u16) -> Result<(), VmError> {
+        let service =
+            Service::from_number(number).ok_or(VmError::UnknownService { service: number })?;
+        if let Some(capability) = service.capability() {
+            self.check_capability(service, capability)?;
+        }
+        let result = match service {
+            Service::Read => {
+                let handle = self.registers[0];
+                self.check_capability(service, handle_capability(handle))?;
+                let buffer = self.heap_buffer()?;
+                self.record_heap_write(buffer.clone());
+                let buffer = &mut self.heap[buffer];
+                let read = match handle {
+                    0 => self.input.0.read(buffer).ok(),
+                    _ => file_mut(&mut self.files, handle).and_then(|f| f.read(buffer).ok()),
+                };
+                read.map(|n| n as i32)
+            }
+            Service::Write => {
+                let handle = self.registers[0];
+                self.check_capability(service, handle_capability(handle))?;
+                let bytes = self.heap[self.heap_buffer()?].to_vec();
+                self.write_to(handle, &bytes)
+            }
+            Service::Print => {
+                let handle = self.registers[0];
+                self.check_capability(service, handle_capability(handle))?;
+                let bytes = self.string_at(self.registers[1])?;
+                self.write_to(handle, &bytes)
+            }
+            Service::Open => {
+                let path =
+                    String::from_utf8_lossy(&self.string_at(self.registers[0])?).into_owned();
+                let file = match self.registers[1] {
+                    0 => File::open(&path).ok(),
+                    1 => File::create(&path).ok(),
+                    2 => OpenOptions::new()
+                        .append(true)
+                        .create(true)
+                        .open(&path)
+                        .ok(),
+                    _ => None,
+                };
+                file.map(|file| self.add_file(file))
+            }
+            Service::Close => {
+                let handle = self.registers[0];
+                self.check_capability(service, handle_capability(handle))?;
+                usize::try_from(handle)
+                    .ok()
+                    .and_then(|handle| handle.checked_sub(FIRST_FILE_HANDLE))
+                    .and_then(|index| self.files.get_mut(index))
+                    .and_then(Option::take)
+                    .map(|_| 0)
+            }
+            Service::Time => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|now| {
+                    self.registers[1] = now.subsec_millis() as i32;
+                    now.as_secs() as i32
+                }),
+            // Negative numbers are for failures
+            Service::Random => Some(self.random.next_i32() & i32::MAX),
+        };
+        self.registers[0] = result.unwrap_or(-1);
+        Ok(())
+    }
+
+    /// Saves bytes of the heap in the journal before the instruction overwrites them
+    fn record_heap_write(&mut self, range: Range<usize>) {
+        if let Some(journal) = &mut self.journal {
+            journal
+                .heap_writes
+                .push((range.start, self.heap[range].to_vec()));
+        }
+    }
+
+    /// Puts the VM back in the state it was before the last instruction recorded in the
+    /// journal, returning false if there is none. What went out of the VM, such as output or
+    /// writes to files, stays done.
+    pub fn undo(&mut self) -> bool {
+        let entry = match self.journal.as_mut().and_then(|j| j.entries.pop_back()) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        self.pc = entry.pc;
+        for (register, value) in entry.registers {
+            self.registers[register] = value;
+        }
+        self.equal_flag = entry.equal_flag;
+        self.remainder = entry.remainder;
+        self.heap.resize(entry.heap_length, 0);
+        // Later writes may have overwritten earlier ones, so they are undone first
+        for (offset, bytes) in entry.heap_writes.into_iter().rev() {
+            self.heap[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+        self.call_stack.truncate(entry.call_depth);
+        if self.call_stack.len() < entry.call_depth {
+            self.call_stack.extend(entry.call_top);
+        }
+        self.executed = self.executed.saturating_sub(1);
+        self.killed = false;
+        true
+    }
+
+    /// Starts or stops keeping a journal of the last `capacity` instructions executed. `None`
+    /// stops it, which frees the instructions recorded so far.
+    pub fn set_journal(&mut self, capacity: Option<usize>) {
+        self.journal = capacity.map(|capacity| {
+            let mut entries = self
+                .journal
+                .take()
+                .map_or_else(VecDeque::new, |journal| journal.entries);
+            while entries.len() > capacity {
+                entries.pop_front();
+            }
+            Journal {
+                capacity,
+                entries,
+                heap_writes: vec![],
+            }
+        });
+    }
+
+    pub fn journal_capacity(&self) -> Option<usize> {
+        self.journal.as_ref().map(|journal| journal.capacity)
+    }
+
+    /// How many instructions `undo` can undo
+    pub fn journal_length(&self) -> usize {
+        self.journal
+            .as_ref()
+            .map_or(0, |journal| journal.entries.len())
+    }
+
+    fn clear_journal(&mut self) {
+        if let Some(journal) = &mut self.journal {
+            journal.entries.clear();
+        }
+    }
+
+    fn check_capability(&self, service: Service, capability: Capabilities) -> Result<(), VmError> {
+        if self.capabilities.contains(capability) {
+            Ok(())
+        } else {
+            Err(VmError::CapabilityDenied {
+                service,
+                capability,
+            })
+        }
+    }
+
+    /// The part of the heap given by the offset in `$1` and the length in `$2`
+    fn heap_buffer(&self) -> Result<Range<usize>, VmError> {
+        let (offset, length) = (self.registers[1], self.registers[2]);
+        usize::try_from(offset)
+            .ok()
+            .zip(usize::try_from(length).ok())
+            .and_then(|(offset, length)| Some(offset..offset.checked_add(length)?))
+            .filter(|buffer| buffer.end <= self.heap.len())
+            .ok_or(VmError::BadBuffer { offset, length })
+    }
+
+    /// The zero terminated string at `address` in the program, without the zero
+    fn string_at(&self, address: i32) -> Result<Vec<u8>, VmError> {
+        usize::try_from(address)
+            .ok()
+            .and_then(|start| self.program.get(start..))
+            .and_then(|bytes| Some(bytes[..bytes.iter().position(|b| *b == 0)?].to_vec()))
+            .ok_or(VmError::BadString { address })
+    }
+
+    /// Writes all of `bytes` to a handle and returns how many there were
+    fn write_to(&mut self, handle: i32, bytes: &[u8]) -> Option<i32> {
+        let stream: &mut dyn Write = match handle {
+            1 => &mut *self.output.0,
+            2 => &mut *self.error_output.0,
+            _ => file_mut(&mut self.files, handle)?,
+        };
+        stream.write_all(bytes).and_then(|_| stream.flush()).ok()?;
+        Some(bytes.len() as i32)
+    }
+
+    /// Keeps an opened file and returns its handle, reusing the one of a closed file if any
+    fn add_file(&mut self, file: File) -> i32 {
+        let index = match self.files.iter().position(Option::is_none) {
+            Some(index) => {
+                self.files[index] = Some(file);
+                index
+            }
+            None => {
+                self.files.push(Some(file));
+                self.files.len() - 1
+            }
+        };
+        (index + FIRST_FILE_HANDLE) as i32
+    }
+
+    /// Executes one instruction. Meant to allow for more controlled execution of the VM.
+    /// Returns why the program stopped, if it did. The program is verified first if it changed,
+    /// as `run` does.
+    pub fn run_once(&mut self) -> Result<Option<ExitReason>, VmError> {
+        self.execute_instruction()
     }
 
     fn decode_opcode(&mut self) -> Opcode {
@@ -210,6 +1217,17 @@ impl VM {
         opcode
     }
 
+    /// Reads the operands of a compare-and-branch instruction: the values of the two compared
+    /// registers and the absolute target, computed from the signed instruction offset
+    fn decode_branch(&mut self) -> (i32, i32, usize) {
+        let start = self.pc - 1;
+        let register1 = self.registers[self.next_8_bits() as usize];
+        let register2 = self.registers[self.next_8_bits() as usize];
+        let offset = self.next_8_bits() as i8 as isize;
+        let target = (start as isize + offset * 4) as usize;
+        (register1, register2, target)
+    }
+
     fn next_8_bits(&mut self) -> u8 {
         let result = self.program[self.pc];
         self.pc += 1;
@@ -221,25 +1239,451 @@ impl VM {
         self.pc += 2;
         result
     }
+    /// Loads an executable as the program, or an object file declaring a `.module` after it.
+    /// The `CALLEXT` instructions of either are bound to the functions exported by modules,
+    /// which may be loaded in any order: calls to a module that isn't loaded yet are bound when
+    /// it is.
+    pub fn load_module(&mut self, image: &[u8]) -> Result<(), ModuleError> {
+        if image.get(..ELF_HEADER_PREFIX.len()) == Some(&ELF_HEADER_PREFIX[..]) {
+            if !self.program.is_empty() {
+                return Err(ModuleError::ProgramAlreadyLoaded);
+            }
+            let end = code_end(image);
+            let table = match image.get(end..) {
+                Some([]) | None => ModuleTable::default(),
+                Some(bytes) => ModuleTable::from_bytes(bytes).ok_or(ModuleError::InvalidImage)?,
+            };
+            verify(image).map_err(|error| ModuleError::InvalidCode { error })?;
+            self.check_natives(&table)?;
+            self.program = image.to_vec();
+            self.verified = true;
+            self.bind_calls(&table, 0..image.len());
+            if !table.name.is_empty() || !table.exports.is_empty() {
+                self.register_module(table, 0..image.len());
+            }
+            return Ok(());
+        }
+
+        let (object, table) = self.module_object(image)?;
+        if self.modules.iter().any(|m| m.name == table.name) {
+            return Err(ModuleError::DuplicateModule { name: table.name });
+        }
+        let (table, code) = self.place_object(image, &object, table)?;
+        self.register_module(table, code);
+        Ok(())
+    }
+
+    /// Loads a new version of a module that is already loaded. Calls through `CALLEXT` go to the
+    /// new version from then on, while code already running in the old version, or returning
+    /// to it, keeps running there until the module is purged. Only one old version is kept, so
+    /// the previous one must have been purged. The VM runs a single program rather than
+    /// processes, so that program is the only code that can be left in the old version.
+    pub fn upgrade_module(&mut self, image: &[u8]) -> Result<(), ModuleError> {
+        let (object, table) = self.module_object(image)?;
+        let index = self
+            .modules
+            .iter()
+            .position(|m| m.name == table.name)
+            .ok_or_else(|| ModuleError::NotLoaded {
+                name: table.name.clone(),
+            })?;
+        if self.modules[index].old_code.is_some() {
+            return Err(ModuleError::OldCodeNotPurged { name: table.name });
+        }
+        let (table, code) = self.place_object(image, &object, table)?;
+        let module = &mut self.modules[index];
+        module.old_code = Some(std::mem::replace(&mut module.code, code));
+        module.exports = exports_of(table);
+        let name = module.name.clone();
+        self.retarget_imports(&name);
+        Ok(())
+    }
+
+    /// Removes the old version of a module, whose place in the program is reused by the modules
+    /// loaded next. If the VM is running the old code, or would return to it, it is stopped:
+    /// that is the only way to get out of code that no longer exists. Without processes to kill
+    /// one by one, that stops the whole program. Purging is done by the host, there is no
+    /// instruction for it. Returns whether the VM was stopped.
+    pub fn purge_module(&mut self, name: &str) -> Result<bool, ModuleError> {
+        let module = self
+            .modules
+            .iter_mut()
+            .find(|m| m.name == name)
+            .ok_or_else(|| ModuleError::NotLoaded {
+                name: name.to_string(),
+            })?;
+        let old = match module.old_code.take() {
+            Some(old) => old,
+            None => return Ok(false),
+        };
+        let stuck = old.contains(&self.pc) || self.call_stack.iter().any(|a| old.contains(a));
+        self.release(old);
+        if stuck {
+            self.killed = true;
+            self.call_stack.clear();
+        }
+        Ok(stuck)
+    }
+
+    /// Makes `range` of the program free for modules loaded later. It is cleared, so that a
+    /// snapshot doesn't keep what it held, and it is cut off if it ends the program.
+    fn release(&mut self, range: Range<usize>) {
+        for byte in &mut self.program[range.clone()] {
+            *byte = 0;
+        }
+        let at = self.free.partition_point(|free| free.start < range.start);
+        self.free.insert(at, range);
+        // Neighbouring ranges are merged, so that a larger module fits
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(self.free.len());
+        for range in self.free.drain(..) {
+            match merged.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => merged.push(range),
+            }
+        }
+        if merged
+            .last()
+            .is_some_and(|last| last.end == self.program.len())
+        {
+            if let Some(last) = merged.pop() {
+                self.program.truncate(last.start);
+            }
+        }
+        self.free = merged;
+        self.section = None;
+    }
+
+    /// Where an image of `length` bytes goes: the first free range it fits in, or the end of
+    /// the program
+    fn free_range_for(&self, length: usize) -> usize {
+        self.free
+            .iter()
+            .find(|free| free.len() >= length)
+            .map_or(self.program.len(), |free| free.start)
+    }
+
+    /// Puts `bytes` at `base`, given by `free_range_for`, and returns where they are
+    fn place(&mut self, base: usize, bytes: &mut Vec<u8>) -> Range<usize> {
+        let placed = base..base + bytes.len();
+        match self.free.iter().position(|free| free.start == base) {
+            Some(index) => {
+                self.program[placed.clone()].copy_from_slice(bytes);
+                self.free[index].start = placed.end;
+                if self.free[index].is_empty() {
+                    self.free.remove(index);
+                }
+            }
+            None => self.program.append(bytes),
+        }
+        placed
+    }
+
+    /// Reads a module from an object file and checks that it can be placed after the program.
+    /// Both the code of the module and the program are verified.
+    fn module_object(&mut self, image: &[u8]) -> Result<(ObjectFile, ModuleTable), ModuleError> {
+        let object = ObjectFile::from_bytes(image).ok_or(ModuleError::InvalidImage)?;
+        let table = object.module.clone().ok_or(ModuleError::NotAModule)?;
+        if self.program.is_empty() {
+            return Err(ModuleError::NoProgram);
+        }
+        self.verify_program()
+            .map_err(|error| ModuleError::InvalidCode { error })?;
+        let code_start = PROGRAM_START + object.data.len();
+        verify_code(&object.code, code_start, &table)
+            .map_err(|error| ModuleError::InvalidCode { error })?;
+        self.check_natives(&table)?;
+        if let Some(symbol) = object
+            .symbols
+            .iter()
+            .find(|s| s.section == Section::External)
+        {
+            return Err(ModuleError::ExternalSymbol {
+                name: symbol.name.clone(),
+            });
+        }
+        Ok((object, table))
+    }
+
+    /// Places a module where purged code was or after what is loaded, relocating it, and binds
+    /// its calls. Returns the module table, with addresses as loaded, and where the module was
+    /// placed.
+    fn place_object(
+        &mut self,
+        image: &[u8],
+        object: &ObjectFile,
+        mut table: ModuleTable,
+    ) -> Result<(ModuleTable, Range<usize>), ModuleError> {
+        // The header, data and code of the module go at `base`, so every address it was
+        // assembled with moves by that much
+        let code_start = PROGRAM_START + object.data.len();
+        let mut loaded = image[..code_start + object.code.len()].to_vec();
+        let base = self.free_range_for(loaded.len());
+        let out_of_range = || ModuleError::AddressOutOfRange {
+            module: table.name.clone(),
+        };
+        for relocation in &object.relocations {
+            let field = code_start + relocation.offset as usize;
+            let address = loaded
+                .get(field..field + 2)
+                .map(|b| (usize::from(b[0]) << 8 | usize::from(b[1])) + base)
+                .filter(|address| *address <= usize::from(u16::MAX))
+                .ok_or_else(out_of_range)?;
+            loaded[field] = (address >> 8) as u8;
+            loaded[field + 1] = address as u8;
+        }
+        if base + loaded.len() > usize::from(u16::MAX) + 1 {
+            return Err(out_of_range());
+        }
+        for export in &mut table.exports {
+            export.address += base as u32;
+        }
+        let placed = self.place(base, &mut loaded);
+        self.bind_calls(&table, placed.clone());
+        Ok((table, placed))
+    }
+
+    /// Makes sure the host registered every native function a module declares
+    fn check_natives(&self, table: &ModuleTable) -> Result<(), ModuleError> {
+        for native in &table.natives {
+            let registered = self
+                .natives
+                .index_of(&native.name)
+                .and_then(|index| self.natives.arity(index))
+                .ok_or_else(|| ModuleError::UndefinedNative {
+                    name: native.name.clone(),
+                })?;
+            if registered != native.arity {
+                return Err(ModuleError::NativeArity {
+                    name: native.name.clone(),
+                    declared: native.arity,
+                    registered,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Points the `CALLEXT` instructions of the code placed at `range` at import slots, and its
+    /// `CALLN` instructions at natives of the registry
+    fn bind_calls(&mut self, table: &ModuleTable, range: Range<usize>) {
+        let slots: Vec<usize> = table
+            .imports
+            .iter()
+            .map(|import| self.import_slot(&import.module, &import.function))
+            .collect();
+        // Every native is registered, `check_natives` made sure of it
+        let natives: Vec<usize> = table
+            .natives
+            .iter()
+            .filter_map(|native| self.natives.index_of(&native.name))
+            .collect();
+        let code = placed_code(&self.program, range);
+        let callext: u8 = Opcode::CALLEXT.into();
+        let calln: u8 = Opcode::CALLN.into();
+        for address in code.clone().step_by(4) {
+            let targets = match self.program[address] {
+                opcode if opcode == callext => &slots,
+                opcode if opcode == calln => &natives,
+                _ => continue,
+            };
+            if address + 2 >= code.end {
+                continue;
+            }
+            let index = usize::from(self.program[address + 1]) << 8
+                | usize::from(self.program[address + 2]);
+            // An index past the table is left alone, to fail when executed
+            if let Some(target) = targets.get(index) {
+                self.program[address + 1] = (target >> 8) as u8;
+                self.program[address + 2] = *target as u8;
+            }
+        }
+    }
+
+    fn register_module(&mut self, table: ModuleTable, code: Range<usize>) {
+        let name = table.name.clone();
+        self.modules.push(LoadedModule {
+            name: table.name.clone(),
+            exports: exports_of(table),
+            code,
+            old_code: None,
+        });
+        self.retarget_imports(&name);
+    }
+
+    /// Points the import slots of a module at its current exports
+    fn retarget_imports(&mut self, module: &str) {
+        let exports = match self.modules.iter().find(|m| m.name == module) {
+            Some(m) => &m.exports,
+            None => return,
+        };
+        for slot in self.imports.iter_mut().filter(|s| s.module == module) {
+            slot.target = exports
+                .iter()
+                .find(|(name, _, _)| *name == slot.function)
+                .map(|(_, _, address)| *address);
+        }
+    }
+
+    /// Finds or creates the slot of an imported function
+    fn import_slot(&mut self, module: &str, function: &str) -> usize {
+        if let Some(index) = self
+            .imports
+            .iter()
+            .position(|s| s.module == module && s.function == function)
+        {
+            return index;
+        }
+        let target = self
+            .modules
+            .iter()
+            .find(|m| m.name == module)
+            .and_then(|m| m.exports.iter().find(|(name, _, _)| name == function))
+            .map(|(_, _, address)| *address);
+        self.imports.push(ImportSlot {
+            module: module.to_string(),
+            function: function.to_string(),
+            target,
+        });
+        self.imports.len() - 1
+    }
+
+    pub fn registers(&self) -> &[i32; REGISTER_COUNT] {
+        &self.registers
+    }
+
+    pub fn register(&self, index: usize) -> Option<i32> {
+        self.registers.get(index).copied()
+    }
+
+    /// Sets a register, returning `false` if there is no such register
+    pub fn set_register(&mut self, index: usize, value: i32) -> bool {
+        match self.registers.get_mut(index) {
+            Some(register) => {
+                *register = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Moves the program counter, for hosts that execute instructions with `run_once`, which
+    /// refuses to execute what isn't an instruction
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+        self.section = None;
+    }
+
+    /// Where `run` starts executing the program
+    pub fn entry_point(&self) -> usize {
+        self.get_starting_offset()
+    }
+
+    /// How many `CALLEXT` calls haven't returned yet
+    pub fn call_depth(&self) -> usize {
+        self.call_stack.len()
+    }
+
+    pub fn equal_flag(&self) -> bool {
+        self.equal_flag
+    }
+
+    pub fn remainder(&self) -> usize {
+        self.remainder
+    }
+
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    /// Where the output of the VM goes
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut *self.output.0
+    }
+
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// The native functions, which must be registered before loading code that uses them
+    pub fn natives_mut(&mut self) -> &mut NativeRegistry {
+        &mut self.natives
+    }
+
+    pub fn set_limits(&mut self, limits: VmLimits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> &VmLimits {
+        &self.limits
+    }
+
+    /// The modules loaded so far
+    pub fn modules(&self) -> &[LoadedModule] {
+        &self.modules
+    }
+
     /// Adds an arbitrary byte to the VM's program
     pub fn add_byte(&mut self, b: u8) {
+        self.verified = false;
         self.program.push(b);
     }
     /// Adds an arbitrary byte to the VM's program
     pub fn add_bytes(&mut self, mut b: Vec<u8>) {
+        self.verified = false;
         self.program.append(&mut b);
     }
 }
 
+/// A VM that the host runs with `run_shared` while REPLs are attached to it
+pub type SharedVm = Arc<Mutex<VM>>;
+
+/// Locks a shared VM. A panic while it was locked doesn't leave the VM in a state that can't
+/// be looked at, so the lock is taken anyway.
+pub fn lock(vm: &SharedVm) -> MutexGuard<'_, VM> {
+    vm.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Runs the program of a shared VM like `VM::run`, letting go of it every `slice` instructions
+/// so that REPLs attached to it can look at it, or change it, meanwhile
+pub fn run_shared(vm: &SharedVm, slice: u64) -> Result<ExitReason, VmError> {
+    lock(vm).start()?;
+    let started = Instant::now();
+    loop {
+        let mut vm = lock(vm);
+        for _ in 0..slice.max(1) {
+            if let Some(reason) = vm.execute_instruction()? {
+                return Ok(reason);
+            }
+        }
+        if let Some(max) = vm.limits.max_duration {
+            if started.elapsed() > max {
+                return Ok(ExitReason::TimeLimit);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::assembler::{ELF_HEADER_LENGTH, ELF_HEADER_PREFIX};
+    use crate::assembler::{Assembler, ELF_HEADER_LENGTH, ELF_HEADER_PREFIX};
 
     fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
         let mut prep = vec![];
         for byte in ELF_HEADER_PREFIX.iter() {
-            prep.push(byte.clone());
+            prep.push(*byte);
         }
         while prep.len() <= ELF_HEADER_LENGTH {
             prep.push(0);
@@ -248,6 +1692,12 @@ mod tests {
         prep
     }
 
+    /// Makes `code` the code of the program and points the program counter at it
+    fn load_code(test_vm: &mut VM, code: Vec<u8>) {
+        test_vm.program = prepend_header(code);
+        test_vm.pc = PROGRAM_START;
+    }
+
     #[test]
     fn test_create_vm() {
         let test_vm = VM::new();
@@ -257,26 +1707,31 @@ mod tests {
     #[test]
     fn test_opcode_hlt() {
         let mut test_vm = VM::new();
-        let test_bytes = vec![0, 0, 0, 0];
-        test_vm.program = test_bytes;
-        test_vm.run_once();
-        assert_eq!(test_vm.pc, 1);
+        load_code(&mut test_vm, vec![0, 0, 0, 0]);
+        assert_eq!(test_vm.run_once(), Ok(Some(ExitReason::Halted)));
+        assert_eq!(test_vm.pc, PROGRAM_START + 1);
     }
 
     #[test]
     fn test_opcode_igl() {
         let mut test_vm = VM::new();
-        let test_bytes = vec![200, 0, 0, 0];
-        test_vm.program = test_bytes;
-        test_vm.run_once();
-        assert_eq!(test_vm.pc, 1);
+        load_code(&mut test_vm, vec![200, 0, 0, 0]);
+        // The verifier would reject the opcode
+        test_vm.verified = true;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::IllegalOpcode {
+                address: PROGRAM_START
+            })
+        );
+        assert_eq!(test_vm.pc, PROGRAM_START + 1);
     }
 
     #[test]
     fn test_opcode_load() {
         let mut test_vm = VM::new();
-        test_vm.program = vec![1, 0, 1, 244]; // Remember, this is how we represent 500 using two u8s in little endian format
-        test_vm.run_once();
+        load_code(&mut test_vm, vec![1, 0, 1, 244]); // Remember, this is how we represent 500 using two u8s in little endian format
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[0], 500);
     }
 
@@ -285,7 +1740,7 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 0, 1, 244]; // Remember, this is how we represent 500 using two u8s in little endian format
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run();
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
         println!("{:?}", test_vm);
         assert_eq!(test_vm.registers[0], 500);
     }
@@ -294,8 +1749,8 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 500;
         test_vm.registers[1] = 500;
-        test_vm.program = vec![2, 0, 1, 2];
-        test_vm.run_once();
+        load_code(&mut test_vm, vec![2, 0, 1, 2]);
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[2], 1000);
     }
     #[test]
@@ -303,8 +1758,8 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 500;
         test_vm.registers[1] = 500;
-        test_vm.program = vec![3, 0, 1, 2];
-        test_vm.run_once();
+        load_code(&mut test_vm, vec![3, 0, 1, 2]);
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[2], 0);
     }
     #[test]
@@ -312,8 +1767,8 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 5;
         test_vm.registers[1] = 5;
-        test_vm.program = vec![4, 0, 1, 2];
-        test_vm.run_once();
+        load_code(&mut test_vm, vec![4, 0, 1, 2]);
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[2], 25);
     }
     #[test]
@@ -321,44 +1776,44 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 24;
         test_vm.registers[1] = 5;
-        test_vm.program = vec![5, 0, 1, 2];
-        test_vm.run_once();
+        load_code(&mut test_vm, vec![5, 0, 1, 2]);
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.remainder, 4);
     }
     #[test]
     fn test_opcode_jmp() {
         let mut test_vm = VM::new();
-        test_vm.registers[0] = 1;
-        test_vm.program = vec![6, 0, 0, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.pc, 1);
+        test_vm.registers[0] = PROGRAM_START as i32 + 4;
+        load_code(&mut test_vm, vec![6, 0, 0, 0, 0, 0, 0, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PROGRAM_START + 4);
     }
     #[test]
     fn test_opcode_jmpf() {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 2;
-        test_vm.program = vec![7, 0, 0, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.pc, 4);
+        load_code(&mut test_vm, vec![7, 0, 0, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PROGRAM_START + 4);
     }
     #[test]
     fn test_opcode_jmpb() {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 2;
-        test_vm.program = vec![8, 0, 0, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.pc, 0);
+        load_code(&mut test_vm, vec![8, 0, 0, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PROGRAM_START);
     }
     #[test]
     fn test_opcode_eq() {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
-        test_vm.program = vec![9, 0, 1, 0, 9, 0, 1, 0];
-        test_vm.run_once();
+        load_code(&mut test_vm, vec![9, 0, 1, 0, 9, 0, 1, 0]);
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
         test_vm.registers[1] = 20;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
     }
     #[test]
@@ -366,11 +1821,11 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 20;
-        test_vm.program = vec![10, 0, 1, 0, 10, 0, 1, 0];
-        test_vm.run_once();
+        load_code(&mut test_vm, vec![10, 0, 1, 0, 10, 0, 1, 0]);
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
         test_vm.registers[1] = 10;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
     }
     #[test]
@@ -378,14 +1833,14 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 9;
-        test_vm.program = vec![11, 0, 1, 0, 11, 0, 1, 0, 11, 0, 1, 0];
-        test_vm.run_once();
+        load_code(&mut test_vm, vec![11, 0, 1, 0, 11, 0, 1, 0, 11, 0, 1, 0]);
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
         test_vm.registers[1] = 10;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
         test_vm.registers[1] = 11;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
     }
     #[test]
@@ -393,14 +1848,14 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 11;
-        test_vm.program = vec![12, 0, 1, 0, 12, 0, 1, 0, 12, 0, 1, 0];
-        test_vm.run_once();
+        load_code(&mut test_vm, vec![12, 0, 1, 0, 12, 0, 1, 0, 12, 0, 1, 0]);
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
         test_vm.registers[1] = 10;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
         test_vm.registers[1] = 9;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
     }
     #[test]
@@ -408,14 +1863,14 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 9;
-        test_vm.program = vec![13, 0, 1, 0, 13, 0, 1, 0, 13, 0, 1, 0];
-        test_vm.run_once();
+        load_code(&mut test_vm, vec![13, 0, 1, 0, 13, 0, 1, 0, 13, 0, 1, 0]);
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
         test_vm.registers[1] = 10;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
         test_vm.registers[1] = 11;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
     }
     #[test]
@@ -423,56 +1878,806 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 11;
-        test_vm.program = vec![14, 0, 1, 0, 14, 0, 1, 0, 14, 0, 1, 0];
-        test_vm.run_once();
+        load_code(&mut test_vm, vec![14, 0, 1, 0, 14, 0, 1, 0, 14, 0, 1, 0]);
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
         test_vm.registers[1] = 10;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
         test_vm.registers[1] = 9;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
     }
     #[test]
     fn test_opcode_jeq() {
         let mut test_vm = VM::new();
-        test_vm.registers[0] = 7;
+        test_vm.registers[0] = PROGRAM_START as i32 + 4;
         test_vm.equal_flag = true;
-        test_vm.program = vec![15, 0, 0, 0, 15, 0, 0, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.pc, 7);
+        load_code(&mut test_vm, vec![15, 0, 0, 0, 15, 0, 0, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PROGRAM_START + 4);
     }
     #[test]
     fn test_opcode_jneq() {
         let mut test_vm = VM::new();
-        test_vm.registers[0] = 7;
+        test_vm.registers[0] = PROGRAM_START as i32 + 4;
         test_vm.equal_flag = false;
-        test_vm.program = vec![16, 0, 0, 0, 16, 0, 0, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.pc, 7);
+        load_code(&mut test_vm, vec![16, 0, 0, 0, 16, 0, 0, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PROGRAM_START + 4);
     }
     #[test]
     fn test_opcode_aloc() {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 1024;
-        test_vm.program = vec![17, 0, 0, 0];
-        test_vm.run_once();
+        load_code(&mut test_vm, vec![17, 0, 0, 0]);
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.heap.len(), 1024);
     }
     #[test]
     fn test_opcode_inc() {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 0;
-        test_vm.program = vec![18, 0, 0, 0];
-        test_vm.run_once();
+        load_code(&mut test_vm, vec![18, 0, 0, 0]);
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[0], 1);
     }
     #[test]
     fn test_opcode_dec() {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 1;
-        test_vm.program = vec![19, 0, 0, 0];
-        test_vm.run_once();
+        load_code(&mut test_vm, vec![19, 0, 0, 0]);
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[0], 0);
     }
+    #[test]
+    fn test_opcode_beq() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 3;
+        test_vm.registers[1] = 3;
+        load_code(&mut test_vm, vec![0, 0, 0, 0, 20, 0, 1, 0xFF]);
+        test_vm.pc = PROGRAM_START + 4;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PROGRAM_START);
+        test_vm.registers[1] = 4;
+        test_vm.pc = PROGRAM_START + 4;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PROGRAM_START + 8);
+    }
+    #[test]
+    fn test_opcode_bne() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 3;
+        test_vm.registers[1] = 4;
+        load_code(&mut test_vm, vec![21, 0, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PROGRAM_START + 8);
+    }
+    #[test]
+    fn test_opcode_bgt_blt() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 4;
+        load_code(
+            &mut test_vm,
+            vec![22, 0, 1, 2, 0, 0, 0, 0, 23, 0, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0],
+        );
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PROGRAM_START + 8);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PROGRAM_START + 12);
+    }
+    #[test]
+    fn test_opcode_bge_ble() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 4;
+        test_vm.registers[1] = 4;
+        load_code(
+            &mut test_vm,
+            vec![24, 0, 1, 2, 0, 0, 0, 0, 25, 0, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0],
+        );
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PROGRAM_START + 8);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PROGRAM_START + 16);
+    }
+    #[test]
+    fn test_opcode_callext_ret() {
+        let mut test_vm = VM::new();
+        test_vm.imports.push(ImportSlot {
+            module: "m".to_string(),
+            function: "f".to_string(),
+            target: Some(PROGRAM_START + 8),
+        });
+        load_code(&mut test_vm, vec![26, 0, 0, 0, 0, 0, 0, 0, 27, 0, 0, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PROGRAM_START + 8);
+        assert_eq!(test_vm.call_stack, vec![PROGRAM_START + 4]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PROGRAM_START + 4);
+        assert!(test_vm.call_stack.is_empty());
+    }
+
+    fn math_module() -> Vec<u8> {
+        let source = ".module math\n.export double/1\nmsg: .asciiz 'x'\ndouble: add $0 $0 $0\nload $1 @msg\nret";
+        Assembler::new().assemble_object(source).unwrap().to_bytes()
+    }
+
+    #[test]
+    fn test_load_modules() {
+        let main = Assembler::new()
+            .assemble(".import math double\nload $0 #21\ncallext @math.double\nhlt")
+            .unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_module(&main).unwrap();
+        let base = test_vm.program.len();
+        test_vm.load_module(&math_module()).unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[0], 42);
+        assert_eq!(test_vm.registers[1], (base + PROGRAM_START) as i32);
+        assert_eq!(
+            test_vm.modules()[0].exports,
+            vec![("double".to_string(), 1, base + PROGRAM_START + 2)]
+        );
+    }
+
+    #[test]
+    fn test_call_to_missing_module() {
+        let main = Assembler::new()
+            .assemble(".import math triple\nload $0 #1\ncallext @math.triple\nload $0 #2\nhlt")
+            .unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_module(&main).unwrap();
+        test_vm.load_module(&math_module()).unwrap();
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::UndefinedFunction {
+                module: "math".to_string(),
+                function: "triple".to_string()
+            })
+        );
+        assert_eq!(test_vm.registers[0], 1);
+        assert_eq!(test_vm.imports[0].target, None);
+    }
+
+    #[test]
+    fn test_load_module_errors() {
+        let mut test_vm = VM::new();
+        assert_eq!(
+            test_vm.load_module(&math_module()),
+            Err(ModuleError::NoProgram)
+        );
+        assert_eq!(
+            test_vm.load_module(&[1, 2, 3]),
+            Err(ModuleError::InvalidImage)
+        );
+        let main = Assembler::new().assemble("hlt").unwrap();
+        test_vm.load_module(&main).unwrap();
+        assert_eq!(
+            test_vm.load_module(&main),
+            Err(ModuleError::ProgramAlreadyLoaded)
+        );
+        test_vm.load_module(&math_module()).unwrap();
+        assert_eq!(
+            test_vm.load_module(&math_module()),
+            Err(ModuleError::DuplicateModule {
+                name: "math".to_string()
+            })
+        );
+        let plain = Assembler::new().assemble_object("hlt").unwrap().to_bytes();
+        assert_eq!(test_vm.load_module(&plain), Err(ModuleError::NotAModule));
+    }
+
+    fn math_versions() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let main = Assembler::new()
+            .assemble(
+                ".import math double\nload $0 #21\ncallext @math.double\ncallext @math.double\nhlt",
+            )
+            .unwrap();
+        let v1 = ".module math\n.export double/1\ndouble: add $0 $0 $0\nret";
+        let v2 = ".module math\n.export double/1\ndouble: add $0 $0 $0\nadd $0 $0 $0\nret";
+        let v1 = Assembler::new().assemble_object(v1).unwrap().to_bytes();
+        let v2 = Assembler::new().assemble_object(v2).unwrap().to_bytes();
+        (main, v1, v2)
+    }
+
+    /// Starts the program and runs it until it is inside the first call to `math.double`
+    fn start_in_first_call(test_vm: &mut VM) {
+        test_vm.pc = test_vm.get_starting_offset();
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.call_stack.len(), 1);
+    }
+
+    #[test]
+    fn test_upgrade_module() {
+        let (main, v1, v2) = math_versions();
+        let mut test_vm = VM::new();
+        test_vm.load_module(&main).unwrap();
+        test_vm.load_module(&v1).unwrap();
+        let old = test_vm.modules()[0].code.clone();
+        start_in_first_call(&mut test_vm);
+        test_vm.upgrade_module(&v2).unwrap();
+        assert_eq!(test_vm.modules()[0].old_code, Some(old.clone()));
+        assert_eq!(
+            test_vm.imports[0].target,
+            Some(test_vm.modules()[0].exports[0].2)
+        );
+
+        // The running call ends in the old version, the next one goes to the new version
+        while test_vm.execute_instruction() == Ok(None) {}
+        assert_eq!(test_vm.registers[0], 21 * 2 * 4);
+
+        assert_eq!(test_vm.purge_module("math"), Ok(false));
+        assert_eq!(test_vm.modules()[0].old_code, None);
+        assert!(test_vm.program[old].iter().all(|b| *b == 0));
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[0], 21 * 4 * 4);
+    }
+
+    #[test]
+    fn test_purge_kills_code_running_old_version() {
+        let (main, v1, v2) = math_versions();
+        let mut test_vm = VM::new();
+        test_vm.load_module(&main).unwrap();
+        test_vm.load_module(&v1).unwrap();
+        start_in_first_call(&mut test_vm);
+        test_vm.upgrade_module(&v2).unwrap();
+        assert_eq!(test_vm.purge_module("math"), Ok(true));
+        assert!(test_vm.call_stack.is_empty());
+        assert_eq!(test_vm.execute_instruction(), Ok(Some(ExitReason::Purged)));
+        assert_eq!(test_vm.registers[0], 21);
+    }
+
+    #[test]
+    fn test_purged_code_is_reused() {
+        let (main, v1, v2) = math_versions();
+        let mut test_vm = VM::new();
+        test_vm.load_module(&main).unwrap();
+        test_vm.load_module(&v1).unwrap();
+        let first = test_vm.modules()[0].code.clone();
+        test_vm.upgrade_module(&v2).unwrap();
+        test_vm.purge_module("math").unwrap();
+        assert_eq!(test_vm.free, vec![first.clone()]);
+
+        // The first version fits where it was, and the second one ended the program
+        let length = test_vm.program.len();
+        test_vm.upgrade_module(&v1).unwrap();
+        assert_eq!(test_vm.modules()[0].code, first);
+        assert_eq!(test_vm.program.len(), length);
+        test_vm.purge_module("math").unwrap();
+        assert_eq!(test_vm.program.len(), first.end);
+        assert!(test_vm.free.is_empty());
+
+        // Upgrading over and over doesn't run out of addresses
+        for _ in 0..1000 {
+            test_vm.upgrade_module(&v2).unwrap();
+            test_vm.purge_module("math").unwrap();
+            test_vm.upgrade_module(&v1).unwrap();
+            test_vm.purge_module("math").unwrap();
+        }
+        assert_eq!(test_vm.program.len(), first.end);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[0], 21 * 4);
+    }
+
+    #[test]
+    fn test_upgrade_module_errors() {
+        let (main, v1, v2) = math_versions();
+        let mut test_vm = VM::new();
+        test_vm.load_module(&main).unwrap();
+        let not_loaded = Err(ModuleError::NotLoaded {
+            name: "math".to_string(),
+        });
+        assert_eq!(test_vm.upgrade_module(&v2), not_loaded);
+        assert_eq!(test_vm.purge_module("math").map(|_| ()), not_loaded);
+        test_vm.load_module(&v1).unwrap();
+        test_vm.upgrade_module(&v2).unwrap();
+        assert_eq!(
+            test_vm.upgrade_module(&v1),
+            Err(ModuleError::OldCodeNotPurged {
+                name: "math".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_code_is_rejected() {
+        let mut program = Assembler::new().assemble("load $0 #1\nhlt").unwrap();
+        program[PROGRAM_START + 1] = 200;
+        let error = VerifierError::InvalidRegister {
+            address: PROGRAM_START,
+            register: 200,
+        };
+        let mut test_vm = VM::new();
+        assert_eq!(
+            test_vm.load_module(&program),
+            Err(ModuleError::InvalidCode {
+                error: error.clone()
+            })
+        );
+        test_vm.add_bytes(program);
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::InvalidProgram {
+                error: error.clone()
+            })
+        );
+        assert_eq!(test_vm.pc, 0);
+        assert_eq!(
+            test_vm.load_module(&math_module()),
+            Err(ModuleError::InvalidCode { error })
+        );
+
+        let mut test_vm = VM::new();
+        test_vm
+            .load_module(&Assembler::new().assemble("hlt").unwrap())
+            .unwrap();
+        let mut module = Assembler::new()
+            .assemble_object(".module m\nload $0 #1")
+            .unwrap();
+        module.code.pop();
+        assert_eq!(
+            test_vm.load_module(&module.to_bytes()),
+            Err(ModuleError::InvalidCode {
+                error: VerifierError::TruncatedInstruction {
+                    address: PROGRAM_START
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_verified_code_does_not_panic() {
+        let run = |source: &str| {
+            let mut test_vm = VM::new();
+            test_vm
+                .load(&Assembler::new().assemble(source).unwrap())
+                .unwrap();
+            let result = test_vm.run();
+            (result, test_vm)
+        };
+        // Into the data section, which holds what looks like an instruction
+        let (result, _) = run("d: .asciiz '\\x01\\xc8'\nload $0 @d\njmp $0");
+        let code = PROGRAM_START + 3;
+        assert_eq!(
+            result,
+            Err(VmError::InvalidJump {
+                address: code + 4,
+                target: PROGRAM_START as i64
+            })
+        );
+        let (result, _) = run("load $0 #500\njmpb $0");
+        assert_eq!(
+            result,
+            Err(VmError::InvalidJump {
+                address: PROGRAM_START + 4,
+                target: PROGRAM_START as i64 + 6 - 500
+            })
+        );
+        let (result, _) = run("load $0 #1\ndiv $0 $1 $2");
+        assert_eq!(
+            result,
+            Err(VmError::DivisionByZero {
+                address: PROGRAM_START + 4
+            })
+        );
+        let (result, test_vm) = run("load $0 #65535\nmul $0 $0 $1\nmul $1 $1 $2\ndec $2\nhlt");
+        assert_eq!(result, Ok(ExitReason::Halted));
+        let square = 65535i32.wrapping_mul(65535);
+        assert_eq!(test_vm.registers[1], square);
+        assert_eq!(test_vm.registers[2], square.wrapping_mul(square) - 1);
+
+        // The module table after the code isn't executed
+        let (result, _) = run(".module m\n.export f/0\nf: load $0 #1");
+        assert_eq!(result, Ok(ExitReason::EndOfProgram));
+        // Into the header
+        let (result, _) = run("load $0 #1\njmp $0");
+        assert_eq!(
+            result,
+            Err(VmError::InvalidJump {
+                address: PROGRAM_START + 4,
+                target: 1
+            })
+        );
+        // To where the code ends, which ends the program
+        let (result, _) = run(&format!("load $0 #{}\njmp $0", PROGRAM_START + 8));
+        assert_eq!(result, Ok(ExitReason::EndOfProgram));
+        // A jump that isn't taken goes on with the next instruction, not with its padding
+        let mut test_vm = VM::new();
+        let mut program = Assembler::new()
+            .assemble("load $1 #1\neq $0 $1\njeq $0\nhlt")
+            .unwrap();
+        program[PROGRAM_START + 10..PROGRAM_START + 12].copy_from_slice(&[1, 200]);
+        test_vm.load(&program).unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+    }
+
+    #[test]
+    fn test_run_once_verifies_the_program() {
+        let mut program = Assembler::new().assemble("load $0 #1\nhlt").unwrap();
+        program[PROGRAM_START + 1] = 200;
+        let mut test_vm = VM::new();
+        test_vm.add_bytes(program);
+        test_vm.set_pc(PROGRAM_START);
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::InvalidProgram {
+                error: VerifierError::InvalidRegister {
+                    address: PROGRAM_START,
+                    register: 200
+                }
+            })
+        );
+
+        let mut test_vm = VM::new();
+        test_vm
+            .load(&Assembler::new().assemble("load $0 #500\nhlt").unwrap())
+            .unwrap();
+        test_vm.set_pc(PROGRAM_START + 1);
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::NotAnInstruction {
+                address: PROGRAM_START + 1
+            })
+        );
+        test_vm.set_pc(PROGRAM_START);
+        assert_eq!(test_vm.run_once(), Ok(None));
+        // Code appended after the program is verified before it runs
+        test_vm.add_bytes(vec![1, 40, 0, 0]);
+        test_vm.set_pc(PROGRAM_START + 8);
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::InvalidProgram {
+                error: VerifierError::InvalidRegister {
+                    address: PROGRAM_START + 8,
+                    register: 40
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let looping = Assembler::new().assemble("top: beq $0 $0 @top").unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_module(&looping).unwrap();
+        test_vm.set_limits(VmLimits {
+            max_instructions: Some(100),
+            ..VmLimits::default()
+        });
+        assert_eq!(test_vm.run(), Ok(ExitReason::InstructionLimit));
+        assert_eq!(test_vm.executed, 100);
+        test_vm.set_limits(VmLimits {
+            max_duration: Some(Duration::from_millis(10)),
+            ..VmLimits::default()
+        });
+        assert_eq!(test_vm.run(), Ok(ExitReason::TimeLimit));
+
+        let allocating = Assembler::new()
+            .assemble("load $0 #1000\naloc $0\nhlt")
+            .unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_module(&allocating).unwrap();
+        test_vm.set_limits(VmLimits {
+            max_heap_bytes: Some(100),
+            ..VmLimits::default()
+        });
+        assert_eq!(test_vm.run(), Ok(ExitReason::HeapLimit { requested: 1000 }));
+        assert!(test_vm.heap.is_empty());
+
+        let negative = Assembler::new().assemble("dec $0\naloc $0\nhlt").unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_module(&negative).unwrap();
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::NegativeAllocation {
+                address: PROGRAM_START + 4,
+                requested: -1
+            })
+        );
+
+        let main = Assembler::new()
+            .assemble(".import r f\ncallext @r.f\nhlt")
+            .unwrap();
+        let recursive = Assembler::new()
+            .assemble_object(".module r\n.import r f\n.export f/0\nf: callext @r.f")
+            .unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_module(&main).unwrap();
+        test_vm.load_module(&recursive.to_bytes()).unwrap();
+        test_vm.set_limits(VmLimits {
+            max_stack_depth: Some(10),
+            ..VmLimits::default()
+        });
+        assert_eq!(test_vm.run(), Ok(ExitReason::StackLimit));
+        assert_eq!(test_vm.call_stack.len(), 10);
+    }
+
+    #[test]
+    fn test_process_limit() {
+        let program = Assembler::new()
+            .assemble(
+                "inc $0
+hlt",
+            )
+            .unwrap();
+        let mut test_vm = VmBuilder::new().max_processes(0).build();
+        test_vm.load(&program).unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitReason::ProcessLimit));
+        assert_eq!(test_vm.register(0), Some(0));
+        // The program is the only process, so any other limit lets it run
+        for max in 1..=2 {
+            let mut test_vm = VmBuilder::new().max_processes(max).build();
+            test_vm.load(&program).unwrap();
+            assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+            assert_eq!(test_vm.register(0), Some(1));
+        }
+    }
+
+    #[test]
+    fn test_builder_and_accessors() {
+        let program = Assembler::new()
+            .assemble("load $0 #7\nload $1 #2\ndiv $0 $1 $2\neq $0 $0\ntop: beq $0 $0 @top")
+            .unwrap();
+        let mut test_vm = VmBuilder::new()
+            .max_instructions(10)
+            .output(io::sink())
+            .build();
+        test_vm.load(&program).unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitReason::InstructionLimit));
+        assert_eq!(test_vm.register(2), Some(3));
+        assert_eq!(test_vm.remainder(), 1);
+        assert!(test_vm.equal_flag());
+        assert!(test_vm.set_register(31, 5));
+        assert!(!test_vm.set_register(32, 5));
+        assert_eq!(test_vm.registers()[31], 5);
+
+        // Loading again starts afresh, with the same limits
+        test_vm.load(&program).unwrap();
+        assert_eq!(test_vm.register(31), Some(0));
+        assert_eq!(test_vm.run(), Ok(ExitReason::InstructionLimit));
+    }
+
+    #[test]
+    fn test_opcode_calln() {
+        let program = Assembler::new()
+            .assemble(".native add/2\n.native fail/0\nload $0 #40\nload $1 #2\ncalln @add\nhlt\ncalln @fail")
+            .unwrap();
+        let mut test_vm = VmBuilder::new()
+            .native("fail", 0, |_| Err("no reason".to_string()))
+            .native("add", 2, |args| Ok(args[0] + args[1]))
+            .build();
+        test_vm.load(&program).unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.register(0), Some(42));
+        // `add` is the first native of the program but the second of the registry
+        assert_eq!(test_vm.program[test_vm.get_starting_offset() + 10], 1);
+        test_vm.pc = test_vm.get_starting_offset() + 16;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::NativeFailed {
+                name: "fail".to_string(),
+                message: "no reason".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_load_natives_errors() {
+        let program = Assembler::new()
+            .assemble(".native add/2\ncalln @add\nhlt")
+            .unwrap();
+        let mut test_vm = VM::new();
+        assert_eq!(
+            test_vm.load(&program),
+            Err(ModuleError::UndefinedNative {
+                name: "add".to_string()
+            })
+        );
+        test_vm.natives_mut().register("add", 3, |args| Ok(args[0]));
+        assert_eq!(
+            test_vm.load(&program),
+            Err(ModuleError::NativeArity {
+                name: "add".to_string(),
+                declared: 2,
+                registered: 3
+            })
+        );
+        assert!(test_vm.program.is_empty());
+    }
+
+    #[test]
+    fn test_journal_undo() {
+        let program = Assembler::new()
+            .assemble(
+                "load $5 #4
+                aloc $5
+                load $1 #0
+                load $2 #4
+                syscall #0
+                load $3 #3
+                aloc $3
+                eq $0 $3
+                div $0 $3 $4
+                hlt",
+            )
+            .unwrap();
+        let mut test_vm = VmBuilder::new()
+            .capabilities(Capabilities::STDIN)
+            .input(&b"abcd"[..])
+            .journal(100)
+            .build();
+        test_vm.load(&program).unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.journal_length(), 10);
+        assert_eq!(test_vm.heap(), &[0, 0, 0]);
+        assert_eq!(test_vm.remainder(), 1);
+        assert!(test_vm.undo());
+        assert_eq!(test_vm.pc(), test_vm.get_starting_offset() + 36);
+        for _ in 0..3 {
+            assert!(test_vm.undo());
+        }
+        assert_eq!(test_vm.heap(), b"abcd");
+        assert_eq!(test_vm.register(4), Some(0));
+        assert_eq!(test_vm.remainder(), 0);
+        assert!(!test_vm.equal_flag());
+        for _ in 0..2 {
+            assert!(test_vm.undo());
+        }
+        assert_eq!(test_vm.heap(), &[0, 0, 0, 0]);
+        while test_vm.undo() {}
+        assert_eq!(test_vm.pc(), test_vm.get_starting_offset());
+        assert_eq!(test_vm.registers(), &[0; REGISTER_COUNT]);
+        assert!(test_vm.heap().is_empty());
+
+        // Going forward again gives the same result, the input aside
+        for _ in 0..4 {
+            assert_eq!(test_vm.run_once(), Ok(None));
+        }
+        assert_eq!(test_vm.journal_length(), 4);
+        test_vm.set_journal(Some(2));
+        assert_eq!(test_vm.journal_length(), 2);
+        assert!(test_vm.undo() && test_vm.undo() && !test_vm.undo());
+        test_vm.set_journal(None);
+        assert_eq!(test_vm.run_once(), Ok(None));
+        assert!(!test_vm.undo());
+    }
+
+    #[test]
+    fn test_journal_undoes_calls() {
+        let program = Assembler::new()
+            .assemble(".module main\n.import lib f\ncallext @lib.f\nhlt")
+            .unwrap();
+        let lib = Assembler::new()
+            .assemble_object(".module lib\n.export f/0\nf: ret")
+            .unwrap();
+        let mut test_vm = VmBuilder::new().journal(10).build();
+        test_vm.load(&program).unwrap();
+        test_vm.load_module(&lib.to_bytes()).unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        test_vm.undo();
+        assert!(test_vm.undo());
+        assert_eq!(test_vm.call_depth(), 1);
+        assert!(test_vm.undo());
+        assert_eq!(test_vm.call_depth(), 0);
+        assert_eq!(test_vm.pc(), test_vm.get_starting_offset());
+    }
+
+    /// An output the test can read after the VM wrote to it
+    #[derive(Clone, Default)]
+    struct SharedOutput(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_opcode_syscall() {
+        let program = Assembler::new()
+            .assemble(
+                "msg: .asciiz 'hi '
+                load $0 #1
+                load $1 @msg
+                syscall #2
+                load $3 #8
+                aloc $3
+                load $0 #0
+                load $1 #0
+                load $2 #8
+                syscall #0
+                add $0 $4 $2
+                load $0 #1
+                syscall #1
+                syscall #6
+                hlt",
+            )
+            .unwrap();
+        let output = SharedOutput::default();
+        let mut test_vm = VmBuilder::new()
+            .capabilities(Capabilities::sandboxed())
+            .output(output.clone())
+            .input(&b"there"[..])
+            .random_seed(7)
+            .build();
+        test_vm.load(&program).unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(&output.0.lock().unwrap()[..], b"hi there");
+        assert_eq!(&test_vm.heap()[..5], b"there");
+        assert!(test_vm.register(0).unwrap() >= 0);
+
+        // Without STDOUT, printing stops the program
+        test_vm.set_capabilities(Capabilities::sandboxed().without(Capabilities::STDOUT));
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::CapabilityDenied {
+                service: Service::Print,
+                capability: Capabilities::STDOUT
+            })
+        );
+        test_vm.set_capabilities(Capabilities::sandboxed());
+        test_vm.pc = test_vm.get_starting_offset() + 44;
+        test_vm.registers[1] = 0;
+        test_vm.registers[2] = 9;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::BadBuffer {
+                offset: 0,
+                length: 9
+            })
+        );
+    }
+
+    #[test]
+    fn test_syscall_files() {
+        let path = std::env::temp_dir().join(format!("bumbam_syscall_{}", std::process::id()));
+        let program = Assembler::new()
+            .assemble(&format!(
+                "path: .asciiz '{}'
+                msg: .asciiz 'saved'
+                load $0 @path
+                load $1 #1
+                syscall #3
+                load $1 @msg
+                syscall #2
+                load $0 #3
+                syscall #4
+                syscall #4
+                hlt",
+                path.display()
+            ))
+            .unwrap();
+        let mut test_vm = VmBuilder::new()
+            .capabilities(Capabilities::sandboxed())
+            .build();
+        test_vm.load(&program).unwrap();
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::CapabilityDenied {
+                service: Service::Open,
+                capability: Capabilities::FILES
+            })
+        );
+        assert!(!path.exists());
+
+        test_vm.set_capabilities(Capabilities::all());
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(std::fs::read(&path).unwrap(), b"saved");
+        // The file was already closed
+        assert_eq!(test_vm.register(0), Some(-1));
+        std::fs::remove_file(&path).unwrap();
+
+        // Opening a file that doesn't exist fails without stopping the program
+        test_vm.pc = test_vm.get_starting_offset();
+        assert_eq!(test_vm.run_once(), Ok(None));
+        test_vm.pc += 4;
+        test_vm.registers[1] = 0;
+        assert_eq!(test_vm.run_once(), Ok(None));
+        assert_eq!(test_vm.register(0), Some(-1));
+    }
 }
@@ -0,0 +1,425 @@
+use super::{ImportSlot, LoadedModule, VM};
+use crate::assembler::object_file::{push_string, push_u32, read_string, read_u32, take};
+use crate::syscall::Random;
+use byteorder::{ByteOrder, LittleEndian};
+use std::fmt;
+use std::iter;
+use std::ops::Range;
+
+/// What snapshots start with
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"BBSS";
+/// Changed whenever the layout of a snapshot changes
+pub const SNAPSHOT_VERSION: u8 = 3;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SnapshotError {
+    /// The bytes don't start with `SNAPSHOT_MAGIC`
+    NotASnapshot,
+    UnsupportedVersion {
+        version: u8,
+    },
+    /// The snapshot is truncated, or what it holds doesn't fit together
+    Malformed,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::NotASnapshot => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion { version } => {
+                write!(f, "snapshot version {} is not supported", version)
+            }
+            SnapshotError::Malformed => write!(f, "the snapshot is malformed"),
+        }
+    }
+}
+
+fn push_u64(bytes: &mut Vec<u8>, value: u64) {
+    let mut buf = [0; 8];
+    LittleEndian::write_u64(&mut buf, value);
+    bytes.extend_from_slice(&buf);
+}
+
+fn read_u64(input: &mut &[u8]) -> Option<u64> {
+    take(input, 8).map(LittleEndian::read_u64)
+}
+
+fn read_usize(input: &mut &[u8]) -> Option<usize> {
+    read_u32(input).map(|value| value as usize)
+}
+
+fn read_bool(input: &mut &[u8]) -> Option<bool> {
+    match take(input, 1)?[0] {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    }
+}
+
+fn push_bytes(bytes: &mut Vec<u8>, data: &[u8]) {
+    push_u32(bytes, data.len() as u32);
+    bytes.extend_from_slice(data);
+}
+
+fn read_bytes(input: &mut &[u8]) -> Option<Vec<u8>> {
+    let length = read_usize(input)?;
+    take(input, length).map(<[u8]>::to_vec)
+}
+
+/// Optional addresses are written as a flag byte followed by the address
+fn push_address(bytes: &mut Vec<u8>, address: Option<usize>) {
+    bytes.push(address.is_some() as u8);
+    push_u32(bytes, address.unwrap_or(0) as u32);
+}
+
+fn read_address(input: &mut &[u8]) -> Option<Option<usize>> {
+    let present = read_bool(input)?;
+    let address = read_usize(input)?;
+    Some(if present { Some(address) } else { None })
+}
+
+/// What `VM::restore` puts back, read before anything is changed so that a malformed
+/// snapshot leaves the VM as it was
+struct State {
+    registers: Vec<i32>,
+    pc: usize,
+    remainder: usize,
+    equal_flag: bool,
+    killed: bool,
+    executed: u64,
+    random: u64,
+    program: Vec<u8>,
+    heap: Vec<u8>,
+    call_stack: Vec<usize>,
+    imports: Vec<ImportSlot>,
+    modules: Vec<LoadedModule>,
+    free: Vec<Range<usize>>,
+}
+
+impl State {
+    fn read(input: &mut &[u8], register_count: usize) -> Option<State> {
+        let registers = (0..register_count)
+            .map(|_| read_u32(input).map(|value| value as i32))
+            .collect::<Option<Vec<i32>>>()?;
+        let mut state = State {
+            registers,
+            pc: read_usize(input)?,
+            remainder: read_usize(input)?,
+            equal_flag: read_bool(input)?,
+            killed: read_bool(input)?,
+            executed: read_u64(input)?,
+            random: read_u64(input)?,
+            program: read_bytes(input)?,
+            heap: read_bytes(input)?,
+            call_stack: vec![],
+            imports: vec![],
+            modules: vec![],
+            free: vec![],
+        };
+        for _ in 0..read_u32(input)? {
+            state.call_stack.push(read_usize(input)?);
+        }
+        for _ in 0..read_u32(input)? {
+            state.imports.push(ImportSlot {
+                module: read_string(input)?,
+                function: read_string(input)?,
+                target: read_address(input)?,
+            });
+        }
+        for _ in 0..read_u32(input)? {
+            let name = read_string(input)?;
+            let mut exports = vec![];
+            for _ in 0..read_u32(input)? {
+                exports.push((read_string(input)?, take(input, 1)?[0], read_usize(input)?));
+            }
+            let code = read_usize(input)?..read_usize(input)?;
+            let old_code = match read_address(input)? {
+                Some(start) => Some(start..read_usize(input)?),
+                None => None,
+            };
+            state.modules.push(LoadedModule {
+                name,
+                exports,
+                code,
+                old_code,
+            });
+        }
+        for _ in 0..read_u32(input)? {
+            state.free.push(read_usize(input)?..read_usize(input)?);
+        }
+        Some(state)
+    }
+
+    /// Whether every address points into the program, and the free ranges are sorted, cleared
+    /// and hold no module
+    fn is_consistent(&self) -> bool {
+        let length = self.program.len();
+        let in_program = |address: usize| address <= length;
+        let range_in_program = |start: usize, end: usize| start <= end && end <= length;
+        in_program(self.pc)
+            && self.call_stack.iter().all(|address| in_program(*address))
+            && self
+                .imports
+                .iter()
+                .all(|slot| slot.target.is_none_or(in_program))
+            && self.modules.iter().all(|module| {
+                range_in_program(module.code.start, module.code.end)
+                    && module
+                        .old_code
+                        .as_ref()
+                        .is_none_or(|old| range_in_program(old.start, old.end))
+                    && module
+                        .exports
+                        .iter()
+                        .all(|(_, _, address)| in_program(*address))
+            })
+            && self.free.iter().all(|free| {
+                range_in_program(free.start, free.end)
+                    && free.start < free.end
+                    && self.program[free.clone()].iter().all(|byte| *byte == 0)
+                    && self
+                        .modules
+                        .iter()
+                        .flat_map(|m| iter::once(&m.code).chain(&m.old_code))
+                        .all(|code| code.end <= free.start || free.end <= code.start)
+            })
+            && self.free.windows(2).all(|pair| pair[0].end < pair[1].start)
+    }
+}
+
+impl VM {
+    /// Saves the state of the program being run, to carry on with it later with `restore`.
+    /// What the host configured isn't part of it, and neither are the files the program
+    /// opened nor the journal.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+        bytes.push(SNAPSHOT_VERSION);
+        for register in self.registers.iter() {
+            push_u32(&mut bytes, *register as u32);
+        }
+        push_u32(&mut bytes, self.pc as u32);
+        push_u32(&mut bytes, self.remainder as u32);
+        bytes.push(self.equal_flag as u8);
+        bytes.push(self.killed as u8);
+        push_u64(&mut bytes, self.executed);
+        push_u64(&mut bytes, self.random.state());
+        push_bytes(&mut bytes, &self.program);
+        push_bytes(&mut bytes, &self.heap);
+        push_u32(&mut bytes, self.call_stack.len() as u32);
+        for address in &self.call_stack {
+            push_u32(&mut bytes, *address as u32);
+        }
+        push_u32(&mut bytes, self.imports.len() as u32);
+        for slot in &self.imports {
+            push_string(&mut bytes, &slot.module);
+            push_string(&mut bytes, &slot.function);
+            push_address(&mut bytes, slot.target);
+        }
+        push_u32(&mut bytes, self.modules.len() as u32);
+        for module in &self.modules {
+            push_string(&mut bytes, &module.name);
+            push_u32(&mut bytes, module.exports.len() as u32);
+            for (name, arity, address) in &module.exports {
+                push_string(&mut bytes, name);
+                bytes.push(*arity);
+                push_u32(&mut bytes, *address as u32);
+            }
+            push_u32(&mut bytes, module.code.start as u32);
+            push_u32(&mut bytes, module.code.end as u32);
+            push_address(&mut bytes, module.old_code.as_ref().map(|old| old.start));
+            if let Some(old) = &module.old_code {
+                push_u32(&mut bytes, old.end as u32);
+            }
+        }
+        push_u32(&mut bytes, self.free.len() as u32);
+        for free in &self.free {
+            push_u32(&mut bytes, free.start as u32);
+            push_u32(&mut bytes, free.end as u32);
+        }
+        bytes
+    }
+
+    /// Puts back the state saved by `snapshot`, replacing the program being run like `load`
+    /// does. Anyone can write a snapshot, so the program is verified again before it runs.
+    /// Natives are called by the index they had, so it must be restored into a VM configured
+    /// the same way. The VM is left as it was if the snapshot can't be read.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        let mut input = snapshot;
+        if take(&mut input, SNAPSHOT_MAGIC.len()) != Some(&SNAPSHOT_MAGIC[..]) {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = take(&mut input, 1).ok_or(SnapshotError::Malformed)?[0];
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+        let state = State::read(&mut input, self.registers.len())
+            .filter(|state| input.is_empty() && state.is_consistent())
+            .ok_or(SnapshotError::Malformed)?;
+        self.registers.copy_from_slice(&state.registers);
+        self.pc = state.pc;
+        self.section = None;
+        self.remainder = state.remainder;
+        self.equal_flag = state.equal_flag;
+        self.killed = state.killed;
+        self.verified = false;
+        self.executed = state.executed;
+        self.random = Random::new(state.random);
+        self.program = state.program;
+        self.heap = state.heap;
+        self.call_stack = state.call_stack;
+        self.imports = state.imports;
+        self.modules = state.modules;
+        self.free = state.free;
+        self.files.clear();
+        self.clear_journal();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::assembler::PROGRAM_START;
+    use crate::vm::{ExitReason, VmError};
+
+    fn program(source: &str) -> Vec<u8> {
+        Assembler::new().assemble(source).unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut vm = VM::new();
+        vm.load(&program(
+            "load $0 #100\naloc $0\nload $1 #7\nload $2 #3\ndiv $1 $2 $3\nadd $3 $3 $3\nhlt",
+        ))
+        .unwrap();
+        vm.start().unwrap();
+        for _ in 0..5 {
+            vm.run_once().unwrap();
+        }
+        let snapshot = vm.snapshot();
+
+        let mut restored = VM::new();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.registers(), vm.registers());
+        assert_eq!(restored.pc(), vm.pc());
+        assert_eq!(restored.remainder(), 1);
+        assert_eq!(restored.heap().len(), 100);
+        assert_eq!(restored.program(), vm.program());
+        assert_eq!(restored.snapshot(), snapshot);
+
+        // Both carry on the same way
+        assert_eq!(restored.run_once(), Ok(None));
+        assert_eq!(restored.register(3), Some(4));
+        assert_eq!(restored.run_once(), Ok(Some(ExitReason::Halted)));
+    }
+
+    #[test]
+    fn test_snapshot_keeps_modules() {
+        let mut vm = VM::new();
+        vm.load(&program(
+            ".import math double\nload $0 #21\ncallext @math.double\nhlt",
+        ))
+        .unwrap();
+        let module = Assembler::new()
+            .assemble_object(".module math\n.export double/1\ndouble: add $0 $0 $0\nret")
+            .unwrap();
+        vm.load_module(&module.to_bytes()).unwrap();
+        vm.start().unwrap();
+        let mut restored = VM::new();
+        restored.restore(&vm.snapshot()).unwrap();
+        assert_eq!(restored.modules()[0].name, "math");
+        assert_eq!(restored.modules()[0].code, vm.modules()[0].code);
+        assert_eq!(restored.run(), Ok(ExitReason::Halted));
+        assert_eq!(restored.register(0), Some(42));
+    }
+
+    #[test]
+    fn test_snapshot_keeps_free_ranges() {
+        let mut vm = VM::new();
+        vm.load(&program(
+            ".import math double\nload $0 #21\ncallext @math.double\nhlt",
+        ))
+        .unwrap();
+        let module = |body: &str| {
+            Assembler::new()
+                .assemble_object(&format!(".module math\n.export double/1\n{}", body))
+                .unwrap()
+                .to_bytes()
+        };
+        vm.load_module(&module("double: add $0 $0 $0\nret"))
+            .unwrap();
+        vm.upgrade_module(&module("double: add $0 $0 $0\nadd $0 $0 $0\nret"))
+            .unwrap();
+        vm.purge_module("math").unwrap();
+        assert_eq!(vm.free.len(), 1);
+        let mut restored = VM::new();
+        restored.restore(&vm.snapshot()).unwrap();
+        assert_eq!(restored.free, vm.free);
+
+        // A free range must not hold anything
+        let start = vm.free[0].start;
+        vm.program[start] = 1;
+        assert_eq!(
+            restored.restore(&vm.snapshot()),
+            Err(SnapshotError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_restored_program_is_verified() {
+        let mut vm = VM::new();
+        let program = program("load $0 #1\nhlt");
+        vm.load(&program).unwrap();
+        let mut snapshot = vm.snapshot();
+        // Gives the LOAD a register that doesn't exist
+        let at = snapshot
+            .windows(program.len())
+            .position(|window| window == &program[..])
+            .unwrap();
+        snapshot[at + PROGRAM_START + 1] = 200;
+
+        let mut restored = VM::new();
+        restored.restore(&snapshot).unwrap();
+        assert!(matches!(
+            restored.run_once(),
+            Err(VmError::InvalidProgram { .. })
+        ));
+        assert!(matches!(
+            restored.run(),
+            Err(VmError::InvalidProgram { .. })
+        ));
+    }
+
+    #[test]
+    fn test_restore_rejects_bad_snapshots() {
+        let mut vm = VM::new();
+        vm.load(&program("hlt")).unwrap();
+        let snapshot = vm.snapshot();
+
+        let mut other = VM::new();
+        assert_eq!(
+            other.restore(b"not a snapshot"),
+            Err(SnapshotError::NotASnapshot)
+        );
+        let mut newer = snapshot.clone();
+        newer[SNAPSHOT_MAGIC.len()] = SNAPSHOT_VERSION + 1;
+        assert_eq!(
+            other.restore(&newer),
+            Err(SnapshotError::UnsupportedVersion {
+                version: SNAPSHOT_VERSION + 1
+            })
+        );
+        assert_eq!(
+            other.restore(&snapshot[..snapshot.len() - 1]),
+            Err(SnapshotError::Malformed)
+        );
+        // A pc past the end of the program
+        let mut bad_pc = snapshot.clone();
+        let pc = SNAPSHOT_MAGIC.len() + 1 + 4 * vm.registers().len();
+        LittleEndian::write_u32(&mut bad_pc[pc..], 10_000);
+        assert_eq!(other.restore(&bad_pc), Err(SnapshotError::Malformed));
+        assert!(other.program().is_empty());
+    }
+}